@@ -1,6 +1,8 @@
 use core::fmt;
 use std::{collections::BTreeMap, vec};
 
+use anyhow::{anyhow, Context, Result};
+
 use crate::types::ByteString;
 
 #[derive(Clone, PartialEq, Eq, Hash)]
@@ -12,40 +14,78 @@ pub enum BencodeValue {
 }
 
 impl BencodeValue {
+    /// Encode value. Dict keys are written in sorted order, so for any canonical bencoded input `x`
+    /// (sorted UTF-8 dict keys without duplicates, integers and string lengths without leading zeros)
+    /// `parse_bencoded(x).0.unwrap().encode() == x`
     pub fn encode(&self) -> ByteString {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut ByteString) {
         match self {
-            BencodeValue::String(s) => [s.len().to_string().as_bytes(), ":".as_bytes(), s.as_slice()].concat(),
-            BencodeValue::Int(i) => ["i".as_bytes(), i.to_string().as_bytes(), "e".as_bytes()].concat(),
-            BencodeValue::List(l) => vec![
-                "l".as_bytes().to_vec(),
-                l.iter().flat_map(|v| v.encode()).collect(),
-                "e".as_bytes().to_vec(),
-            ]
-            .into_iter()
-            .flatten()
-            .collect(),
-            BencodeValue::Dict(d) => vec![
-                "d".as_bytes().to_vec(),
-                d.iter()
-                    .flat_map(|(k, v)| {
-                        [
-                            BencodeValue::String(k.as_bytes().to_vec()).encode().as_slice(),
-                            v.encode().as_slice(),
-                        ]
-                        .concat()
-                    })
-                    .collect(),
-                "e".as_bytes().to_vec(),
-            ]
-            .into_iter()
-            .flatten()
-            .collect(),
+            BencodeValue::String(s) => encode_string(s, buf),
+            BencodeValue::Int(i) => {
+                buf.push(b'i');
+                buf.extend_from_slice(i.to_string().as_bytes());
+                buf.push(b'e');
+            }
+            BencodeValue::List(l) => {
+                buf.push(b'l');
+                l.iter().for_each(|v| v.encode_into(buf));
+                buf.push(b'e');
+            }
+            BencodeValue::Dict(d) => {
+                buf.push(b'd');
+                for (k, v) in d {
+                    encode_string(k.as_bytes(), buf);
+                    v.encode_into(buf);
+                }
+                buf.push(b'e');
+            }
         }
     }
+
+    /// Length of the encoded value, without encoding it
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            BencodeValue::String(s) => string_encoded_len(s),
+            BencodeValue::Int(i) => 2 + (*i < 0) as usize + digits(i.unsigned_abs()),
+            BencodeValue::List(l) => 2 + l.iter().map(|v| v.encoded_len()).sum::<usize>(),
+            BencodeValue::Dict(d) => {
+                2 + d
+                    .iter()
+                    .map(|(k, v)| string_encoded_len(k.as_bytes()) + v.encoded_len())
+                    .sum::<usize>()
+            }
+        }
+    }
+}
+
+fn encode_string(s: &[u8], buf: &mut ByteString) {
+    buf.extend_from_slice(s.len().to_string().as_bytes());
+    buf.push(b':');
+    buf.extend_from_slice(s);
+}
+
+fn string_encoded_len(s: &[u8]) -> usize {
+    digits(s.len() as u64) + 1 + s.len()
+}
+
+/// Number of decimal digits
+fn digits(n: u64) -> usize {
+    n.checked_ilog10().map_or(1, |d| d as usize + 1)
+}
+
+/// Whether decimal number has leading zeros or is a negative zero, which is not allowed in bencode
+fn is_non_canonical_number(number: &str) -> bool {
+    let digits = number.strip_prefix('-').unwrap_or(number);
+    (digits.len() > 1 && digits.starts_with('0')) || number == "-0"
 }
 
 impl fmt::Debug for BencodeValue {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             BencodeValue::String(s) => match String::from_utf8(s.clone()) {
                 Ok(str) => str.fmt(f),
@@ -70,158 +110,128 @@ impl From<i64> for BencodeValue {
     }
 }
 
+/// Max nesting of lists and dicts accepted by the parser, so that untrusted input cannot exhaust the stack
+pub const MAX_DEPTH: usize = 64;
+
+/// Parse a single bencoded value spanning the whole input
+pub fn parse(bencoded: &[u8]) -> Result<BencodeValue> {
+    let (value, left) = parse_prefix(bencoded)?;
+    if !left.is_empty() {
+        return Err(anyhow!("{} trailing bytes", left.len()));
+    }
+    Ok(value)
+}
+
+/// Parse a bencoded value at the start of the input, returning it with the rest of the input
+pub fn parse_prefix(bencoded: &[u8]) -> Result<(BencodeValue, &[u8])> {
+    parse_value(bencoded, 0)
+}
+
+/// Parse bencoded value at the start of the input. On error, value is `None` and the input is returned as is
 pub fn parse_bencoded(bencoded: ByteString) -> (Option<BencodeValue>, ByteString) {
-    let next = match bencoded.first() {
-        Some(f) => f,
-        _ => return (None, bencoded),
-    };
-    match *next as char {
-        c if c.is_ascii_digit() => parse_string(bencoded),
-        'i' => parse_int(bencoded),
-        'l' => parse_list(bencoded),
-        'd' => parse_dict(bencoded),
-        _ => {
-            error!("unexpected character `{}`", *next as char);
+    match parse_prefix(&bencoded) {
+        Ok((value, left)) => (Some(value), left.to_vec()),
+        Err(e) => {
+            debug!("bencode parsing error: {e:#}");
             (None, bencoded)
         }
     }
 }
 
-/// Format: <string length encoded in base ten ASCII>:<string data>
-pub fn parse_string(bencoded: ByteString) -> (Option<BencodeValue>, ByteString) {
-    let mut i = 0;
-
-    let size_chars = bencoded
-        .iter()
-        .take_while(|c| (**c as char).is_ascii_digit())
-        .map(|d| *d as char)
-        .collect::<String>();
-    let size = match size_chars.parse::<i64>() {
-        Ok(n) => n,
-        _ => return (None, bencoded),
-    };
-    i += size_chars.len();
-
-    if bencoded.get(i).filter(|c| (**c as char) == ':').is_none() {
-        return (None, bencoded);
+fn parse_value(bencoded: &[u8], depth: usize) -> Result<(BencodeValue, &[u8])> {
+    match bencoded.first() {
+        Some(c) if c.is_ascii_digit() => {
+            parse_string(bencoded).map(|(s, left)| (BencodeValue::String(s.to_vec()), left))
+        }
+        Some(b'i') => parse_int(bencoded),
+        Some(b'l' | b'd') if depth >= MAX_DEPTH => Err(anyhow!("nesting is deeper than {MAX_DEPTH}")),
+        Some(b'l') => parse_list(bencoded, depth),
+        Some(b'd') => parse_dict(bencoded, depth),
+        Some(c) => Err(anyhow!("unexpected character `{}`", c.escape_ascii())),
+        None => Err(anyhow!("unexpected end of input")),
     }
-    i += 1;
-
-    let str: ByteString = bencoded
-        .iter()
-        .skip(size_chars.len() + 1)
-        .take(size as usize)
-        .cloned()
-        .collect();
-    i += str.len();
-
-    (
-        Some(BencodeValue::String(str)),
-        bencoded.iter().skip(i).cloned().collect(),
-    )
 }
 
-/// Format: i<integer encoded in base ten ASCII>e
-pub fn parse_int(bencoded: ByteString) -> (Option<BencodeValue>, ByteString) {
-    let mut i = 0;
-
-    if bencoded.get(i).filter(|c| (**c as char) == 'i').is_none() {
-        return (None, bencoded);
+/// Split decimal number terminated by `end` off the input
+fn parse_number(bencoded: &[u8], end: u8) -> Result<(i64, &[u8])> {
+    let pos = bencoded
+        .iter()
+        .position(|c| *c == end)
+        .context(format!("no `{}` after number", end.escape_ascii()))?;
+    let (number, left) = bencoded.split_at(pos);
+    let number = std::str::from_utf8(number).context("number is not ASCII")?;
+    let digits = number.strip_prefix('-').unwrap_or(number);
+    if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return Err(anyhow!("malformed number: {number}"));
+    }
+    if is_non_canonical_number(number) {
+        return Err(anyhow!("non-canonical number: {number}"));
     }
-    i += 1;
+    let number = number
+        .parse::<i64>()
+        .context(format!("number out of range: {number}"))?;
+    Ok((number, &left[1..]))
+}
 
-    let int_chars = bencoded
-        .iter()
-        .skip(i)
-        .map(|c| *c as char)
-        .take_while(|c| c.is_ascii_digit() || *c == '-')
-        .collect::<String>();
-    let int = match int_chars.parse::<i64>() {
-        Ok(int) => int,
-        _ => return (None, bencoded),
-    };
-    i += int_chars.len();
-
-    if bencoded.get(i).filter(|c| (**c as char) == 'e').is_none() {
-        return (None, bencoded);
+/// Format: <string length encoded in base ten ASCII>:<string data>
+fn parse_string(bencoded: &[u8]) -> Result<(&[u8], &[u8])> {
+    let (size, left) = parse_number(bencoded, b':')?;
+    let size = usize::try_from(size).context("negative string length")?;
+    if size > left.len() {
+        return Err(anyhow!("string length {size} exceeds input length {}", left.len()));
     }
-    i += 1;
+    Ok(left.split_at(size))
+}
 
-    (Some(BencodeValue::Int(int)), bencoded.iter().skip(i).cloned().collect())
+/// Format: i<integer encoded in base ten ASCII>e
+fn parse_int(bencoded: &[u8]) -> Result<(BencodeValue, &[u8])> {
+    let (int, left) = parse_number(bencoded.get(1..).unwrap_or_default(), b'e')?;
+    Ok((BencodeValue::Int(int), left))
 }
 
 /// Format: l<bencoded values>e
-pub fn parse_list(bencoded: ByteString) -> (Option<BencodeValue>, ByteString) {
-    let mut i = 0;
+fn parse_list(bencoded: &[u8], depth: usize) -> Result<(BencodeValue, &[u8])> {
     let mut items = vec![];
-
-    if bencoded.get(i).filter(|c| (**c as char) == 'l').is_none() {
-        return (None, bencoded);
-    }
-    i += 1;
-
-    while bencoded.get(i).is_some() && bencoded.get(i).filter(|c| (**c as char) == 'e').is_none() {
-        if let (Some(item), left) = parse_bencoded(bencoded.iter().skip(i).cloned().collect()) {
-            items.push(item);
-            i = bencoded.len() - left.len()
-        } else {
-            break;
+    let mut left = bencoded.get(1..).unwrap_or_default();
+    loop {
+        match left.split_first() {
+            Some((b'e', rest)) => return Ok((BencodeValue::List(items), rest)),
+            Some(_) => {
+                let (item, rest) = parse_value(left, depth + 1)?;
+                items.push(item);
+                left = rest;
+            }
+            None => return Err(anyhow!("unterminated list")),
         }
     }
-
-    if bencoded.get(i).filter(|c| (**c as char) == 'e').is_none() {
-        return (None, bencoded);
-    }
-    i += 1;
-
-    (
-        Some(BencodeValue::List(items)),
-        bencoded.iter().skip(i).cloned().collect(),
-    )
 }
 
 /// Format: d<bencoded string><bencoded element>e
-pub fn parse_dict(bencoded: ByteString) -> (Option<BencodeValue>, ByteString) {
-    let mut i = 0;
+fn parse_dict(bencoded: &[u8], depth: usize) -> Result<(BencodeValue, &[u8])> {
     let mut map: BTreeMap<String, BencodeValue> = BTreeMap::new();
-
-    if bencoded.get(i).filter(|c| (**c as char) == 'd').is_none() {
-        return (None, bencoded);
-    }
-    i += 1;
-
-    while bencoded.get(i).is_some() && bencoded.get(i).filter(|c| (**c as char) == 'e').is_none() {
-        let key = if let (Some(item), left) = parse_bencoded(bencoded.iter().skip(i).cloned().collect()) {
-            i = bencoded.len() - left.len();
-            match item {
-                BencodeValue::String(s) => String::from_utf8_lossy(s.as_slice()).to_string(),
-                _ => return (None, bencoded),
+    let mut left = bencoded.get(1..).unwrap_or_default();
+    loop {
+        match left.split_first() {
+            Some((b'e', rest)) => return Ok((BencodeValue::Dict(map), rest)),
+            Some(_) => {
+                let (key, rest) = parse_string(left).context("malformed dict key")?;
+                let key = String::from_utf8(key.to_vec()).context("dict key is not UTF-8")?;
+                let (value, rest) = parse_value(rest, depth + 1)?;
+                if map.last_key_value().is_some_and(|(last, _)| last >= &key) {
+                    debug!("dict keys are not sorted or not unique: {:?}", key);
+                }
+                map.insert(key, value);
+                left = rest;
             }
-        } else {
-            return (None, bencoded);
-        };
-        let value = if let (Some(item), left) = parse_bencoded(bencoded.iter().skip(i).cloned().collect()) {
-            i = bencoded.len() - left.len();
-            item
-        } else {
-            return (None, bencoded);
-        };
-        map.insert(key, value);
-    }
-
-    if bencoded.get(i).filter(|c| (**c as char) == 'e').is_none() {
-        return (None, bencoded);
+            None => return Err(anyhow!("unterminated dict")),
+        }
     }
-    i += 1;
-
-    (
-        Some(BencodeValue::Dict(map)),
-        bencoded.iter().skip(i).cloned().collect(),
-    )
 }
 
 #[cfg(test)]
 mod test {
+    use rand::{seq::SliceRandom, Rng};
+
     use super::*;
 
     #[test]
@@ -274,4 +284,107 @@ mod test {
         );
         assert!(left.is_empty());
     }
+
+    #[test]
+    fn should_reject_non_canonical_int() {
+        assert_eq!(parse_bencoded(String::into_bytes("i03e".into())).0, None);
+        assert_eq!(parse_bencoded(String::into_bytes("i-0e".into())).0, None);
+        assert_eq!(
+            parse_bencoded(String::into_bytes("i0e".into())).0,
+            Some(BencodeValue::Int(0))
+        );
+    }
+
+    #[test]
+    fn should_reject_non_canonical_string_length() {
+        assert_eq!(parse_bencoded(String::into_bytes("05:hello".into())).0, None);
+        assert_eq!(
+            parse_bencoded(String::into_bytes("0:".into())).0,
+            Some(BencodeValue::String(vec![]))
+        );
+    }
+
+    #[test]
+    fn should_reject_truncated_string() {
+        assert!(parse(b"5:hell").is_err());
+        assert!(parse(b"99999999999999999999:a").is_err());
+        assert_eq!(parse(b"4:hell").unwrap(), BencodeValue::from("hell"));
+        assert!(parse(b"4:hello").is_err());
+    }
+
+    #[test]
+    fn should_limit_depth() {
+        let nested = |depth: usize| ["l".repeat(depth), "e".repeat(depth)].concat().into_bytes();
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(parse(&nested(1 << 20)).is_err());
+    }
+
+    #[test]
+    fn should_not_panic_on_malformed_input() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let mut encoded = random_value(&mut rng, 4).encode();
+            for _ in 0..rng.gen_range(1..4) {
+                let i = rng.gen_range(0..encoded.len());
+                match rng.gen_range(0..3) {
+                    0 => encoded[i] = rng.gen(),
+                    1 => encoded.truncate(i),
+                    _ => encoded.insert(i, *b"ldie:0123456789-".choose(&mut rng).unwrap()),
+                }
+                if encoded.is_empty() {
+                    break;
+                }
+            }
+            let _ = parse(&encoded);
+        }
+    }
+
+    #[test]
+    fn should_compute_encoded_len() {
+        for v in [
+            BencodeValue::Int(0),
+            BencodeValue::Int(9),
+            BencodeValue::Int(10),
+            BencodeValue::Int(-10),
+            BencodeValue::Int(i64::MIN),
+            BencodeValue::Int(i64::MAX),
+            BencodeValue::String(vec![b'a'; 10]),
+            BencodeValue::List(vec![]),
+            BencodeValue::Dict(BTreeMap::new()),
+        ] {
+            assert_eq!(v.encoded_len(), v.encode().len(), "{:?}", v);
+        }
+    }
+
+    fn random_value(rng: &mut impl rand::Rng, depth: u32) -> BencodeValue {
+        match rng.gen_range(0..if depth == 0 { 2 } else { 4 }) {
+            0 => BencodeValue::String((0..rng.gen_range(0..20)).map(|_| rng.gen()).collect()),
+            1 => BencodeValue::Int(rng.gen()),
+            2 => BencodeValue::List((0..rng.gen_range(0..5)).map(|_| random_value(rng, depth - 1)).collect()),
+            _ => BencodeValue::Dict(
+                (0..rng.gen_range(0..5))
+                    .map(|_| {
+                        let key = (0..rng.gen_range(0..8)).map(|_| rng.gen::<char>()).collect();
+                        (key, random_value(rng, depth - 1))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn should_round_trip_random_values() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let value = random_value(&mut rng, 4);
+            let encoded = value.encode();
+            assert_eq!(value.encoded_len(), encoded.len());
+            let (parsed, left) = parse_bencoded(encoded.clone());
+            assert!(left.is_empty());
+            let parsed = parsed.unwrap();
+            assert_eq!(parsed, value);
+            assert_eq!(parsed.encode(), encoded);
+        }
+    }
 }
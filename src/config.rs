@@ -1,14 +1,108 @@
-use std::time::Duration;
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{persist::PersistBackend, selector::PieceSelection};
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, Hash)]
 pub struct Config {
-    pub port: u16,
+    /// Directory where completed torrents are saved
+    pub download_dir: PathBuf,
+    /// Directory where torrents are downloaded to, moved to `download_dir` once complete
+    pub incomplete_dir: Option<PathBuf>,
+    /// Ports to listen for peer connections, first available one is used
+    pub ports: Vec<u16>,
+    /// Try ports in random order instead of sequentially
+    pub random_port: bool,
+    /// Local address to bind outgoing connections to, e.g. address of VPN interface
+    pub bind_address: Option<IpAddr>,
     pub respect_choke: bool,
     pub choke_wait: Duration,
     pub reconnect_wait: Duration,
+    /// Max number of known peers, idle peers over the limit are evicted
+    pub max_peers: usize,
+    /// Max wait between connection attempts to the peer that keeps failing
+    pub max_reconnect_wait: Duration,
+    /// Number of consecutive failed connection attempts after which peer is forgotten
+    pub max_connect_fails: u32,
     pub downloaded_check_wait: Duration,
     pub peer_connect_timeout: Duration,
-    pub piece_request_wait: Duration,
+    /// Max number of peer connections, lowered to fit into the OS file descriptor limit
+    pub max_connections: usize,
+    /// Max number of torrent files kept open for writing
+    pub max_open_files: usize,
+    /// Max number of peers being connected to at once
+    pub max_connecting: usize,
+    /// Max time to exchange handshakes once connected
+    pub handshake_timeout: Duration,
+    /// Max time after handshake for the peer supporting extensions to send extended handshake
+    pub ext_handshake_timeout: Duration,
+    /// Max time after handshake for the peer to send a message other than keep alive
+    pub first_message_timeout: Duration,
+    /// Keep enough outstanding block requests to cover this much time at the peer's download rate
+    pub request_queue_time: Duration,
+    /// How often pieces in flight are rebalanced from slow peers to fast ones, see [crate::scheduler::scheduler_loop]
+    pub schedule_wait: Duration,
+    /// Max number of outstanding requests we accept from a peer, advertised as `reqq` in extended handshake
+    pub reqq: usize,
+    /// Block request without response is considered lost after this timeout
+    pub request_timeout: Duration,
     pub dht_chunk: usize,
     pub dht_min_peers: usize,
+    /// Run DHT peer discovery when fewer than this many peers are connected
+    pub dht_min_connected: usize,
+    pub dht_discover_wait: Duration,
+    /// How often to announce to DHT that we are accepting peers of the torrent
+    pub dht_announce_wait: Duration,
+    /// Send `stopped` event to the tracker when torrent is paused
+    pub announce_on_pause: bool,
+    /// Ban peer after contributing to this many pieces failing hash check
+    pub max_hash_fails: usize,
+    /// Number of piece write retries before giving up
+    pub write_retries: usize,
+    /// Wait before the first write retry, doubled on every next one
+    pub write_retry_wait: Duration,
+    /// Max size in bytes of downloaded blocks held in memory before new piece requests are throttled
+    pub max_piece_buffer: usize,
+    /// Read back every written piece and verify its hash before marking it saved, to catch silent disk corruption
+    pub verify_after_write: bool,
+    /// How often progress of the download is saved to be resumed without re-hashing every piece
+    pub resume_save_wait: Duration,
+    /// Number of random saved pieces re-hashed on resume when torrent files are unchanged
+    pub resume_spot_checks: usize,
+    /// Max size in bytes of pieces cached in memory to serve block requests
+    pub read_cache_size: usize,
+    /// Max percentage of wasted to downloaded bytes, after which endgame requests are no longer duplicated
+    pub max_waste_percent: u32,
+    pub piece_selection: PieceSelection,
+    /// Resolve peer hostnames received from trackers, otherwise such peers are ignored
+    pub resolve_peer_hosts: bool,
+    /// How often torrent progress is logged
+    pub progress_wait: Duration,
+    /// Stop once metainfo is known, without downloading torrent data
+    pub metainfo_only: bool,
+    /// Log connected peers with the progress, see [crate::progress::peer_list]
+    pub peer_list: bool,
+    /// Log piece map with the progress, see [crate::progress::render_piece_map]
+    pub piece_map: bool,
+    /// Record every peer wire message to this JSONL file, see [crate::wire_dump::record]
+    pub wire_dump: Option<PathBuf>,
+    /// Print download summary as JSON on exit
+    pub stats_json: bool,
+    /// Shell command to run once metainfo is known, see [crate::hook::run_hook]
+    pub on_metainfo: Option<String>,
+    /// Shell command to run once torrent is downloaded
+    pub on_complete: Option<String>,
+    /// Shell command to run when download fails
+    pub on_error: Option<String>,
+    pub persist_backend: PersistBackend,
+}
+
+impl Config {
+    /// Directory where data of torrents in progress is written
+    pub fn data_dir(&self) -> &Path {
+        self.incomplete_dir.as_deref().unwrap_or(&self.download_dir)
+    }
 }
@@ -0,0 +1,15 @@
+/// CRC-32C (Castagnoli) checksum, used by BEP-42 node id generation
+pub fn checksum(value: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in value {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
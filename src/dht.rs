@@ -1,45 +1,363 @@
+use core::fmt;
 use std::{
     cmp,
-    collections::{BTreeSet, VecDeque},
-    time::Duration,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    mem,
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Result};
 use futures::{stream::FuturesUnordered, StreamExt};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use tokio::time::timeout;
+use tokio::{
+    sync::Mutex,
+    time::{sleep, timeout},
+};
 
 use crate::{
-    bencode::{parse_bencoded, BencodeValue},
+    bencode::{self, BencodeValue},
+    crc32c,
     hex::hex,
-    state::PeerInfo,
+    persist::PersistState,
+    state::{PeerInfo, PeerStatus, State},
+    stats::PeerSource,
     types::ByteString,
     udp::send_udp,
 };
 
-pub async fn find_peers(
+/// KRPC error codes, see [BEP-5](https://www.bittorrent.org/beps/bep_0005.html#errors)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KrpcErrorCode {
+    Generic,
+    Server,
+    Protocol,
+    MethodUnknown,
+    Other(i64),
+}
+
+impl From<i64> for KrpcErrorCode {
+    fn from(value: i64) -> Self {
+        match value {
+            201 => KrpcErrorCode::Generic,
+            202 => KrpcErrorCode::Server,
+            203 => KrpcErrorCode::Protocol,
+            204 => KrpcErrorCode::MethodUnknown,
+            c => KrpcErrorCode::Other(c),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DhtError {
+    /// Node responded with KRPC error message
+    Krpc { code: KrpcErrorCode, message: String },
+    /// Response transaction id does not match the query one
+    TransactionMismatch,
+    /// Response is not a valid KRPC message or not a response to the sent query
+    Malformed(String),
+}
+
+impl DhtError {
+    /// Whether node sending this error should not be queried again
+    pub fn is_garbage(&self) -> bool {
+        !matches!(self, DhtError::Krpc { .. })
+    }
+}
+
+impl fmt::Display for DhtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DhtError::Krpc { code, message } => write!(f, "krpc error {:?}: {}", code, message),
+            DhtError::TransactionMismatch => f.write_str("transaction id mismatch"),
+            DhtError::Malformed(reason) => write!(f, "malformed krpc response: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DhtError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DhtNode {
+    pub id: ByteString,
+    pub info: PeerInfo,
+}
+
+impl TryFrom<&[u8]> for DhtNode {
+    type Error = anyhow::Error;
+
+    /// Parse compact node info: <20 byte node id><6 byte compact peer info>
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() != 26 {
+            return Err(anyhow!("expected 26 byte slice"));
+        }
+        Ok(DhtNode {
+            id: value[0..20].to_vec(),
+            info: PeerInfo::try_from(&value[20..26])?,
+        })
+    }
+}
+
+/// Successful KRPC response
+#[derive(Clone, Debug, PartialEq)]
+pub struct KrpcResponse {
+    /// Response `r` dict
+    pub body: BTreeMap<String, BencodeValue>,
+    /// Our external ip as seen by the queried node, see BEP-42
+    pub ip: Option<Ipv4Addr>,
+}
+
+/// Response to `get_peers` query
+struct GetPeersResponse {
+    /// Either found peers or nodes closer to the info hash
+    result: Result<Vec<PeerInfo>, Vec<DhtNode>>,
+    /// Our external ip as seen by the queried node
+    ip: Option<Ipv4Addr>,
+    /// Bloom filters of seeds and peers, present if scrape is requested, see BEP-33
+    scrape: Option<(ScrapeBloom, ScrapeBloom)>,
+    /// Token required to announce to the node
+    token: Option<ByteString>,
+}
+
+/// Number of nodes to announce to, closest ones returning a token
+const ANNOUNCE_NODES: usize = 8;
+
+/// DHT client, querying the network starting from the known nodes
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dht {
+    pub node_id: ByteString,
+    pub bind_address: Option<IpAddr>,
+    /// Number of nodes queried concurrently
+    pub chunk: usize,
+    /// Nodes the lookup starts from, in order of preference
+    nodes: Vec<PeerInfo>,
+    /// Results of node queries made by peer lookups
+    pub health: NodeHealth,
+}
+
+/// Nodes that responded to queries and ones that failed to
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NodeHealth {
+    pub responded: BTreeSet<PeerInfo>,
+    pub failed: BTreeSet<PeerInfo>,
+}
+
+impl Dht {
+    pub fn new(
+        node_id: ByteString,
+        nodes: impl IntoIterator<Item = PeerInfo>,
+        bind_address: Option<IpAddr>,
+        chunk: usize,
+    ) -> Dht {
+        Dht {
+            node_id,
+            bind_address,
+            chunk,
+            nodes: nodes.into_iter().collect::<Vec<_>>(),
+            health: NodeHealth::default(),
+        }
+    }
+
+    /// Number of known nodes
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Find at least `min` peers of the info hash, returning found peers and our external ip reported by the majority
+    /// of nodes
+    pub async fn get_peers(
+        &mut self,
+        info_hash: ByteString,
+        min: usize,
+    ) -> Result<(BTreeSet<PeerInfo>, Option<Ipv4Addr>)> {
+        find_peers(
+            self.nodes.clone(),
+            self.node_id.clone(),
+            self.bind_address,
+            info_hash,
+            min,
+            self.chunk,
+            &mut self.health,
+        )
+        .await
+    }
+
+    /// Announce that we are downloading the info hash and accept peer connections on the port.
+    /// Returns number of nodes that accepted the announce
+    pub async fn announce(&self, info_hash: ByteString, port: u16) -> Result<usize> {
+        let mut tokens = vec![];
+        let mut queried = BTreeSet::new();
+        let mut queue = self.nodes.iter().cloned().collect::<VecDeque<_>>();
+        while !queue.is_empty() && tokens.len() < ANNOUNCE_NODES {
+            let chunk = queue
+                .drain(..cmp::min(queue.len(), self.chunk))
+                .filter(|p| queried.insert(p.clone()))
+                .collect::<Vec<_>>();
+            let mut handles = chunk
+                .into_iter()
+                .map(|p| async {
+                    let res = find_peers_single(
+                        p.clone(),
+                        self.node_id.clone(),
+                        self.bind_address,
+                        info_hash.clone(),
+                        false,
+                    )
+                    .await;
+                    (p, res)
+                })
+                .collect::<FuturesUnordered<_>>();
+            while let Some((node, res)) = handles.next().await {
+                match res {
+                    Ok(resp) => {
+                        if let Some(token) = resp.token {
+                            tokens.push((node, token));
+                        }
+                        if let Err(nodes) = resp.result {
+                            queue.extend(nodes.into_iter().map(|n| n.info).filter(|n| !queried.contains(n)));
+                        }
+                    }
+                    Err(e) => trace!("dht error: {e:#}"),
+                }
+            }
+        }
+        let info_hash = &info_hash;
+        let announced = tokens
+            .into_iter()
+            .take(ANNOUNCE_NODES)
+            .map(|(node, token)| async move {
+                timeout(
+                    Duration::from_millis(500),
+                    announce_peer(&node, &self.node_id, self.bind_address, info_hash, port, token),
+                )
+                .await
+            })
+            .collect::<FuturesUnordered<_>>()
+            .filter(|res| futures::future::ready(matches!(res, Ok(Ok(_)))))
+            .count()
+            .await;
+        debug!("announced to {} dht nodes", announced);
+        Ok(announced)
+    }
+}
+
+/// Bloom filter of BEP-33 scrape response
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScrapeBloom(pub Vec<u8>);
+
+impl Default for ScrapeBloom {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScrapeBloom {
+    const BITS: f64 = 2048.;
+
+    pub fn new() -> ScrapeBloom {
+        ScrapeBloom(vec![0; 256])
+    }
+
+    pub fn merge(&mut self, other: &ScrapeBloom) {
+        self.0.iter_mut().zip(&other.0).for_each(|(a, b)| *a |= b);
+    }
+
+    /// Estimated number of items inserted into the filter
+    pub fn estimate(&self) -> f64 {
+        let zeros = self.0.iter().map(|b| b.count_zeros()).sum::<u32>().max(1) as f64;
+        (zeros / Self::BITS).ln() / (2. * (1. - 1. / Self::BITS).ln())
+    }
+}
+
+impl TryFrom<&BencodeValue> for ScrapeBloom {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &BencodeValue) -> Result<Self, Self::Error> {
+        match value {
+            BencodeValue::String(s) if s.len() == 256 => Ok(ScrapeBloom(s.clone())),
+            _ => Err(anyhow!("bloom filter is not a 256 byte string")),
+        }
+    }
+}
+
+/// Generate node id from external ip, see [BEP-42](https://www.bittorrent.org/beps/bep_0042.html)
+pub fn generate_node_id(ip: Ipv4Addr) -> ByteString {
+    let mut id: ByteString = thread_rng().gen::<[u8; 20]>().to_vec();
+    let crc = node_id_crc(ip, id[19]);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x07);
+    id
+}
+
+/// Check whether node id is valid for the given ip according to BEP-42. Nodes from local networks are always valid
+pub fn is_valid_node_id(id: &[u8], ip: Ipv4Addr) -> bool {
+    if ip.is_private() || ip.is_loopback() || ip.is_link_local() {
+        return true;
+    }
+    if id.len() != 20 {
+        return false;
+    }
+    let crc = node_id_crc(ip, id[19]);
+    id[0] == (crc >> 24) as u8 && id[1] == (crc >> 16) as u8 && id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+fn node_id_crc(ip: Ipv4Addr, rand: u8) -> u32 {
+    let mask = [0x03, 0x0f, 0x3f, 0xff];
+    let mut ip = ip.octets();
+    ip.iter_mut().zip(mask).for_each(|(b, m)| *b &= m);
+    ip[0] |= (rand & 0x07) << 5;
+    crc32c::checksum(&ip)
+}
+
+/// Find peers for the info hash, returning found peers and our external ip reported by the majority of nodes
+async fn find_peers(
     dht_peers: Vec<PeerInfo>,
-    peer_id: ByteString,
+    node_id: ByteString,
+    bind_address: Option<IpAddr>,
     info_hash: ByteString,
     min: usize,
     dht_chunk: usize,
-) -> Result<BTreeSet<PeerInfo>> {
+    health: &mut NodeHealth,
+) -> Result<(BTreeSet<PeerInfo>, Option<Ipv4Addr>)> {
     let mut peers = BTreeSet::new();
+    let mut queried = BTreeSet::new();
+    let mut blacklist = BTreeSet::new();
+    let mut ip_votes: BTreeMap<Ipv4Addr, usize> = BTreeMap::new();
+    let external_ip = |votes: &BTreeMap<Ipv4Addr, usize>| votes.iter().max_by_key(|(_, v)| **v).map(|(ip, _)| *ip);
     let mut queue = VecDeque::from(dht_peers);
     loop {
         debug!("dht queue: {} nodes", queue.len());
 
-        let chunk = queue.drain(..cmp::min(queue.len(), dht_chunk)).collect::<Vec<_>>();
+        let chunk = queue
+            .drain(..cmp::min(queue.len(), dht_chunk))
+            .filter(|p| !blacklist.contains(p) && queried.insert(p.clone()))
+            .collect::<Vec<_>>();
         if chunk.is_empty() {
-            break;
+            if queue.is_empty() {
+                break;
+            }
+            continue;
         }
 
         let mut handles = chunk
             .into_iter()
-            .map(|p| find_peers_single(p.clone(), peer_id.clone(), info_hash.clone()))
+            .map(|p| async {
+                let res = find_peers_single(p.clone(), node_id.clone(), bind_address, info_hash.clone(), false).await;
+                (p, res)
+            })
             .collect::<FuturesUnordered<_>>();
-        while let Some(res) = handles.next().await {
-            match res {
+        while let Some((node, res)) = handles.next().await {
+            if let Ok(GetPeersResponse { ip: Some(ip), .. }) = res {
+                *ip_votes.entry(ip).or_default() += 1;
+            }
+            if res.is_ok() {
+                health.responded.insert(node.clone());
+            } else {
+                health.failed.insert(node.clone());
+            }
+            match res.map(|r| r.result) {
                 Ok(Ok(values)) => {
                     let found = values.len();
                     let before = peers.len();
@@ -54,54 +372,140 @@ pub async fn find_peers(
                         min
                     );
                     if peers.len() >= min {
-                        return Ok(peers);
+                        return Ok((peers, external_ip(&ip_votes)));
                     }
                 }
                 Ok(Err(nodes)) => {
                     for n in nodes {
-                        if !queue.contains(&n) {
-                            queue.insert(0, n);
+                        if queue.contains(&n.info) || queried.contains(&n.info) || blacklist.contains(&n.info) {
+                            continue;
+                        }
+                        // prefer nodes following BEP-42, since they are more likely to be accepted by the network
+                        match n.info.ip {
+                            IpAddr::V4(ip) if is_valid_node_id(&n.id, ip) => queue.push_front(n.info),
+                            _ => queue.push_back(n.info),
                         }
                     }
                 }
                 Err(e) => {
-                    trace!("dht error: {e:#}");
+                    if e.downcast_ref::<DhtError>().is_some_and(|e| e.is_garbage()) {
+                        debug!("blacklisting dht node {:?}: {e:#}", node);
+                        blacklist.insert(node);
+                    } else {
+                        trace!("dht error: {e:#}");
+                    }
                 }
             }
         }
     }
 
     debug!("dht queue exhausted, found {} peers", peers.len());
-    Ok(peers)
+    Ok((peers, external_ip(&ip_votes)))
+}
+
+/// Keep discovering peers via DHT while torrent is active, adding new peers when connected peer count is low.
+/// DHT nodes discovered from peers are persisted as they are found
+pub async fn dht_loop(state: Arc<Mutex<State>>, p_state: Arc<Mutex<PersistState>>) {
+    let config = state.lock().await.config.clone();
+    let mut last_announce: Option<Instant> = None;
+    loop {
+        sleep(config.dht_discover_wait).await;
+        let (node_id, info_hash, port, new_nodes, connected) = {
+            let mut state = state.lock().await;
+            (
+                state.dht_node_id.clone(),
+                state.info_hash.clone(),
+                state.port,
+                // pinged nodes are credited once, later responses are counted by query results
+                mem::take(&mut state.dht_nodes),
+                state
+                    .peers
+                    .values()
+                    .filter(|p| p.status == PeerStatus::Connected)
+                    .count(),
+            )
+        };
+        let nodes = {
+            let mut p_state = p_state.lock().await;
+            if p_state.seen_dht_nodes(new_nodes) {
+                if let Err(e) = p_state.save() {
+                    debug!("{:#}", e.context("persist state save error"));
+                }
+            }
+            p_state.dht_nodes()
+        };
+        let mut dht = Dht::new(node_id, nodes, config.bind_address, config.dht_chunk);
+        // we won't have any data to serve in metainfo only mode
+        if !config.metainfo_only && last_announce.is_none_or(|t| t.elapsed() >= config.dht_announce_wait) {
+            last_announce = Some(Instant::now());
+            if let Err(e) = dht.announce(info_hash.clone(), port).await {
+                debug!("dht announce error: {e:#}");
+            }
+        }
+        if connected >= config.dht_min_connected {
+            trace!("enough peers connected, skipping dht discovery");
+            continue;
+        }
+
+        debug!("{} peers connected, discovering dht peers", connected);
+        let res = dht.get_peers(info_hash, config.dht_min_peers).await;
+        p_state.lock().await.update_dht_nodes(&dht.health);
+        match res {
+            Ok((peers, external_ip)) => {
+                let mut state = state.lock().await;
+                let new_peers = state.add_peers(peers, PeerSource::Dht);
+                info!("received {} new peers via dht", new_peers);
+                if external_ip.is_some() {
+                    state.external_ip = external_ip;
+                }
+            }
+            Err(e) => debug!("dht discovery error: {e:#}"),
+        }
+    }
+}
+
+/// Ping node and remember it as a good DHT node if it responds
+pub async fn add_dht_node(state: Arc<Mutex<State>>, node: PeerInfo) {
+    let (node_id, bind_address) = {
+        let state = state.lock().await;
+        (state.dht_node_id.clone(), state.config.bind_address)
+    };
+    // TODO: make configurable
+    match timeout(Duration::from_millis(500), ping(&node, &node_id, bind_address)).await {
+        Ok(Ok(_)) => {
+            debug!("dht node is alive: {:?}", node);
+            state.lock().await.dht_nodes.insert(node);
+        }
+        Ok(Err(e)) => trace!("dht ping error: {e:#}"),
+        Err(_) => trace!("dht ping timeout: {:?}", node),
+    }
 }
 
 async fn find_peers_single(
     peer: PeerInfo,
-    peer_id: ByteString,
+    node_id: ByteString,
+    bind_address: Option<IpAddr>,
     info_hash: ByteString,
-) -> Result<Result<Vec<PeerInfo>, Vec<PeerInfo>>> {
+    scrape: bool,
+) -> Result<GetPeersResponse> {
     trace!("quering dht peer: {:?}", peer);
-    let res = timeout(
+    let KrpcResponse { body: r_dict, ip } = timeout(
         // TODO: make configurable
         Duration::from_millis(500),
-        dht_find_peers(&peer, &peer_id, info_hash.clone()),
+        dht_find_peers(&peer, &node_id, bind_address, info_hash.clone(), scrape),
     )
     .await??;
-    let dict = match res {
-        BencodeValue::Dict(dict) => dict,
-        _ => return Err(anyhow!("response is not a dict")),
+    let scrape = match (r_dict.get("BFsd"), r_dict.get("BFpe")) {
+        (Some(seeds), Some(peers)) => Some((ScrapeBloom::try_from(seeds)?, ScrapeBloom::try_from(peers)?)),
+        _ => None,
     };
-
-    if matches!(dict.get("y"),  Some(BencodeValue::String(s)) if s == "e".as_bytes()) {
-        debug!("krpc error: {:?}", dict);
-    }
-    let r_dict = match dict.get("r") {
-        Some(BencodeValue::Dict(d)) => d,
-        _ => return Err(anyhow!("no response dict")),
+    let token = match r_dict.get("token") {
+        Some(BencodeValue::String(t)) => Some(t.clone()),
+        _ => None,
     };
 
     if let Some(BencodeValue::List(vs)) = r_dict.get("values") {
-        return Ok(Ok(vs
+        let values = vs
             .iter()
             .map(|b_v| {
                 let v = match b_v {
@@ -110,28 +514,139 @@ async fn find_peers_single(
                 };
                 PeerInfo::try_from(v.as_slice())
             })
-            .collect::<Result<Vec<PeerInfo>>>()?));
+            .collect::<Result<Vec<PeerInfo>>>()?;
+        return Ok(GetPeersResponse {
+            result: Ok(values),
+            ip,
+            scrape,
+            token,
+        });
     }
 
-    if let Some(BencodeValue::String(ns_str)) = r_dict.get("nodes") {
-        if ns_str.len() % 6 != 0 {
-            trace!("nodes string length is weird: {}", hex(ns_str));
+    if let Some(nodes) = parse_nodes(&r_dict)? {
+        return Ok(GetPeersResponse {
+            result: Err(nodes),
+            ip,
+            scrape,
+            token,
+        });
+    }
+
+    Err(DhtError::Malformed("no `values` or `nodes`".into()).into())
+}
+
+fn parse_nodes(r_dict: &BTreeMap<String, BencodeValue>) -> Result<Option<Vec<DhtNode>>> {
+    match r_dict.get("nodes") {
+        Some(BencodeValue::String(ns_str)) => {
+            if ns_str.len() % 26 != 0 {
+                trace!("nodes string length is weird: {}", hex(ns_str));
+            }
+            Ok(Some(
+                ns_str
+                    .chunks_exact(26)
+                    .map(DhtNode::try_from)
+                    .collect::<Result<Vec<DhtNode>>>()?,
+            ))
         }
-        return Ok(Err(ns_str
-            .chunks_exact(6)
-            .map(PeerInfo::try_from)
-            .collect::<Result<Vec<PeerInfo>>>()?));
+        _ => Ok(None),
     }
+}
 
-    Err(anyhow!("malformed dht response"))
+/// Estimate number of seeds and peers of the info hash, merging BEP-33 scrape responses of up to `max_responses`
+/// nodes
+pub async fn scrape(
+    dht_peers: Vec<PeerInfo>,
+    node_id: ByteString,
+    bind_address: Option<IpAddr>,
+    info_hash: ByteString,
+    max_responses: usize,
+    dht_chunk: usize,
+) -> Result<(f64, f64)> {
+    let mut seeds = ScrapeBloom::new();
+    let mut peers = ScrapeBloom::new();
+    let mut responses = 0;
+    let mut queried = BTreeSet::new();
+    let mut queue = VecDeque::from(dht_peers);
+    while !queue.is_empty() && responses < max_responses {
+        let chunk = queue
+            .drain(..cmp::min(queue.len(), dht_chunk))
+            .filter(|p| queried.insert(p.clone()))
+            .collect::<Vec<_>>();
+        let mut handles = chunk
+            .into_iter()
+            .map(|p| find_peers_single(p, node_id.clone(), bind_address, info_hash.clone(), true))
+            .collect::<FuturesUnordered<_>>();
+        while let Some(res) = handles.next().await {
+            match res {
+                Ok(resp) => {
+                    if let Some((s, p)) = resp.scrape {
+                        seeds.merge(&s);
+                        peers.merge(&p);
+                        responses += 1;
+                        debug!("scrape response {}/{}", responses, max_responses);
+                    }
+                    if let Err(nodes) = resp.result {
+                        queue.extend(nodes.into_iter().map(|n| n.info).filter(|n| !queried.contains(n)));
+                    }
+                }
+                Err(e) => trace!("dht error: {e:#}"),
+            }
+        }
+    }
+    debug!("scrape done with {} responses", responses);
+    Ok((seeds.estimate(), peers.estimate()))
 }
 
-async fn dht_find_peers(peer: &PeerInfo, peer_id: &ByteString, info_hash: ByteString) -> Result<BencodeValue> {
-    let tx_id = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(2)
-        .map(char::from)
-        .collect::<String>();
+/// Request random sample of info hashes stored by the node, returning samples and nodes closer to the target,
+/// see [BEP-51](https://www.bittorrent.org/beps/bep_0051.html)
+pub async fn sample_infohashes(
+    peer: &PeerInfo,
+    node_id: &ByteString,
+    bind_address: Option<IpAddr>,
+    target: ByteString,
+) -> Result<(Vec<ByteString>, Vec<DhtNode>)> {
+    let tx_id = generate_tx_id();
+    let req = BencodeValue::Dict(
+        [
+            ("t".into(), BencodeValue::from(tx_id.as_str())),
+            ("y".into(), BencodeValue::from("q")),
+            ("q".into(), BencodeValue::from("sample_infohashes")),
+            (
+                "a".into(),
+                BencodeValue::Dict(
+                    [
+                        ("id".into(), BencodeValue::String(node_id.clone())),
+                        ("target".into(), BencodeValue::String(target)),
+                    ]
+                    .into_iter()
+                    .collect(),
+                ),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+    );
+    // TODO: make configurable
+    let resp = timeout(
+        Duration::from_millis(500),
+        send_krpc(peer, bind_address, tx_id.as_bytes(), &req),
+    )
+    .await??;
+    let samples = match resp.body.get("samples") {
+        Some(BencodeValue::String(s)) => s.chunks_exact(20).map(|c| c.to_vec()).collect(),
+        _ => vec![],
+    };
+    Ok((samples, parse_nodes(&resp.body)?.unwrap_or_default()))
+}
+
+async fn dht_find_peers(
+    peer: &PeerInfo,
+    node_id: &ByteString,
+    bind_address: Option<IpAddr>,
+    info_hash: ByteString,
+    scrape: bool,
+) -> Result<KrpcResponse> {
+    let tx_id = generate_tx_id();
     let req = BencodeValue::Dict(
         [
             ("t".into(), BencodeValue::from(tx_id.as_str())),
@@ -141,10 +656,11 @@ async fn dht_find_peers(peer: &PeerInfo, peer_id: &ByteString, info_hash: ByteSt
                 "a".into(),
                 BencodeValue::Dict(
                     [
-                        ("id".into(), BencodeValue::String(peer_id.clone())),
+                        ("id".into(), BencodeValue::String(node_id.clone())),
                         ("info_hash".into(), BencodeValue::String(info_hash)),
                     ]
                     .into_iter()
+                    .chain(scrape.then(|| ("scrape".into(), BencodeValue::from(1))))
                     .collect(),
                 ),
             ),
@@ -152,17 +668,168 @@ async fn dht_find_peers(peer: &PeerInfo, peer_id: &ByteString, info_hash: ByteSt
         .into_iter()
         .collect(),
     );
-    // TODO: verify tx_id
-    send_krpc(peer, &req).await
+    send_krpc(peer, bind_address, tx_id.as_bytes(), &req).await
 }
 
-async fn send_krpc(peer: &PeerInfo, request: &BencodeValue) -> Result<BencodeValue> {
+async fn announce_peer(
+    peer: &PeerInfo,
+    node_id: &ByteString,
+    bind_address: Option<IpAddr>,
+    info_hash: &ByteString,
+    port: u16,
+    token: ByteString,
+) -> Result<KrpcResponse> {
+    let tx_id = generate_tx_id();
+    let req = BencodeValue::Dict(
+        [
+            ("t".into(), BencodeValue::from(tx_id.as_str())),
+            ("y".into(), BencodeValue::from("q")),
+            ("q".into(), BencodeValue::from("announce_peer")),
+            (
+                "a".into(),
+                BencodeValue::Dict(
+                    [
+                        ("id".into(), BencodeValue::String(node_id.clone())),
+                        ("info_hash".into(), BencodeValue::String(info_hash.clone())),
+                        ("port".into(), BencodeValue::from(port as i64)),
+                        ("token".into(), BencodeValue::String(token)),
+                    ]
+                    .into_iter()
+                    .collect(),
+                ),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+    );
+    send_krpc(peer, bind_address, tx_id.as_bytes(), &req).await
+}
+
+async fn ping(peer: &PeerInfo, node_id: &ByteString, bind_address: Option<IpAddr>) -> Result<KrpcResponse> {
+    let tx_id = generate_tx_id();
+    let req = BencodeValue::Dict(
+        [
+            ("t".into(), BencodeValue::from(tx_id.as_str())),
+            ("y".into(), BencodeValue::from("q")),
+            ("q".into(), BencodeValue::from("ping")),
+            (
+                "a".into(),
+                BencodeValue::Dict(
+                    [("id".into(), BencodeValue::String(node_id.clone()))]
+                        .into_iter()
+                        .collect(),
+                ),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+    );
+    send_krpc(peer, bind_address, tx_id.as_bytes(), &req).await
+}
+
+fn generate_tx_id() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(2)
+        .map(char::from)
+        .collect::<String>()
+}
+
+/// Send KRPC query and return the response, verifying that it matches the query
+async fn send_krpc(
+    peer: &PeerInfo,
+    bind_address: Option<IpAddr>,
+    tx_id: &[u8],
+    request: &BencodeValue,
+) -> Result<KrpcResponse> {
     let packet = request.encode();
-    let addr = peer.to_addr();
+    let addr = peer.to_addr().to_string();
     trace!("krpc request: {:?}", packet);
-    let (resp, _) = send_udp(&addr, &packet).await?;
+    let (resp, _) = send_udp(&addr, bind_address, &packet).await?;
     trace!("krpc response: {:?}", resp);
-    let dict = parse_bencoded(resp).0.context("bencode error")?;
+    decode_krpc(&resp, tx_id)
+}
+
+/// Decode KRPC response packet to the query with transaction id `tx_id`
+pub fn decode_krpc(packet: &[u8], tx_id: &[u8]) -> Result<KrpcResponse> {
+    let dict = match bencode::parse(packet) {
+        Ok(BencodeValue::Dict(dict)) => dict,
+        _ => return Err(DhtError::Malformed("response is not a dict".into()).into()),
+    };
     trace!("krpc response dict: {:?}", dict);
-    Ok(dict)
+    match dict.get("t") {
+        Some(BencodeValue::String(t)) if t == tx_id => {}
+        _ => return Err(DhtError::TransactionMismatch.into()),
+    }
+    match dict.get("y") {
+        Some(BencodeValue::String(y)) if y == "r".as_bytes() => match dict.get("r") {
+            Some(BencodeValue::Dict(r)) if r.contains_key("id") => Ok(KrpcResponse {
+                body: r.clone(),
+                ip: match dict.get("ip") {
+                    Some(BencodeValue::String(ip)) if ip.len() >= 4 => Some(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])),
+                    _ => None,
+                },
+            }),
+            _ => Err(DhtError::Malformed("no response dict".into()).into()),
+        },
+        Some(BencodeValue::String(y)) if y == "e".as_bytes() => Err(parse_krpc_error(dict.get("e")).into()),
+        _ => Err(DhtError::Malformed("unexpected message type".into()).into()),
+    }
+}
+
+fn parse_krpc_error(value: Option<&BencodeValue>) -> DhtError {
+    match value {
+        Some(BencodeValue::List(l)) => match l.as_slice() {
+            [BencodeValue::Int(code), BencodeValue::String(message)] => DhtError::Krpc {
+                code: KrpcErrorCode::from(*code),
+                message: String::from_utf8_lossy(message).into(),
+            },
+            _ => DhtError::Malformed("unexpected error list".into()),
+        },
+        _ => DhtError::Malformed("no error list".into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hex::from_hex;
+
+    #[test]
+    fn should_decode_krpc_response() {
+        let resp = b"d2:ip4:\x01\x02\x03\x041:rd2:id20:abcdefghij0123456789e1:t2:aa1:y1:re";
+        let decoded = decode_krpc(resp, b"aa").unwrap();
+        assert_eq!(decoded.ip, Some(Ipv4Addr::new(1, 2, 3, 4)));
+        assert!(decoded.body.contains_key("id"));
+        assert!(decode_krpc(resp, b"ab").is_err());
+        for i in 0..resp.len() {
+            assert!(decode_krpc(&resp[..i], b"aa").is_err());
+        }
+        let error = b"d1:eli201e7:Generice1:t2:aa1:y1:ee";
+        assert!(decode_krpc(error, b"aa").is_err());
+    }
+
+    #[test]
+    fn should_validate_bep42_node_ids() {
+        let cases = [
+            ("124.31.75.21", "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401"),
+            ("21.75.31.124", "5a3ce9c14e7a08645677bbd1cfe7d8f956d53256"),
+            ("65.23.51.170", "a5d43220bc8f112a3d426c84764f8c2a1150e616"),
+            ("84.124.73.14", "1b0321dd1bb1fe518101ceef99462b947a01ff41"),
+            ("43.213.53.83", "e56f6cbf5b7c4be0237986d5243b87aa6d51305a"),
+        ];
+        for (ip, id) in cases {
+            assert!(is_valid_node_id(&from_hex(id), ip.parse().unwrap()));
+        }
+        assert!(!is_valid_node_id(
+            &from_hex("5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401"),
+            "21.75.31.124".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn should_generate_valid_node_id() {
+        let ip = "124.31.75.21".parse().unwrap();
+        assert!(is_valid_node_id(&generate_node_id(ip), ip));
+    }
 }
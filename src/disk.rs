@@ -0,0 +1,276 @@
+use core::fmt;
+use std::{
+    collections::BTreeMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::{ensure, Context, Result};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{Mutex, OwnedMutexGuard},
+};
+
+/// Torrent files opened for writing. Every file is guarded by its own lock, so that concurrent piece writes to the
+/// same file are serialized. At most `max_open_files` are kept open, least recently used idle ones are closed
+#[derive(Clone)]
+pub struct Disk {
+    files: Arc<Mutex<BTreeMap<PathBuf, Arc<Mutex<OpenFile>>>>>,
+    max_open_files: usize,
+    read_cache: Arc<Mutex<ReadCache>>,
+}
+
+/// LRU cache of pieces read from disk to serve block requests
+#[derive(Debug, Default)]
+struct ReadCache {
+    /// Max total size in bytes of cached pieces
+    capacity: usize,
+    /// Map of <piece index> -> <last use, piece data>
+    pieces: BTreeMap<u32, (u64, Vec<u8>)>,
+    /// Use counter, incremented on every cache access
+    clock: u64,
+}
+
+impl ReadCache {
+    fn get(&mut self, index: u32) -> Option<&[u8]> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.pieces.get_mut(&index).map(|(used, data)| {
+            *used = clock;
+            data.as_slice()
+        })
+    }
+
+    /// Insert piece, evicting least recently used pieces to fit into capacity. Pieces larger than capacity are not
+    /// cached
+    fn insert(&mut self, index: u32, data: Vec<u8>) {
+        if data.len() > self.capacity {
+            return;
+        }
+        while self.size() + data.len() > self.capacity {
+            let lru = match self.pieces.iter().min_by_key(|(_, (used, _))| *used) {
+                Some((i, _)) => *i,
+                _ => break,
+            };
+            trace!("evicting piece {} from read cache", lru);
+            self.pieces.remove(&lru);
+        }
+        self.clock += 1;
+        self.pieces.insert(index, (self.clock, data));
+    }
+
+    fn size(&self) -> usize {
+        self.pieces.values().map(|(_, d)| d.len()).sum()
+    }
+}
+
+struct OpenFile {
+    /// File handle, `None` if file is closed to free the descriptor
+    file: Option<File>,
+    last_used: Instant,
+    length: u64,
+    /// Map of written regions <offset> -> <length>
+    written: BTreeMap<u64, u64>,
+}
+
+impl Disk {
+    /// Disk with the read cache of `read_cache_size` bytes
+    pub fn new(read_cache_size: usize, max_open_files: usize) -> Disk {
+        Disk {
+            files: Arc::default(),
+            max_open_files: max_open_files.max(1),
+            read_cache: Arc::new(Mutex::new(ReadCache {
+                capacity: read_cache_size,
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Write data at offset of the file, syncing it to disk once every byte of the file is written
+    pub async fn write(&self, path: &Path, file_length: u64, offset: u64, data: &[u8]) -> Result<()> {
+        let mut file = self.open(path, file_length).await?;
+        let OpenFile {
+            file: handle,
+            length,
+            written,
+            ..
+        } = &mut *file;
+        let handle = handle.as_mut().context("file is closed")?;
+
+        trace!("witing {} bytes at {} of {}", data.len(), offset, path.display());
+        handle.seek(SeekFrom::Start(offset)).await?;
+        handle.write_all(data).await?;
+        let position = handle.stream_position().await?;
+        ensure!(
+            position == offset + data.len() as u64,
+            "partial write of {}: {} bytes at {}",
+            path.display(),
+            position.saturating_sub(offset),
+            offset
+        );
+        written.insert(offset, data.len() as u64);
+
+        if written.values().sum::<u64>() >= *length {
+            handle.sync_all().await.context("file sync error")?;
+            debug!("file is written: {}", path.display());
+        }
+        Ok(())
+    }
+
+    /// Read data at offset of the file
+    pub async fn read(&self, path: &Path, offset: u64, length: usize) -> Result<Vec<u8>> {
+        let mut file = File::open(path)
+            .await
+            .context(format!("unable to open {}", path.display()))?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut data = vec![0; length];
+        file.read_exact(&mut data).await?;
+        Ok(data)
+    }
+
+    /// Read `length` bytes at `begin` of the piece. On cache miss, the whole piece is read from `locations` of
+    /// <path, file offset, length> and cached, since peers usually request every block of the piece
+    pub async fn read_block(
+        &self,
+        piece_index: u32,
+        locations: &[(PathBuf, u64, usize)],
+        begin: usize,
+        length: usize,
+    ) -> Result<Vec<u8>> {
+        let block = |piece: &[u8]| {
+            piece.get(begin..begin + length).map(|b| b.to_vec()).context(format!(
+                "block {}+{} is out of piece {} bounds",
+                begin, length, piece_index
+            ))
+        };
+        if let Some(piece) = self.read_cache.lock().await.get(piece_index) {
+            return block(piece);
+        }
+        trace!("read cache miss, reading piece {}", piece_index);
+        let piece = self.read_locations(locations).await?;
+        let res = block(&piece);
+        self.read_cache.lock().await.insert(piece_index, piece);
+        res
+    }
+
+    /// Read and concatenate file regions of <path, file offset, length>
+    pub async fn read_locations(&self, locations: &[(PathBuf, u64, usize)]) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(locations.iter().map(|(_, _, l)| l).sum());
+        for (path, offset, length) in locations {
+            data.extend(self.read(path, *offset, *length).await?);
+        }
+        Ok(data)
+    }
+
+    /// Create zero-length file, truncating existing one
+    pub async fn create_empty(&self, path: &Path) -> Result<()> {
+        tokio::fs::create_dir_all(&path.parent().context("no parent")?).await?;
+        File::create(path)
+            .await
+            .context(format!("unable to create {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Move file, closing it if it is open. Falls back to copying if files are on different filesystems
+    pub async fn move_file(&self, from: &Path, to: &Path) -> Result<()> {
+        self.files.lock().await.remove(from);
+        tokio::fs::create_dir_all(&to.parent().context("no parent")?).await?;
+        if tokio::fs::rename(from, to).await.is_err() {
+            tokio::fs::copy(from, to).await.context(format!(
+                "unable to copy {} to {}",
+                from.display(),
+                to.display()
+            ))?;
+            tokio::fs::remove_file(from).await?;
+        }
+        Ok(())
+    }
+
+    /// Lock the file, opening it if it is not open yet
+    async fn open(&self, path: &Path, file_length: u64) -> Result<OwnedMutexGuard<OpenFile>> {
+        let mut files = self.files.lock().await;
+        let file = files
+            .entry(path.to_path_buf())
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(OpenFile {
+                    file: None,
+                    last_used: Instant::now(),
+                    length: file_length,
+                    written: BTreeMap::new(),
+                }))
+            })
+            .clone();
+        let mut file = file.lock_owned().await;
+        file.last_used = Instant::now();
+        if file.file.is_none() {
+            Disk::close_idle(&files, self.max_open_files - 1);
+            tokio::fs::create_dir_all(&path.parent().context("no parent")?).await?;
+            let handle = File::options()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)
+                .await?;
+            file.file = Some(handle);
+        }
+        Ok(file)
+    }
+
+    /// Close least recently used files not locked by writers, until at most `max_open` files are open
+    fn close_idle(files: &BTreeMap<PathBuf, Arc<Mutex<OpenFile>>>, max_open: usize) {
+        let mut open = files
+            .iter()
+            .filter_map(|(path, f)| f.try_lock().ok().map(|f| (path, f)))
+            .filter(|(_, f)| f.file.is_some())
+            .collect::<Vec<_>>();
+        // files locked by writers are open too
+        let locked = files.len() - files.values().filter(|f| f.try_lock().is_ok()).count();
+        let total = open.len() + locked;
+        if total <= max_open {
+            return;
+        }
+        open.sort_by_key(|(_, f)| f.last_used);
+        for (path, mut f) in open.into_iter().take(total - max_open) {
+            trace!("closing idle file {}", path.display());
+            f.file = None;
+        }
+    }
+}
+
+impl fmt::Debug for Disk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<disk>")
+    }
+}
+
+impl PartialEq for Disk {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.files, &other.files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_evict_least_recently_used() {
+        let mut cache = ReadCache {
+            capacity: 4,
+            ..Default::default()
+        };
+        cache.insert(0, vec![0; 2]);
+        cache.insert(1, vec![1; 2]);
+        assert!(cache.get(0).is_some());
+        cache.insert(2, vec![2; 2]);
+        assert_eq!(cache.get(0), Some([0, 0].as_slice()));
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get(2), Some([2, 2].as_slice()));
+        cache.insert(3, vec![3; 5]);
+        assert_eq!(cache.get(3), None);
+        assert_eq!(cache.size(), 4);
+    }
+}
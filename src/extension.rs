@@ -6,6 +6,8 @@ use crate::bencode::BencodeValue;
 pub enum Extension {
     Metadata,
     PeerExchange,
+    Holepunch,
+    UploadOnly,
 }
 
 impl Extension {
@@ -13,6 +15,8 @@ impl Extension {
         match self {
             Extension::Metadata => 1,
             Extension::PeerExchange => 2,
+            Extension::Holepunch => 3,
+            Extension::UploadOnly => 4,
         }
     }
 
@@ -20,21 +24,30 @@ impl Extension {
         match &self {
             Extension::Metadata => "ut_metadata".into(),
             Extension::PeerExchange => "ut_pex".into(),
+            Extension::Holepunch => "ut_holepunch".into(),
+            Extension::UploadOnly => "upload_only".into(),
         }
     }
 
-    pub fn handshake(extensions: &[Extension]) -> BencodeValue {
+    /// Extended handshake, `upload_only` signals that we are not interested in downloading,
+    /// see [BEP-21](https://www.bittorrent.org/beps/bep_0021.html), `port` is our listen port, `reqq` is max number of
+    /// outstanding requests we accept
+    pub fn handshake(extensions: &[Extension], upload_only: bool, port: u16, reqq: usize) -> BencodeValue {
         BencodeValue::Dict(
-            [(
-                "m".into(),
-                BencodeValue::Dict(
-                    extensions
-                        .iter()
-                        .enumerate()
-                        .map(|(i, ext)| (ext.name(), BencodeValue::from(i as i64 + 1)))
-                        .collect(),
+            [
+                (
+                    "m".into(),
+                    BencodeValue::Dict(
+                        extensions
+                            .iter()
+                            .map(|ext| (ext.name(), BencodeValue::from(ext.id() as i64)))
+                            .collect(),
+                    ),
                 ),
-            )]
+                ("upload_only".into(), BencodeValue::from(upload_only as i64)),
+                ("p".into(), BencodeValue::from(port as i64)),
+                ("reqq".into(), BencodeValue::from(reqq as i64)),
+            ]
             .into_iter()
             .collect(),
         )
@@ -45,7 +58,7 @@ impl TryFrom<usize> for Extension {
     type Error = Error;
 
     fn try_from(value: usize) -> Result<Self, Self::Error> {
-        [Extension::Metadata]
+        [Extension::Metadata, Extension::Holepunch, Extension::UploadOnly]
             .into_iter()
             .find(|e| e.id() == value)
             .context("unknown id")
@@ -59,6 +72,8 @@ impl TryFrom<&str> for Extension {
         match value {
             "ut_metadata" => Ok(Extension::Metadata),
             "ut_pex" => Ok(Extension::PeerExchange),
+            "ut_holepunch" => Ok(Extension::Holepunch),
+            "upload_only" => Ok(Extension::UploadOnly),
             _ => Err(anyhow!("unknown extension")),
         }
     }
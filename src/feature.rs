@@ -1,9 +1,31 @@
+use std::collections::BTreeSet;
+
+/// Protocol feature advertised in the handshake reserved bytes
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Feature {
-    Dht,
+    /// Azureus messaging protocol
+    AzureusMessaging,
+    /// Extension protocol, see [BEP-10](https://www.bittorrent.org/beps/bep_0010.html)
     Extension,
+    /// Hybrid v1/v2 torrent support, see [BEP-52](https://www.bittorrent.org/beps/bep_0052.html)
+    Hybrid,
+    /// Fast extension, see [BEP-6](https://www.bittorrent.org/beps/bep_0006.html)
+    Fast,
+    Dht,
 }
 
 impl Feature {
+    pub const ALL: [Feature; 5] = [
+        Feature::AzureusMessaging,
+        Feature::Extension,
+        Feature::Hybrid,
+        Feature::Fast,
+        Feature::Dht,
+    ];
+
+    /// Features we advertise. Fast messages are not implemented, so peers must not send them
+    pub const SUPPORTED: [Feature; 2] = [Feature::Dht, Feature::Extension];
+
     pub fn new_with(features: &[Feature]) -> Vec<u8> {
         let mut reserved = vec![0u8; 8];
         for f in features {
@@ -12,10 +34,26 @@ impl Feature {
         reserved
     }
 
+    /// Features enabled in the reserved bytes
+    pub fn parse(reserved: &[u8]) -> BTreeSet<Feature> {
+        Feature::ALL.into_iter().filter(|f| f.enabled(reserved)).collect()
+    }
+
+    /// Features supported by both us and the peer with `features`
+    pub fn negotiate(features: &BTreeSet<Feature>) -> BTreeSet<Feature> {
+        Feature::SUPPORTED
+            .into_iter()
+            .filter(|f| features.contains(f))
+            .collect()
+    }
+
     pub fn bit(&self) -> (usize, u8) {
         match &self {
-            Feature::Dht => (7, 0x01),
+            Feature::AzureusMessaging => (0, 0x80),
             Feature::Extension => (5, 0x10),
+            Feature::Hybrid => (7, 0x10),
+            Feature::Fast => (7, 0x04),
+            Feature::Dht => (7, 0x01),
         }
     }
 
@@ -26,6 +64,28 @@ impl Feature {
 
     pub fn enabled(&self, features: &[u8]) -> bool {
         let (i, b) = self.bit();
-        features[i] & b != 0
+        features.get(i).is_some_and(|f| f & b != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_reserved() {
+        let reserved = [0x80, 0, 0, 0, 0, 0x10, 0, 0x15];
+        assert_eq!(Feature::parse(&reserved), BTreeSet::from(Feature::ALL));
+        assert_eq!(
+            Feature::negotiate(&Feature::parse(&reserved)),
+            BTreeSet::from(Feature::SUPPORTED)
+        );
+        let reserved = Feature::new_with(&[Feature::Fast, Feature::Extension]);
+        assert_eq!(reserved, vec![0, 0, 0, 0, 0, 0x10, 0, 0x04]);
+        assert_eq!(
+            Feature::negotiate(&Feature::parse(&reserved)),
+            BTreeSet::from([Feature::Extension])
+        );
+        assert!(Feature::parse(&[]).is_empty());
     }
 }
@@ -0,0 +1,770 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use rand::{thread_rng, Rng};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    spawn,
+    sync::Mutex,
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::{
+    bencode::{self, BencodeValue},
+    config::Config,
+    extension::Extension,
+    feature::Feature,
+    message::{read_handshake, read_message, Message},
+    metainfo::Metainfo,
+    peer::{generate_peer_id, send_message},
+    peer_metainfo::{PeerMetainfoMessage, METAINFO_PIECE_SIZE},
+    persist::{PersistBackend, PersistState},
+    selector::PieceSelection,
+    session::Session,
+    sha1,
+    state::{Block, PeerInfo},
+    torrent::get_info_hash,
+    types::ByteString,
+};
+
+const SEEDER_PEER_ID: &[u8; 20] = b"-MS0000-000000000000";
+
+/// Connection id handed out by [MockUdpTracker]
+const UDP_CONNECTION_ID: i64 = 0x1234;
+
+/// Announce interval of mock trackers, in seconds
+const ANNOUNCE_INTERVAL: i64 = 60;
+
+/// Single file torrent of random data
+#[derive(Clone, Debug)]
+pub struct MockTorrent {
+    pub metainfo: Metainfo,
+    pub info_hash: ByteString,
+    pub data: Vec<u8>,
+}
+
+impl MockTorrent {
+    pub fn new(name: &str, length: usize, piece_length: usize, announce: Option<&str>) -> Result<MockTorrent> {
+        let mut rng = thread_rng();
+        let data = (0..length).map(|_| rng.gen()).collect::<Vec<u8>>();
+        let pieces = data
+            .chunks(piece_length)
+            .flat_map(|c| sha1::encode(c.to_vec()))
+            .collect();
+        let info = BencodeValue::Dict(
+            [
+                ("length".into(), BencodeValue::from(length as i64)),
+                ("name".into(), BencodeValue::from(name)),
+                ("piece length".into(), BencodeValue::from(piece_length as i64)),
+                ("pieces".into(), BencodeValue::String(pieces)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let dict = BencodeValue::Dict(
+            [("info".into(), info)]
+                .into_iter()
+                .chain(announce.map(|a| ("announce".into(), BencodeValue::from(a))))
+                .collect(),
+        );
+        Ok(MockTorrent {
+            info_hash: get_info_hash(&dict)?,
+            metainfo: Metainfo::try_from(dict)?,
+            data,
+        })
+    }
+
+    /// Bencoded .torrent file
+    pub fn bencoded(&self) -> ByteString {
+        self.metainfo.bencoded.0.clone()
+    }
+
+    /// Bencoded info dict, as transferred by the metadata extension
+    fn info_dict(&self) -> Result<ByteString> {
+        match bencode::parse(&self.metainfo.bencoded.0)? {
+            BencodeValue::Dict(d) => Ok(d.get("info").context("no info")?.encode()),
+            _ => Err(anyhow!("metainfo is not a dict")),
+        }
+    }
+
+    fn bitfield(&self) -> Vec<u8> {
+        let count = self.metainfo.info.pieces.len();
+        (0..count.div_ceil(8))
+            .map(|byte| {
+                (0..8)
+                    .filter(|bit| byte * 8 + bit < count)
+                    .fold(0u8, |acc, bit| acc | (0x80 >> bit))
+            })
+            .collect()
+    }
+
+    fn block(&self, piece_index: u32, begin: u32, length: u32) -> Option<&[u8]> {
+        let start = piece_index as usize * self.metainfo.info.piece_length as usize + begin as usize;
+        self.data.get(start..start + length as usize)
+    }
+}
+
+/// Peer seeding the mock torrent: accepts connections, serves pieces and metainfo
+pub struct MockSeeder {
+    pub addr: SocketAddr,
+    seeder: Arc<Seeder>,
+    task: JoinHandle<()>,
+}
+
+struct Seeder {
+    torrent: MockTorrent,
+    info_dict: ByteString,
+    /// Pieces sent corrupted the next time they are requested
+    corrupt: Mutex<BTreeSet<u32>>,
+    /// Piece index of every served block request
+    served: Mutex<Vec<u32>>,
+}
+
+impl MockSeeder {
+    /// Start seeding. Pieces in `corrupt` are sent with corrupted data the first time they are requested
+    pub async fn start(torrent: &MockTorrent, corrupt: BTreeSet<u32>) -> Result<MockSeeder> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let seeder = Arc::new(Seeder {
+            torrent: torrent.clone(),
+            info_dict: torrent.info_dict()?,
+            corrupt: Mutex::new(corrupt),
+            served: Mutex::new(vec![]),
+        });
+        let task = spawn({
+            let seeder = seeder.clone();
+            async move {
+                // connections are aborted along with the seeder
+                let mut connections = JoinSet::new();
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, addr)) => {
+                            let seeder = seeder.clone();
+                            connections.spawn(async move {
+                                if let Err(e) = serve_peer(stream, seeder).await {
+                                    debug!("mock seeder connection {} closed: {e:#}", addr);
+                                }
+                            });
+                        }
+                        Err(e) => debug!("mock seeder accept error: {e:#}"),
+                    }
+                }
+            }
+        });
+        Ok(MockSeeder { addr, seeder, task })
+    }
+
+    /// Piece index of every served block request, in order
+    pub async fn served(&self) -> Vec<u32> {
+        self.seeder.served.lock().await.clone()
+    }
+
+    pub async fn clear_served(&self) {
+        self.seeder.served.lock().await.clear();
+    }
+}
+
+impl Drop for MockSeeder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve_peer(mut stream: TcpStream, seeder: Arc<Seeder>) -> Result<()> {
+    let torrent = &seeder.torrent;
+    stream.set_nodelay(true)?;
+    match read_handshake(&mut stream).await? {
+        Message::Handshake { info_hash, .. } if info_hash == torrent.info_hash => {}
+        _ => return Err(anyhow!("unexpected handshake")),
+    }
+    let handshake: Vec<u8> = Message::Handshake {
+        info_hash: torrent.info_hash.clone(),
+        peer_id: SEEDER_PEER_ID.to_vec(),
+        reserved: Feature::new_with(&[Feature::Extension]),
+    }
+    .into();
+    stream.write_all(&handshake).await?;
+
+    let (mut r_stream, mut w_stream) = stream.into_split();
+    let ext_handshake = Extension::handshake(&[Extension::Metadata], true, 0, 250);
+    send_message(
+        &mut w_stream,
+        Message::Extended {
+            ext_id: 0,
+            payload: Some(ext_handshake.encode()),
+        },
+    )
+    .await?;
+    send_message(
+        &mut w_stream,
+        Message::Bitfield {
+            bitfield: torrent.bitfield(),
+        },
+    )
+    .await?;
+    send_message(&mut w_stream, Message::Unchoke).await?;
+
+    // id of the metadata extension chosen by the peer
+    let mut metadata_id = None;
+    loop {
+        match read_message(&mut r_stream).await? {
+            Message::Request {
+                piece_index,
+                begin,
+                length,
+            } => {
+                let mut block = torrent
+                    .block(piece_index, begin, length)
+                    .context("requested block is out of bounds")?
+                    .to_vec();
+                if seeder.corrupt.lock().await.remove(&piece_index) {
+                    debug!("mock seeder corrupts piece {}", piece_index);
+                    block.iter_mut().for_each(|b| *b = !*b);
+                }
+                seeder.served.lock().await.push(piece_index);
+                let block = Block(block);
+                send_message(
+                    &mut w_stream,
+                    Message::Piece {
+                        piece_index,
+                        begin,
+                        block,
+                    },
+                )
+                .await?;
+            }
+            Message::Extended {
+                ext_id: 0,
+                payload: Some(payload),
+            } => {
+                metadata_id = match bencode::parse(&payload)? {
+                    BencodeValue::Dict(d) => match d.get("m") {
+                        Some(BencodeValue::Dict(m)) => match m.get(&Extension::Metadata.name()) {
+                            Some(BencodeValue::Int(id)) => u8::try_from(*id).ok(),
+                            _ => None,
+                        },
+                        _ => None,
+                    },
+                    _ => None,
+                };
+            }
+            Message::Extended {
+                ext_id,
+                payload: Some(payload),
+            } if ext_id as usize == Extension::Metadata.id() => {
+                if let PeerMetainfoMessage::Request { piece } = PeerMetainfoMessage::try_from(payload)? {
+                    let data = seeder
+                        .info_dict
+                        .chunks(METAINFO_PIECE_SIZE)
+                        .nth(piece)
+                        .context("no such metainfo piece")?;
+                    let msg = PeerMetainfoMessage::Data {
+                        piece,
+                        total_size: seeder.info_dict.len(),
+                        data: Block(data.to_vec()),
+                    };
+                    send_message(
+                        &mut w_stream,
+                        Message::Extended {
+                            ext_id: metadata_id.context("peer does not support metadata extension")?,
+                            payload: Some(msg.into()),
+                        },
+                    )
+                    .await?;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Compact IPv4 peers, IPv6 ones are skipped
+fn compact_peers(peers: &[SocketAddr]) -> Vec<u8> {
+    peers
+        .iter()
+        .filter_map(|p| match p {
+            SocketAddr::V4(p) => Some([p.ip().octets().as_slice(), &p.port().to_be_bytes()].concat()),
+            SocketAddr::V6(_) => None,
+        })
+        .flatten()
+        .collect()
+}
+
+/// Peers handed out by a mock tracker or DHT node, along with the number of requests it answered
+#[derive(Clone, Default)]
+struct Swarm {
+    peers: Arc<std::sync::Mutex<Vec<SocketAddr>>>,
+    requests: Arc<AtomicUsize>,
+}
+
+impl Swarm {
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        self.peers.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn add_peer(&self, peer: SocketAddr) {
+        self.peers.lock().unwrap_or_else(|e| e.into_inner()).push(peer);
+    }
+}
+
+/// Minimal HTTP tracker, answering every announce with the added peers
+pub struct MockHttpTracker {
+    pub url: String,
+    swarm: Swarm,
+    task: JoinHandle<()>,
+}
+
+impl MockHttpTracker {
+    pub async fn start() -> Result<MockHttpTracker> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let url = format!("http://{}/announce", listener.local_addr()?);
+        let swarm = Swarm::default();
+        let task = spawn({
+            let swarm = swarm.clone();
+            async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            if let Err(e) = serve_http_announce(stream, &swarm).await {
+                                debug!("mock http tracker error: {e:#}");
+                            }
+                        }
+                        Err(e) => debug!("mock http tracker accept error: {e:#}"),
+                    }
+                }
+            }
+        });
+        Ok(MockHttpTracker { url, swarm, task })
+    }
+
+    pub fn add_peer(&self, peer: SocketAddr) {
+        self.swarm.add_peer(peer);
+    }
+
+    pub fn announces(&self) -> usize {
+        self.swarm.requests.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockHttpTracker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve_http_announce(mut stream: TcpStream, swarm: &Swarm) -> Result<()> {
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() > 1 << 16 {
+            return Err(anyhow!("malformed request"));
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let body = BencodeValue::Dict(
+        [
+            ("interval".into(), BencodeValue::from(ANNOUNCE_INTERVAL)),
+            ("peers".into(), BencodeValue::String(compact_peers(&swarm.peers()))),
+        ]
+        .into_iter()
+        .collect(),
+    )
+    .encode();
+    let header = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(&[header.as_bytes(), &body].concat()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Minimal UDP tracker, see [BEP-15](https://www.bittorrent.org/beps/bep_0015.html), answering every announce with
+/// the added peers
+pub struct MockUdpTracker {
+    pub url: String,
+    swarm: Swarm,
+    task: JoinHandle<()>,
+}
+
+impl MockUdpTracker {
+    pub async fn start() -> Result<MockUdpTracker> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let url = format!("udp://{}/announce", socket.local_addr()?);
+        let swarm = Swarm::default();
+        let task = spawn({
+            let swarm = swarm.clone();
+            async move {
+                let mut buf = [0u8; 1 << 16];
+                loop {
+                    let (n, addr) = match socket.recv_from(&mut buf).await {
+                        Ok(r) => r,
+                        Err(e) => {
+                            debug!("mock udp tracker error: {e:#}");
+                            continue;
+                        }
+                    };
+                    if let Some(resp) = udp_tracker_response(&buf[..n], &swarm) {
+                        let _ = socket.send_to(&resp, addr).await;
+                    }
+                }
+            }
+        });
+        Ok(MockUdpTracker { url, swarm, task })
+    }
+
+    pub fn add_peer(&self, peer: SocketAddr) {
+        self.swarm.add_peer(peer);
+    }
+
+    pub fn announces(&self) -> usize {
+        self.swarm.requests.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockUdpTracker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn udp_tracker_response(pkt: &[u8], swarm: &Swarm) -> Option<Vec<u8>> {
+    let conn_id = i64::from_be_bytes(pkt.get(0..8)?.try_into().ok()?);
+    let action = i32::from_be_bytes(pkt.get(8..12)?.try_into().ok()?);
+    let tx_id = pkt.get(12..16)?;
+    match action {
+        0 => Some([&0_i32.to_be_bytes()[..], tx_id, &UDP_CONNECTION_ID.to_be_bytes()].concat()),
+        1 if conn_id != UDP_CONNECTION_ID => {
+            Some([&3_i32.to_be_bytes()[..], tx_id, b"connection id mismatch"].concat())
+        }
+        1 => {
+            let peers = swarm.peers();
+            Some(
+                [
+                    &1_i32.to_be_bytes()[..],
+                    tx_id,
+                    &(ANNOUNCE_INTERVAL as i32).to_be_bytes(),
+                    &0_i32.to_be_bytes(),
+                    &(peers.len() as i32).to_be_bytes(),
+                    &compact_peers(&peers),
+                ]
+                .concat(),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// DHT node on the loopback, answering `get_peers` queries with the added peers and every other query with its id
+pub struct MockDhtNode {
+    pub addr: SocketAddr,
+    swarm: Swarm,
+    task: JoinHandle<()>,
+}
+
+impl MockDhtNode {
+    pub async fn start() -> Result<MockDhtNode> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = socket.local_addr()?;
+        let node_id = (0..20).map(|_| thread_rng().gen()).collect::<ByteString>();
+        let swarm = Swarm::default();
+        let task = spawn({
+            let swarm = swarm.clone();
+            async move {
+                let mut buf = [0u8; 1 << 16];
+                loop {
+                    let (n, addr) = match socket.recv_from(&mut buf).await {
+                        Ok(r) => r,
+                        Err(e) => {
+                            debug!("mock dht node error: {e:#}");
+                            continue;
+                        }
+                    };
+                    if let Some(resp) = krpc_response(&buf[..n], &node_id, &swarm) {
+                        let _ = socket.send_to(&resp.encode(), addr).await;
+                    }
+                }
+            }
+        });
+        Ok(MockDhtNode { addr, swarm, task })
+    }
+
+    pub fn info(&self) -> PeerInfo {
+        PeerInfo::from(self.addr)
+    }
+
+    pub fn add_peer(&self, peer: SocketAddr) {
+        self.swarm.add_peer(peer);
+    }
+
+    /// Number of `get_peers` queries answered
+    pub fn queries(&self) -> usize {
+        self.swarm.requests.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockDhtNode {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn krpc_response(pkt: &[u8], node_id: &[u8], swarm: &Swarm) -> Option<BencodeValue> {
+    let query = match bencode::parse(pkt).ok()? {
+        BencodeValue::Dict(d) => d,
+        _ => return None,
+    };
+    let mut r: BTreeMap<String, BencodeValue> = [("id".into(), BencodeValue::String(node_id.to_vec()))].into();
+    if query.get("q") == Some(&BencodeValue::from("get_peers")) {
+        let values = compact_peers(&swarm.peers())
+            .chunks(6)
+            .map(|p| BencodeValue::String(p.to_vec()))
+            .collect();
+        r.insert("token".into(), BencodeValue::from("token"));
+        r.insert("values".into(), BencodeValue::List(values));
+    }
+    Some(BencodeValue::Dict(
+        [
+            ("t".into(), query.get("t")?.clone()),
+            ("y".into(), BencodeValue::from("r")),
+            ("r".into(), BencodeValue::Dict(r)),
+        ]
+        .into(),
+    ))
+}
+
+/// Empty directory under the system temp directory
+pub fn temp_dir(name: &str) -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("biter-{}-{:08x}", name, thread_rng().gen::<u32>()));
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Remove the directory created with [temp_dir]. Session is dropped first, since its persist state is saved to the
+/// directory on drop
+pub fn remove_temp_dir(dir: &Path, session: Session) {
+    drop(session);
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+/// Config with short timeouts, downloading to `dir`
+pub fn config(dir: &Path) -> Config {
+    Config {
+        download_dir: dir.join("download"),
+        incomplete_dir: None,
+        ports: vec![0],
+        random_port: false,
+        bind_address: None,
+        respect_choke: false,
+        choke_wait: Duration::from_millis(100),
+        reconnect_wait: Duration::from_millis(100),
+        max_peers: 50,
+        max_reconnect_wait: Duration::from_secs(1),
+        max_connect_fails: 8,
+        downloaded_check_wait: Duration::from_millis(50),
+        peer_connect_timeout: Duration::from_secs(1),
+        max_connections: 50,
+        max_open_files: 8,
+        max_connecting: 10,
+        handshake_timeout: Duration::from_secs(2),
+        ext_handshake_timeout: Duration::from_secs(2),
+        first_message_timeout: Duration::from_secs(2),
+        request_queue_time: Duration::from_secs(1),
+        schedule_wait: Duration::from_millis(100),
+        reqq: 250,
+        request_timeout: Duration::from_secs(2),
+        dht_chunk: 10,
+        dht_min_peers: 1,
+        dht_min_connected: 1,
+        dht_discover_wait: Duration::from_secs(1),
+        dht_announce_wait: Duration::from_secs(60),
+        announce_on_pause: false,
+        max_hash_fails: 3,
+        write_retries: 3,
+        write_retry_wait: Duration::from_millis(100),
+        max_piece_buffer: 16 << 20,
+        verify_after_write: false,
+        resume_save_wait: Duration::from_millis(200),
+        resume_spot_checks: 16,
+        read_cache_size: 1 << 20,
+        max_waste_percent: 10,
+        piece_selection: PieceSelection::Random,
+        resolve_peer_hosts: false,
+        progress_wait: Duration::from_secs(1),
+        metainfo_only: false,
+        peer_list: false,
+        piece_map: false,
+        wire_dump: None,
+        stats_json: false,
+        on_metainfo: None,
+        on_complete: None,
+        on_error: None,
+        persist_backend: PersistBackend::Json,
+    }
+}
+
+/// Persist state saved to `dir`, knowing the DHT nodes
+pub fn persist_state(dir: &Path, dht_nodes: impl IntoIterator<Item = PeerInfo>) -> Arc<Mutex<PersistState>> {
+    let mut p_state = PersistState {
+        path: dir.join("state.json"),
+        peer_id: generate_peer_id(),
+        dht_peers: vec![],
+        dht_node_id: None,
+        external_ip: None,
+        torrents: BTreeMap::new(),
+        backend: PersistBackend::Json,
+    };
+    p_state.seen_dht_nodes(dht_nodes);
+    Arc::new(Mutex::new(p_state))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::torrent::file_path;
+
+    const PIECE_LENGTH: usize = 1 << 15;
+    const LENGTH: usize = 8 * PIECE_LENGTH - 1000;
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    async fn downloaded(config: &Config, torrent: &MockTorrent) -> Vec<u8> {
+        let info = &torrent.metainfo.info;
+        let file = info.file_info.files()[0];
+        tokio::fs::read(file_path(&config.download_dir, info, file))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_download_from_http_tracker_peer() {
+        let dir = temp_dir("http").unwrap();
+        let tracker = MockHttpTracker::start().await.unwrap();
+        let torrent = MockTorrent::new("http", LENGTH, PIECE_LENGTH, Some(&tracker.url)).unwrap();
+        let seeder = MockSeeder::start(&torrent, BTreeSet::new()).await.unwrap();
+        tracker.add_peer(seeder.addr);
+
+        let config = config(&dir);
+        let session = Session::new(config.clone(), persist_state(&dir, []));
+        let handle = session.add_torrent_bytes(torrent.bencoded()).await.unwrap();
+        timeout(TIMEOUT, handle.wait()).await.unwrap().unwrap();
+
+        assert!(tracker.announces() > 0);
+        assert_eq!(downloaded(&config, &torrent).await, torrent.data);
+        remove_temp_dir(&dir, session);
+    }
+
+    #[tokio::test]
+    async fn should_fall_back_to_next_tracker_tier() {
+        let dir = temp_dir("tiers").unwrap();
+        let tracker = MockHttpTracker::start().await.unwrap();
+        let mut torrent = MockTorrent::new("tiers", LENGTH, PIECE_LENGTH, None).unwrap();
+        let unreachable = "http://127.0.0.1:1/announce".to_string();
+        torrent.metainfo.announce_list = Some(vec![vec![unreachable.clone()], vec![tracker.url.clone()]]);
+        let seeder = MockSeeder::start(&torrent, BTreeSet::new()).await.unwrap();
+        tracker.add_peer(seeder.addr);
+
+        let config = config(&dir);
+        let session = Session::new(config.clone(), persist_state(&dir, []));
+        let handle = session.add_metainfo(torrent.metainfo.clone()).await.unwrap();
+        timeout(TIMEOUT, handle.wait()).await.unwrap().unwrap();
+
+        assert!(tracker.announces() > 0);
+        let trackers = handle.trackers().await;
+        assert_eq!(trackers.len(), 2);
+        assert!(trackers.iter().any(|t| t.url == unreachable && t.last_error.is_some()));
+        assert!(trackers.iter().any(|t| t.url == tracker.url && t.last_error.is_none()));
+        assert_eq!(downloaded(&config, &torrent).await, torrent.data);
+        remove_temp_dir(&dir, session);
+    }
+
+    #[tokio::test]
+    async fn should_fetch_metainfo_from_dht_peer() {
+        let dir = temp_dir("dht").unwrap();
+        let torrent = MockTorrent::new("dht", LENGTH, PIECE_LENGTH, None).unwrap();
+        let seeder = MockSeeder::start(&torrent, BTreeSet::new()).await.unwrap();
+        let node = MockDhtNode::start().await.unwrap();
+        node.add_peer(seeder.addr);
+
+        let config = config(&dir);
+        let session = Session::new(config.clone(), persist_state(&dir, [node.info()]));
+        let handle = session.add_info_hash(torrent.info_hash.clone()).await.unwrap();
+        timeout(TIMEOUT, handle.wait()).await.unwrap().unwrap();
+
+        assert!(node.queries() > 0);
+        assert_eq!(
+            handle.metainfo().await.map(|m| m.info),
+            Some(torrent.metainfo.info.clone())
+        );
+        assert_eq!(downloaded(&config, &torrent).await, torrent.data);
+        remove_temp_dir(&dir, session);
+    }
+
+    #[tokio::test]
+    async fn should_recover_from_hash_fail() {
+        let dir = temp_dir("hash-fail").unwrap();
+        let tracker = MockUdpTracker::start().await.unwrap();
+        let torrent = MockTorrent::new("hash-fail", LENGTH, PIECE_LENGTH, Some(&tracker.url)).unwrap();
+        let seeder = MockSeeder::start(&torrent, [2].into()).await.unwrap();
+        tracker.add_peer(seeder.addr);
+
+        let config = config(&dir);
+        let session = Session::new(config.clone(), persist_state(&dir, []));
+        let handle = session.add_torrent_bytes(torrent.bencoded()).await.unwrap();
+        timeout(TIMEOUT, handle.wait()).await.unwrap().unwrap();
+
+        assert!(tracker.announces() > 0);
+        let served = seeder.served().await;
+        assert!(served.iter().filter(|p| **p == 2).count() > served.iter().filter(|p| **p == 3).count());
+        assert_eq!(downloaded(&config, &torrent).await, torrent.data);
+        remove_temp_dir(&dir, session);
+    }
+
+    #[tokio::test]
+    async fn should_resume_download() {
+        let dir = temp_dir("resume").unwrap();
+        let torrent = MockTorrent::new("resume", LENGTH, PIECE_LENGTH, None).unwrap();
+        let seeder = MockSeeder::start(&torrent, BTreeSet::new()).await.unwrap();
+        let config = config(&dir);
+        let p_state = persist_state(&dir, []);
+
+        let session = Session::new(config.clone(), p_state.clone());
+        let handle = session.add_torrent_bytes(torrent.bencoded()).await.unwrap();
+        handle.add_peer(seeder.addr).await;
+        timeout(TIMEOUT, handle.wait()).await.unwrap().unwrap();
+        assert_eq!(downloaded(&config, &torrent).await, torrent.data);
+
+        // damage the last piece, only it must be downloaded again
+        let info = &torrent.metainfo.info;
+        let path = file_path(&config.download_dir, info, info.file_info.files()[0]);
+        let mut data = torrent.data.clone();
+        *data.last_mut().unwrap() ^= 0xff;
+        tokio::fs::write(&path, &data).await.unwrap();
+        seeder.clear_served().await;
+        drop(session);
+
+        let session = Session::new(config.clone(), p_state);
+        let handle = session.add_torrent_bytes(torrent.bencoded()).await.unwrap();
+        handle.add_peer(seeder.addr).await;
+        timeout(TIMEOUT, handle.wait()).await.unwrap().unwrap();
+
+        let served = seeder.served().await;
+        assert!(!served.is_empty());
+        assert!(served.iter().all(|p| *p == 7));
+        assert_eq!(downloaded(&config, &torrent).await, torrent.data);
+        remove_temp_dir(&dir, session);
+    }
+}
@@ -0,0 +1,129 @@
+use std::net::IpAddr;
+
+use anyhow::{anyhow, ensure, Error};
+
+use crate::state::PeerInfo;
+
+/// Holepunch extension message, see [BEP-55](https://www.bittorrent.org/beps/bep_0055.html)
+#[derive(Clone, Debug, PartialEq, PartialOrd, Hash)]
+pub enum HolepunchMessage {
+    /// Ask relay peer to connect us with the target peer
+    Rendezvous {
+        addr: PeerInfo,
+    },
+    /// Sent by relay peer, asking to initiate connection with the peer
+    Connect {
+        addr: PeerInfo,
+    },
+    Error {
+        addr: PeerInfo,
+        code: HolepunchError,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd, Hash)]
+pub enum HolepunchError {
+    NoSuchPeer,
+    NotConnected,
+    NoSupport,
+    NoSelf,
+    Other(u32),
+}
+
+impl HolepunchError {
+    pub fn code(&self) -> u32 {
+        match self {
+            HolepunchError::NoSuchPeer => 1,
+            HolepunchError::NotConnected => 2,
+            HolepunchError::NoSupport => 3,
+            HolepunchError::NoSelf => 4,
+            HolepunchError::Other(c) => *c,
+        }
+    }
+}
+
+impl From<u32> for HolepunchError {
+    fn from(value: u32) -> Self {
+        match value {
+            1 => HolepunchError::NoSuchPeer,
+            2 => HolepunchError::NotConnected,
+            3 => HolepunchError::NoSupport,
+            4 => HolepunchError::NoSelf,
+            c => HolepunchError::Other(c),
+        }
+    }
+}
+
+impl HolepunchMessage {
+    pub fn msg_type(&self) -> u8 {
+        match self {
+            HolepunchMessage::Rendezvous { .. } => 0,
+            HolepunchMessage::Connect { .. } => 1,
+            HolepunchMessage::Error { .. } => 2,
+        }
+    }
+
+    pub fn addr(&self) -> &PeerInfo {
+        match self {
+            HolepunchMessage::Rendezvous { addr } => addr,
+            HolepunchMessage::Connect { addr } => addr,
+            HolepunchMessage::Error { addr, .. } => addr,
+        }
+    }
+}
+
+impl TryFrom<HolepunchMessage> for Vec<u8> {
+    type Error = Error;
+
+    /// Format: <msg_type><addr_type><addr><port><err_code>
+    fn try_from(value: HolepunchMessage) -> Result<Self, Error> {
+        let (addr_type, addr) = match value.addr().ip {
+            IpAddr::V4(ip) => (0u8, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (1u8, ip.octets().to_vec()),
+        };
+        let err_code = match &value {
+            HolepunchMessage::Error { code, .. } => code.code(),
+            _ => 0,
+        };
+        Ok([
+            &[value.msg_type(), addr_type][..],
+            &addr,
+            &value.addr().port.to_be_bytes(),
+            &err_code.to_be_bytes(),
+        ]
+        .concat())
+    }
+}
+
+impl TryFrom<Vec<u8>> for HolepunchMessage {
+    type Error = Error;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Error> {
+        ensure!(value.len() >= 2, "holepunch message too short");
+        let addr_len = match value[1] {
+            0 => 4,
+            1 => 16,
+            t => return Err(anyhow!("unexpected addr_type: {}", t)),
+        };
+        ensure!(
+            value.len() == 2 + addr_len + 2 + 4,
+            "unexpected holepunch message length"
+        );
+        let ip = match addr_len {
+            4 => IpAddr::from(<[u8; 4]>::try_from(&value[2..6])?),
+            _ => IpAddr::from(<[u8; 16]>::try_from(&value[2..18])?),
+        };
+        let port = u16::from_be_bytes(value[2 + addr_len..4 + addr_len].try_into()?);
+        let err_code = u32::from_be_bytes(value[4 + addr_len..8 + addr_len].try_into()?);
+        let addr = PeerInfo { ip, port };
+        Ok(match value[0] {
+            0 => HolepunchMessage::Rendezvous { addr },
+            1 => HolepunchMessage::Connect { addr },
+            2 => HolepunchMessage::Error {
+                addr,
+                code: HolepunchError::from(err_code),
+            },
+            t => return Err(anyhow!("unexpected msg_type: {}", t)),
+        })
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{config::Config, hex::hex, platform::shell_command, state::State, torrent::torrent_dir};
+
+/// Torrent event that triggers a hook command
+#[derive(Clone, Debug, PartialEq)]
+pub enum HookEvent {
+    /// Metainfo is known, either from the torrent file or downloaded from peers
+    Metainfo,
+    /// Torrent is downloaded, verified and moved to `download_dir`
+    Complete,
+    Error(String),
+}
+
+impl HookEvent {
+    fn name(&self) -> &str {
+        match self {
+            HookEvent::Metainfo => "metainfo",
+            HookEvent::Complete => "complete",
+            HookEvent::Error(_) => "error",
+        }
+    }
+
+    fn command<'a>(&self, config: &'a Config) -> Option<&'a str> {
+        match self {
+            HookEvent::Metainfo => config.on_metainfo.as_deref(),
+            HookEvent::Complete => config.on_complete.as_deref(),
+            HookEvent::Error(_) => config.on_error.as_deref(),
+        }
+    }
+}
+
+/// Torrent description passed to the hook command as environment variables
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HookEnv {
+    pub info_hash: String,
+    pub name: Option<String>,
+    /// Path of the torrent directory
+    pub path: Option<PathBuf>,
+    pub size: Option<u64>,
+    /// Time since the download start
+    pub duration: Duration,
+}
+
+impl HookEnv {
+    /// Environment of the torrent, located in the `dir`
+    pub fn new(state: &State, dir: &Path) -> HookEnv {
+        let info = state.metainfo.as_ref().ok().map(|m| &m.info);
+        HookEnv {
+            info_hash: hex(&state.info_hash),
+            name: info.map(|i| i.name.clone()),
+            path: info.map(|i| torrent_dir(dir, i)),
+            size: info.map(|i| i.file_info.total_length()),
+            duration: state.started.elapsed(),
+        }
+    }
+
+    fn vars(&self) -> Vec<(&str, String)> {
+        let mut vars = vec![
+            ("BITER_INFO_HASH", self.info_hash.clone()),
+            ("BITER_DURATION", self.duration.as_secs().to_string()),
+        ];
+        if let Some(name) = &self.name {
+            vars.push(("BITER_NAME", name.clone()));
+        }
+        if let Some(path) = &self.path {
+            vars.push(("BITER_PATH", path.display().to_string()));
+        }
+        if let Some(size) = self.size {
+            vars.push(("BITER_SIZE", size.to_string()));
+        }
+        vars
+    }
+}
+
+/// Run the hook command configured for the event with the platform shell, see [shell_command], if any. Hook failures
+/// are logged and do not affect the download
+pub async fn run_hook(config: &Config, event: HookEvent, env: HookEnv) {
+    let command = match event.command(config) {
+        Some(c) => c,
+        _ => return,
+    };
+    debug!("running {} hook: {}", event.name(), command);
+    let mut cmd = shell_command(command);
+    cmd.env("BITER_EVENT", event.name()).envs(env.vars());
+    if let HookEvent::Error(e) = &event {
+        cmd.env("BITER_ERROR", e);
+    }
+    match cmd.status().await {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("{} hook exited with {}", event.name(), status),
+        Err(e) => warn!("unable to run {} hook: {}", event.name(), e),
+    }
+}
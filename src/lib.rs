@@ -0,0 +1,40 @@
+#![allow(clippy::format_collect)]
+
+#[macro_use]
+extern crate log;
+
+pub mod abort;
+pub mod bencode;
+pub mod config;
+pub mod crc32c;
+pub mod dht;
+pub mod disk;
+pub mod extension;
+pub mod feature;
+#[cfg(feature = "harness")]
+pub mod harness;
+pub mod hex;
+pub mod holepunch;
+pub mod hook;
+pub mod limits;
+pub mod message;
+pub mod metainfo;
+pub mod peer;
+pub mod peer_metainfo;
+pub mod persist;
+pub mod platform;
+pub mod progress;
+pub mod registry;
+pub mod scheduler;
+pub mod selector;
+pub mod session;
+pub mod sha1;
+pub mod state;
+pub mod stats;
+pub mod torrent;
+pub mod tracker;
+pub mod tracker_udp;
+pub mod types;
+pub mod udp;
+pub mod webseed;
+pub mod wire_dump;
@@ -0,0 +1,106 @@
+use crate::config::Config;
+
+/// File descriptors reserved for the listener, DHT and tracker sockets, stdio and transient file reads
+const RESERVED_FDS: u64 = 64;
+
+/// Max number of file descriptors for peer connections and open torrent files
+#[derive(Clone, Debug, PartialEq)]
+pub struct FdBudget {
+    pub connections: usize,
+    pub files: usize,
+}
+
+/// Split file descriptors allowed by the OS between peer connections and open files, within configured maximums.
+/// Warns if the limit constrains connections
+pub fn fd_budget(config: &Config) -> FdBudget {
+    let wanted = FdBudget {
+        connections: config.max_connections,
+        files: config.max_open_files,
+    };
+    let limit = match fd_limit() {
+        Some(limit) => limit,
+        _ => return wanted,
+    };
+    debug!("file descriptor limit is {}", limit);
+    let budget = split_fds(limit, &wanted);
+    if budget.connections < wanted.connections {
+        warn!(
+            "file descriptor limit of {} allows only {} peer connections out of {}, consider raising it with `ulimit -n`",
+            limit, budget.connections, wanted.connections
+        );
+    }
+    budget
+}
+
+/// Files get at most a quarter of available descriptors, the rest is for connections
+fn split_fds(limit: u64, wanted: &FdBudget) -> FdBudget {
+    let available = limit.saturating_sub(RESERVED_FDS) as usize;
+    let files = wanted.files.min(available / 4).max(1);
+    FdBudget {
+        connections: wanted.connections.min(available.saturating_sub(files)),
+        files,
+    }
+}
+
+/// Soft limit of open file descriptors, raised to the hard limit if possible
+#[cfg(unix)]
+fn fd_limit() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid pointer to rlimit struct
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    if limit.rlim_cur < limit.rlim_max {
+        let raised = libc::rlimit {
+            rlim_cur: limit.rlim_max,
+            rlim_max: limit.rlim_max,
+        };
+        // SAFETY: `raised` is a valid pointer to rlimit struct
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            debug!(
+                "raised file descriptor limit from {} to {}",
+                limit.rlim_cur, raised.rlim_cur
+            );
+            limit = raised;
+        }
+    }
+    // rlim_t width differs between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Some(limit.rlim_cur as u64)
+}
+
+#[cfg(not(unix))]
+fn fd_limit() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_split_fds() {
+        let wanted = FdBudget {
+            connections: 200,
+            files: 64,
+        };
+        assert_eq!(split_fds(1 << 20, &wanted), wanted);
+        assert_eq!(
+            split_fds(256, &wanted),
+            FdBudget {
+                connections: 144,
+                files: 48
+            }
+        );
+        assert_eq!(
+            split_fds(10, &wanted),
+            FdBudget {
+                connections: 0,
+                files: 1
+            }
+        );
+    }
+}
@@ -1,42 +1,22 @@
-#![allow(clippy::format_collect)]
-
 #[macro_use]
 extern crate log;
 
 use anyhow::{anyhow, Context, Result};
-use expanduser::expanduser;
-use reqwest::Url;
-use std::{collections::BTreeSet, env, path::PathBuf, process, sync::Arc, time::Duration};
+use std::{collections::BTreeMap, env, fs, net::SocketAddr, path::PathBuf, process, sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 
-use crate::{
+use biter::{
     config::Config,
-    hex::from_hex,
+    dht::{sample_infohashes, scrape, Dht},
+    hex::{from_hex, hex},
     peer::generate_peer_id,
-    persist::PersistState,
-    torrent::{download_torrent, metainfo_from_path},
+    persist::{PersistBackend, PersistState},
+    platform::{safe_component, state_dir},
+    selector::PieceSelection,
+    session::Session,
+    wire_dump,
 };
 
-mod abort;
-mod bencode;
-mod config;
-mod dht;
-mod extension;
-mod feature;
-mod hex;
-mod message;
-mod metainfo;
-mod peer;
-mod peer_metainfo;
-mod persist;
-mod sha1;
-mod state;
-mod torrent;
-mod tracker;
-mod tracker_udp;
-mod types;
-mod udp;
-
 #[tokio::main]
 async fn main() {
     if let Err(e) = try_main().await {
@@ -48,49 +28,244 @@ async fn main() {
 async fn try_main() -> Result<()> {
     env_logger::init_from_env(env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"));
 
-    let arg = match env::args().nth(1) {
-        Some(arg) => arg,
+    let (flags, mut args): (Vec<_>, Vec<_>) = env::args().skip(1).partition(|a| a.starts_with("--"));
+    if args.first().is_some_and(|a| a == "download") {
+        args.remove(0);
+    }
+    // `pieces <torrent>` downloads the torrent logging its piece map
+    let piece_map = args.first().is_some_and(|a| a == "pieces");
+    if piece_map {
+        args.remove(0);
+    }
+    // `peers <torrent>` downloads the torrent logging connected peers
+    let peer_list = args.first().is_some_and(|a| a == "peers");
+    if peer_list {
+        args.remove(0);
+    }
+    let arg = match args.first() {
+        Some(arg) => arg.clone(),
         _ => return Err(anyhow!("no torrent file/magnet specified")),
     };
 
+    let add_peers = flags
+        .iter()
+        .filter_map(|f| f.strip_prefix("--add-peer="))
+        .map(|a| a.parse().context(format!("invalid peer address: {}", a)))
+        .collect::<Result<Vec<SocketAddr>>>()?;
+
     let config = Config {
-        port: 6881,
+        download_dir: flag_value(&flags, "--download-dir").unwrap_or("download").into(),
+        incomplete_dir: flag_value(&flags, "--incomplete-dir").map(PathBuf::from),
+        ports: (6881..=6889).collect(),
+        random_port: false,
+        bind_address: None,
         respect_choke: false,
         choke_wait: Duration::from_secs(10),
         reconnect_wait: Duration::from_secs(20),
+        max_peers: 500,
+        max_reconnect_wait: Duration::from_secs(600),
+        max_connect_fails: 8,
         downloaded_check_wait: Duration::from_secs(1),
         peer_connect_timeout: Duration::from_secs(4),
-        piece_request_wait: Duration::from_millis(100),
+        max_connections: 200,
+        max_open_files: 64,
+        max_connecting: 50,
+        handshake_timeout: Duration::from_secs(10),
+        ext_handshake_timeout: Duration::from_secs(10),
+        first_message_timeout: Duration::from_secs(30),
+        request_queue_time: Duration::from_secs(3),
+        schedule_wait: Duration::from_secs(1),
+        reqq: 250,
+        request_timeout: Duration::from_secs(30),
         dht_chunk: 200,
         dht_min_peers: 50,
+        dht_min_connected: 10,
+        dht_discover_wait: Duration::from_secs(30),
+        dht_announce_wait: Duration::from_secs(15 * 60),
+        announce_on_pause: true,
+        max_hash_fails: 3,
+        write_retries: 3,
+        write_retry_wait: Duration::from_secs(1),
+        max_piece_buffer: 64 << 20,
+        verify_after_write: flags.iter().any(|f| f == "--verify-after-write"),
+        resume_save_wait: Duration::from_secs(30),
+        resume_spot_checks: 16,
+        read_cache_size: 32 << 20,
+        max_waste_percent: 10,
+        piece_selection: match flag_value(&flags, "--piece-selection") {
+            Some(s) => s.parse()?,
+            None => PieceSelection::Random,
+        },
+        resolve_peer_hosts: true,
+        progress_wait: Duration::from_secs(10),
+        metainfo_only: false,
+        peer_list,
+        piece_map,
+        wire_dump: flag_value(&flags, "--wire-dump").map(PathBuf::from),
+        stats_json: flags.iter().any(|f| f == "--stats-json"),
+        on_metainfo: flag_value(&flags, "--on-metainfo").map(String::from),
+        on_complete: flag_value(&flags, "--on-complete").map(String::from),
+        on_error: flag_value(&flags, "--on-error").map(String::from),
+        persist_backend: match flag_value(&flags, "--persist-backend") {
+            Some(s) => s.parse()?,
+            None => PersistBackend::default(),
+        },
     };
 
-    let state_path = expanduser("~/.local/state/biter")?;
-    let p_state = PersistState::load(&state_path).ok().unwrap_or_else(|| PersistState {
-        path: state_path,
-        peer_id: generate_peer_id(),
-        dht_peers: BTreeSet::new(),
-    });
-    debug!("read persist state from file: {:?}", p_state);
+    if let Some(path) = &config.wire_dump {
+        wire_dump::init(path)?;
+    }
+
+    let state_path = state_dir()?;
+    let p_state = match PersistState::load(&state_path, config.persist_backend) {
+        Ok(p_state) => p_state,
+        // existing state is never replaced with a fresh one, since it would be saved over on exit
+        Err(e) if state_path.exists() || config.persist_backend.path(&state_path).exists() => {
+            return Err(e.context(format!(
+                "unable to load persist state from {}, move it away to start with a fresh one",
+                state_path.display()
+            )));
+        }
+        Err(e) => {
+            debug!("no persist state: {e:#}");
+            PersistState {
+                path: config.persist_backend.path(&state_path),
+                peer_id: generate_peer_id(),
+                dht_peers: vec![],
+                dht_node_id: None,
+                external_ip: None,
+                torrents: BTreeMap::new(),
+                backend: config.persist_backend,
+            }
+        }
+    };
+    debug!("read persist state: {:?}", p_state);
     let p_state = Arc::new(Mutex::new(p_state));
 
-    if arg.starts_with("magnet:") {
-        debug!("parsing magnet: {}", arg);
-        let uri = Url::parse(&arg).context("magnet uri parsing error")?;
-        let xt = uri
-            .query_pairs()
-            .find(|(k, _)| k == "xt")
-            .context("no `info_hash` query param")?
-            .1
-            .to_string();
-        trace!("xt: {}", xt);
-        let info_hash = xt.split("urn:btih:").last().context("invalid magnet")?.to_lowercase();
-        info!("magnet info hash: {}", info_hash);
-        download_torrent(from_hex(&info_hash), None, &config, p_state).await?;
+    if arg == "export-state" {
+        let path = args.get(1).context("usage: export-state <file>")?;
+        p_state.lock().await.export(&PathBuf::from(path))?;
+        info!("state exported to {}", path);
+    } else if arg == "import-state" {
+        let path = args.get(1).context("usage: import-state <file>")?;
+        let imported = PersistState::import(
+            &PathBuf::from(path),
+            config.persist_backend.path(&state_path),
+            config.persist_backend,
+        )?;
+        let mut imported = imported;
+        imported.save()?;
+        info!(
+            "imported {} torrents, {} dht nodes",
+            imported.torrents.len(),
+            imported.dht_peers.len()
+        );
+        *p_state.lock().await = imported;
+    } else if arg == "fetch-meta" {
+        let magnet = args.get(1).context("usage: fetch-meta <magnet> [file]")?;
+        let metainfo = Session::new(config, p_state).fetch_metainfo(magnet).await?;
+        let path = match args.get(2) {
+            Some(path) => PathBuf::from(path),
+            None => PathBuf::from(format!("{}.torrent", safe_component(&metainfo.info.name))),
+        };
+        fs::write(&path, &metainfo.bencoded.0).context(format!("unable to write {}", path.display()))?;
+        info!("metainfo written to {}", path.display());
+    } else if arg == "dht-scrape" {
+        match args.get(1) {
+            Some(info_hash) if !is_info_hash(info_hash) => return Err(anyhow!("usage: dht-scrape [info hash]")),
+            info_hash => dht_scrape(info_hash, &config, p_state).await?,
+        }
+    } else if arg == "dht" {
+        match (args.get(1).map(|a| a.as_str()), args.get(2)) {
+            (Some("get-peers"), Some(info_hash)) if is_info_hash(info_hash) => {
+                dht_get_peers(info_hash, &config, p_state).await?
+            }
+            _ => return Err(anyhow!("usage: dht get-peers <info hash>")),
+        }
     } else {
-        let (info_hash, metainfo) = metainfo_from_path(&PathBuf::from(arg))?;
-        download_torrent(info_hash, Some(metainfo), &config, p_state).await?;
+        let session = Session::new(config, p_state);
+        let torrent = if arg.starts_with("magnet:") {
+            session.add_magnet(&arg).await?
+        } else if is_info_hash(&arg) {
+            info!("info hash: {}", arg);
+            session.add_info_hash(from_hex(&arg.to_lowercase())).await?
+        } else {
+            debug!("reading torrent file: {:?}", arg);
+            session
+                .add_torrent_bytes(fs::read(&arg).context("no metadata file")?)
+                .await?
+        };
+        for addr in add_peers {
+            torrent.add_peer(addr).await;
+        }
+        torrent.wait().await?;
     }
 
     Ok(())
 }
+
+/// Value of the flag in format `--flag=value`
+fn flag_value<'a>(flags: &'a [String], flag: &str) -> Option<&'a str> {
+    flags
+        .iter()
+        .find_map(|f| f.strip_prefix(flag).and_then(|v| v.strip_prefix('=')))
+}
+
+/// Whether argument is a 40 character hex info hash
+fn is_info_hash(arg: &str) -> bool {
+    arg.len() == 40 && arg.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Print peers of the info hash found via DHT
+async fn dht_get_peers(info_hash: &str, config: &Config, p_state: Arc<Mutex<PersistState>>) -> Result<()> {
+    let mut dht = {
+        let mut p_state = p_state.lock().await;
+        Dht::new(
+            p_state.dht_node_id(),
+            p_state.dht_nodes(),
+            config.bind_address,
+            config.dht_chunk,
+        )
+    };
+    debug!("querying dht with {} nodes", dht.node_count());
+    let res = dht
+        .get_peers(from_hex(&info_hash.to_lowercase()), config.dht_min_peers)
+        .await;
+    p_state.lock().await.update_dht_nodes(&dht.health);
+    let (peers, _) = res?;
+    peers.iter().for_each(|p| println!("{}", p.to_addr()));
+    Ok(())
+}
+
+/// Print approximate seed and peer count of the info hash from DHT, or sample of info hashes known to DHT nodes if
+/// info hash is not specified
+async fn dht_scrape(info_hash: Option<&String>, config: &Config, p_state: Arc<Mutex<PersistState>>) -> Result<()> {
+    let (dht_peers, node_id) = {
+        let mut p_state = p_state.lock().await;
+        (p_state.dht_nodes(), p_state.dht_node_id())
+    };
+    match info_hash {
+        Some(info_hash) => {
+            let (seeds, peers) = scrape(
+                dht_peers,
+                node_id,
+                config.bind_address,
+                from_hex(&info_hash.to_lowercase()),
+                config.dht_min_peers,
+                config.dht_chunk,
+            )
+            .await?;
+            println!("seeds: ~{:.0}", seeds);
+            println!("peers: ~{:.0}", peers);
+        }
+        None => {
+            for node in dht_peers {
+                match sample_infohashes(&node, &node_id, config.bind_address, node_id.clone()).await {
+                    Ok((samples, _)) => samples.iter().for_each(|s| println!("{}", hex(s))),
+                    Err(e) => debug!("{e:#}"),
+                }
+            }
+        }
+    }
+    Ok(())
+}
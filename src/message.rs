@@ -1,6 +1,25 @@
 use crate::{hex::hex, state::Block, types::ByteString};
 use anyhow::{anyhow, Context, Error, Result};
-use tokio::{io::AsyncReadExt, net::tcp::OwnedReadHalf};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const PSTR: &str = "BitTorrent protocol";
+
+/// Max block size a peer is allowed to send in a `Piece` message
+pub const MAX_BLOCK_SIZE: u32 = 1 << 18;
+/// Max length of any non-`Piece` message, enough for bitfields of torrents with ~1M pieces and metadata pieces
+pub const MAX_MESSAGE_LEN: u32 = 1 << 17;
+
+/// Max allowed message length (including message id) for the given message id
+fn max_message_len(id: u8) -> u32 {
+    match id {
+        0..=3 => 1,
+        4 => 5,
+        6 | 8 => 13,
+        7 => 9 + MAX_BLOCK_SIZE,
+        9 => 3,
+        _ => MAX_MESSAGE_LEN,
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum Message {
@@ -44,6 +63,44 @@ pub enum Message {
     },
 }
 
+impl Message {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Handshake { .. } => "handshake",
+            Message::KeepAlive => "keep_alive",
+            Message::Choke => "choke",
+            Message::Unchoke => "unchoke",
+            Message::Interested => "interested",
+            Message::NotInterested => "not_interested",
+            Message::Have { .. } => "have",
+            Message::Bitfield { .. } => "bitfield",
+            Message::Request { .. } => "request",
+            Message::Piece { .. } => "piece",
+            Message::Cancel { .. } => "cancel",
+            Message::Port { .. } => "port",
+            Message::Extended { .. } => "extended",
+        }
+    }
+
+    /// Decode length-prefixed message at the start of the input, returning it with the rest of the input.
+    /// Handshake has no length prefix and is decoded with `Message::try_from`
+    pub fn decode(bytes: &[u8]) -> Result<(Message, &[u8])> {
+        let (len_p, rest) = bytes.split_at_checked(4).context("truncated message length")?;
+        let len = u32::from_be_bytes(len_p.try_into()?);
+        if len == 0 {
+            return Ok((Message::KeepAlive, rest));
+        }
+        let (id, rest) = rest.split_first().context("truncated message id")?;
+        if len > max_message_len(*id) {
+            return Err(anyhow!("message #{} is too long: {}", id, len));
+        }
+        let (payload, rest) = rest
+            .split_at_checked(len as usize - 1)
+            .context(format!("truncated message #{id}"))?;
+        Ok((decode_payload(*id, payload.to_vec())?, rest))
+    }
+}
+
 impl From<Message> for Vec<u8> {
     fn from(value: Message) -> Self {
         fn u32tb(n: u32) -> Vec<u8> {
@@ -55,9 +112,8 @@ impl From<Message> for Vec<u8> {
                 peer_id,
                 reserved,
             } => {
-                let pstr = "BitTorrent protocol";
-                let pstrlen = &[pstr.len() as u8];
-                [pstrlen, pstr.as_bytes(), &reserved, &info_hash, &peer_id].concat()
+                let pstrlen = &[PSTR.len() as u8];
+                [pstrlen, PSTR.as_bytes(), &reserved, &info_hash, &peer_id].concat()
             }
             Message::KeepAlive => [u32tb(0).as_slice()].concat(),
             Message::Choke => [u32tb(1).as_slice(), &[0]].concat(),
@@ -114,34 +170,51 @@ impl From<Message> for Vec<u8> {
 impl TryFrom<Vec<u8>> for Message {
     type Error = Error;
 
+    /// Parse handshake message. Trailing bytes after the handshake are ignored
     fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
-        if value.len() != 68 {
-            return Err(anyhow!("invalid handshake len: {}", value.len()));
+        let pstrlen = *value.first().context("empty handshake")? as usize;
+        if pstrlen == 0 {
+            return Err(anyhow!("invalid pstrlen: {}", pstrlen));
         }
-        let pstrlen = &value.as_slice()[0..1];
-        if pstrlen != [19u8] {
-            return Err(anyhow!("invalid pstrlen: {}", hex(pstrlen)));
+        let len = handshake_len(pstrlen);
+        if value.len() < len {
+            return Err(anyhow!("invalid handshake len: {}", value.len()));
         }
-        let pstr = &value.as_slice()[1..20];
-        if pstr != "BitTorrent protocol".as_bytes() {
-            return Err(anyhow!("invalid pstr: {}", hex(pstr)));
+        let pstr = &value[1..1 + pstrlen];
+        if pstr != PSTR.as_bytes() {
+            debug!("unexpected pstr: {}", String::from_utf8_lossy(pstr));
         }
+        let reserved_start = 1 + pstrlen;
         Ok(Message::Handshake {
-            info_hash: value.as_slice()[28..48].to_vec(),
-            peer_id: value.as_slice()[48..68].to_vec(),
-            reserved: value.as_slice()[20..28].to_vec(),
+            reserved: value[reserved_start..reserved_start + 8].to_vec(),
+            info_hash: value[reserved_start + 8..reserved_start + 28].to_vec(),
+            peer_id: value[reserved_start + 28..reserved_start + 48].to_vec(),
         })
     }
 }
 
-pub async fn read_message(stream: &mut OwnedReadHalf) -> Result<Message> {
-    fn u32_from_slice(slice: &[u8]) -> Result<u32> {
-        Ok(u32::from_be_bytes(slice.try_into()?))
-    }
-    fn u16_from_slice(slice: &[u8]) -> Result<u16> {
-        Ok(u16::from_be_bytes(slice.try_into()?))
+/// Handshake length given pstr length: <pstrlen><pstr><reserved><info_hash><peer_id>
+fn handshake_len(pstrlen: usize) -> usize {
+    1 + pstrlen + 8 + 20 + 20
+}
+
+/// Read handshake message, consuming exactly the handshake bytes, so that any message sent by the peer in the same
+/// flush (e.g. bitfield) stays in the stream for the message loop
+pub async fn read_handshake<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Message> {
+    let mut pstrlen_p = [0; 1];
+    stream.read_exact(&mut pstrlen_p).await.context("pstrlen read error")?;
+    let pstrlen = pstrlen_p[0] as usize;
+    if pstrlen == 0 {
+        return Err(anyhow!("invalid pstrlen: {}", pstrlen));
     }
+    let mut rest_p = vec![0; handshake_len(pstrlen) - 1];
+    stream.read_exact(&mut rest_p).await.context("handshake read error")?;
+    let msg = [pstrlen_p.as_slice(), &rest_p].concat();
+    trace!("peer handshake: {}", hex(&msg));
+    Message::try_from(msg)
+}
 
+pub async fn read_message<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Message> {
     let mut len_p = [0; 4];
     stream.read_exact(&mut len_p).await?;
     let len = u32::from_be_bytes(len_p);
@@ -152,58 +225,115 @@ pub async fn read_message(stream: &mut OwnedReadHalf) -> Result<Message> {
     let mut id_p = [0; 1];
     stream.read_exact(&mut id_p).await.context("id_p read error")?;
     let id = u8::from_be_bytes(id_p);
+    if len > max_message_len(id) {
+        return Err(anyhow!("message #{} is too long: {}", id, len));
+    }
 
-    let msg = match id {
+    let mut payload_p = vec![0; len as usize - 1];
+    stream
+        .read_exact(&mut payload_p)
+        .await
+        .context("payload_p read error")?;
+    let msg = decode_payload(id, payload_p)?;
+    trace!("<<< read message: {:?}", msg);
+    Ok(msg)
+}
+
+/// Decode message by its id and payload, not longer than [max_message_len]
+fn decode_payload(id: u8, payload: Vec<u8>) -> Result<Message> {
+    fn u32_from_slice(slice: &[u8]) -> Result<u32> {
+        Ok(u32::from_be_bytes(slice.try_into()?))
+    }
+    fn u16_from_slice(slice: &[u8]) -> Result<u16> {
+        Ok(u16::from_be_bytes(slice.try_into()?))
+    }
+
+    let len = payload.len() + 1;
+    match id {
         0 if len == 1 => Ok(Message::Choke),
         1 if len == 1 => Ok(Message::Unchoke),
         2 if len == 1 => Ok(Message::Interested),
         3 if len == 1 => Ok(Message::NotInterested),
         _ if len == 1 => Err(anyhow!("unexpected message of size 1")),
-        _ => {
-            let mut payload_p = vec![0; len as usize - 1];
-            stream
-                .read_exact(&mut payload_p)
-                .await
-                .context("payload_p read error")?;
-            match id {
-                4 if len == 5 => Ok(Message::Have {
-                    piece_index: u32_from_slice(&payload_p[0..4])?,
-                }),
-                5 => Ok(Message::Bitfield { bitfield: payload_p }),
-                6 if len == 13 => Ok(Message::Request {
-                    piece_index: u32_from_slice(&payload_p[0..4])?,
-                    begin: u32_from_slice(&payload_p[4..8])?,
-                    length: u32_from_slice(&payload_p[8..12])?,
-                }),
-                7 if len > 9 => Ok(Message::Piece {
-                    piece_index: u32_from_slice(&payload_p[0..4])?,
-                    begin: u32_from_slice(&payload_p[4..8])?,
-                    block: Block(payload_p[8..].to_vec()),
-                }),
-                8 if len == 13 => Ok(Message::Cancel {
-                    piece_index: u32_from_slice(&payload_p[0..4])?,
-                    begin: u32_from_slice(&payload_p[4..8])?,
-                    length: u32_from_slice(&payload_p[8..12])?,
-                }),
-                9 if len == 3 => Ok(Message::Port {
-                    port: u16_from_slice(&payload_p[0..2])?,
-                }),
-                20 => {
-                    let ext_id = payload_p[0];
-                    let payload = if payload_p.len() == 1 {
-                        None
-                    } else {
-                        Some(payload_p[1..].to_vec())
-                    };
-                    Ok(Message::Extended { ext_id, payload })
-                }
-                _ => Err(anyhow!(
-                    "unexpected message: {}",
-                    hex(&[len_p.as_ref(), &id_p, payload_p.as_slice()].concat())
-                )),
-            }
+        4 if len == 5 => Ok(Message::Have {
+            piece_index: u32_from_slice(&payload[0..4])?,
+        }),
+        5 => Ok(Message::Bitfield { bitfield: payload }),
+        6 if len == 13 => Ok(Message::Request {
+            piece_index: u32_from_slice(&payload[0..4])?,
+            begin: u32_from_slice(&payload[4..8])?,
+            length: u32_from_slice(&payload[8..12])?,
+        }),
+        7 if len > 9 => Ok(Message::Piece {
+            piece_index: u32_from_slice(&payload[0..4])?,
+            begin: u32_from_slice(&payload[4..8])?,
+            block: Block(payload[8..].to_vec()),
+        }),
+        8 if len == 13 => Ok(Message::Cancel {
+            piece_index: u32_from_slice(&payload[0..4])?,
+            begin: u32_from_slice(&payload[4..8])?,
+            length: u32_from_slice(&payload[8..12])?,
+        }),
+        9 if len == 3 => Ok(Message::Port {
+            port: u16_from_slice(&payload[0..2])?,
+        }),
+        20 => {
+            let (ext_id, payload) = payload.split_first().context("empty extended message")?;
+            let payload = if payload.is_empty() {
+                None
+            } else {
+                Some(payload.to_vec())
+            };
+            Ok(Message::Extended {
+                ext_id: *ext_id,
+                payload,
+            })
         }
-    }?;
-    trace!("<<< read message: {:?}", msg);
-    Ok(msg)
+        _ => Err(anyhow!(
+            "unexpected message: {}",
+            hex(&[(len as u32).to_be_bytes().as_slice(), &[id], payload.as_slice()].concat())
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    #[test]
+    fn should_decode_message() {
+        let have: Vec<u8> = Message::Have { piece_index: 7 }.into();
+        let extended: Vec<u8> = Message::Extended {
+            ext_id: 1,
+            payload: Some(b"de".to_vec()),
+        }
+        .into();
+        let bytes = [have.as_slice(), &extended, &[0, 0]].concat();
+        let (msg, rest) = Message::decode(&bytes).unwrap();
+        assert!(matches!(msg, Message::Have { piece_index: 7 }));
+        let (msg, rest) = Message::decode(rest).unwrap();
+        assert!(matches!(msg, Message::Extended { ext_id: 1, payload: Some(p) } if p == b"de"));
+        assert_eq!(rest, [0, 0]);
+        assert!(Message::decode(rest).is_err());
+        assert!(Message::decode(&have[..have.len() - 1]).is_err());
+        assert!(Message::decode(&[0, 0, 0, 1, 20]).is_err());
+        assert!(Message::decode(&[0xff, 0xff, 0xff, 0xff, 7]).is_err());
+    }
+
+    #[test]
+    fn should_not_panic_on_random_input() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..10000 {
+            let len = rng.gen_range(0..20u32);
+            let bytes = [
+                len.to_be_bytes().as_slice(),
+                &(0..rng.gen_range(0..20)).map(|_| rng.gen()).collect::<Vec<u8>>(),
+            ]
+            .concat();
+            let _ = Message::decode(&bytes);
+            let _ = Message::try_from(bytes[3..].to_vec());
+        }
+    }
 }
@@ -1,9 +1,16 @@
 use core::fmt;
 use std::path::PathBuf;
 
-use anyhow::{anyhow, Error, Result};
+use anyhow::{anyhow, Context, Error, Result};
+use serde::{Deserialize, Serialize};
 
-use crate::{bencode::BencodeValue, state::PieceHash};
+use crate::{
+    bencode::{self, parse_bencoded, BencodeValue},
+    state::PieceHash,
+    torrent::get_info_hash,
+    types::ByteString,
+    webseed::WebSeed,
+};
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, Hash)]
 pub struct Metainfo {
@@ -14,6 +21,19 @@ pub struct Metainfo {
     pub comment: Option<String>,
     pub created_by: Option<String>,
     pub encoding: Option<String>,
+    /// Web seeds from `url-list` and `httpseeds` keys
+    pub web_seeds: Vec<WebSeed>,
+    /// Canonical bencoded metainfo dict
+    pub bencoded: Bencoded,
+}
+
+#[derive(Clone, PartialEq, PartialOrd, Hash, Serialize, Deserialize)]
+pub struct Bencoded(pub ByteString);
+
+impl fmt::Debug for Bencoded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<bencoded {}>", self.0.len())
+    }
 }
 
 #[derive(Clone, PartialEq, PartialOrd, Hash)]
@@ -65,10 +85,49 @@ pub struct PathInfo {
     pub md5_sum: Option<String>,
 }
 
+impl Metainfo {
+    /// Parse bencoded metainfo file
+    pub fn parse(bencoded: &[u8]) -> Result<Metainfo> {
+        Metainfo::try_from(bencode::parse(bencoded)?)
+    }
+
+    /// SHA-1 hash of the bencoded info dict
+    pub fn info_hash(&self) -> Result<ByteString> {
+        match parse_bencoded(self.bencoded.0.clone()) {
+            (Some(dict), _) => get_info_hash(&dict),
+            _ => Err(anyhow!("malformed bencoded metainfo")),
+        }
+    }
+
+    /// Tracker to announce to: `announce` or the first one of `announce-list`
+    /// Tracker tiers of `announce-list`, or a single tier of `announce` if there is no list, see
+    /// [BEP-12](https://www.bittorrent.org/beps/bep_0012.html)
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        let tiers = self
+            .announce_list
+            .iter()
+            .flatten()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect::<Vec<_>>();
+        if tiers.is_empty() {
+            self.announce.iter().map(|a| vec![a.clone()]).collect()
+        } else {
+            tiers
+        }
+    }
+
+    /// Whether metainfo lists any tracker. Tracker-less torrents rely on DHT for peer discovery
+    pub fn has_trackers(&self) -> bool {
+        !self.tracker_tiers().is_empty()
+    }
+}
+
 impl TryFrom<BencodeValue> for Metainfo {
     type Error = Error;
 
     fn try_from(value: BencodeValue) -> Result<Self, Self::Error> {
+        let bencoded = Bencoded(value.encode());
         let dict = match value {
             BencodeValue::Dict(d) => d,
             _ => return Err(anyhow!("metafile is not a dict")),
@@ -78,7 +137,8 @@ impl TryFrom<BencodeValue> for Metainfo {
             _ => return Err(anyhow!("'info' is not a dict")),
         };
         let pieces: Vec<PieceHash> = match info_dict.get("pieces") {
-            Some(BencodeValue::String(s)) => s.chunks(20).map(|c| PieceHash(c.to_vec())).collect(),
+            Some(BencodeValue::String(s)) if s.len() % 20 == 0 => s.chunks(20).map(|c| PieceHash(c.to_vec())).collect(),
+            Some(BencodeValue::String(_)) => return Err(anyhow!("'pieces' length is not a multiple of 20")),
             _ => return Err(anyhow!("'pieces' missing")),
         };
         let name: String = match info_dict.get("name") {
@@ -90,7 +150,7 @@ impl TryFrom<BencodeValue> for Metainfo {
             None => FileInfo::Single(PathInfo {
                 path: PathBuf::from(&name),
                 length: match info_dict.get("length") {
-                    Some(BencodeValue::Int(v)) => *v as u64,
+                    Some(BencodeValue::Int(v)) => u64::try_from(*v).context("negative 'length'")?,
                     _ => return Err(anyhow!("'length' missing")),
                 },
                 md5_sum: match info_dict.get("md5_sum") {
@@ -102,7 +162,7 @@ impl TryFrom<BencodeValue> for Metainfo {
         let metainfo = Metainfo {
             info: Info {
                 piece_length: match info_dict.get("piece length") {
-                    Some(BencodeValue::Int(v)) => *v as u64,
+                    Some(BencodeValue::Int(v)) if *v > 0 => *v as u64,
                     _ => return Err(anyhow!("'piece length' missing")),
                 },
                 pieces,
@@ -149,11 +209,48 @@ impl TryFrom<BencodeValue> for Metainfo {
                 Some(BencodeValue::String(s)) => Some(String::from_utf8_lossy(s).into()),
                 _ => None,
             },
+            web_seeds: parse_urls(dict.get("url-list"))
+                .into_iter()
+                .map(WebSeed::Url)
+                .chain(parse_urls(dict.get("httpseeds")).into_iter().map(WebSeed::Http))
+                .collect(),
+            bencoded,
         };
+        validate_pieces(&metainfo.info)?;
         Ok(metainfo)
     }
 }
 
+/// Check that total length does not overflow and matches the number of pieces
+fn validate_pieces(info: &Info) -> Result<()> {
+    let total_length = info
+        .file_info
+        .files()
+        .iter()
+        .try_fold(0u64, |acc, f| acc.checked_add(f.length))
+        .context("total length overflow")?;
+    let expected = total_length.div_ceil(info.piece_length);
+    if info.pieces.len() as u64 != expected {
+        return Err(anyhow!("expected {} pieces, got {}", expected, info.pieces.len()));
+    }
+    Ok(())
+}
+
+/// Parse URL or list of URLs, skipping malformed ones
+fn parse_urls(value: Option<&BencodeValue>) -> Vec<String> {
+    match value {
+        Some(BencodeValue::String(s)) if !s.is_empty() => vec![String::from_utf8_lossy(s).into()],
+        Some(BencodeValue::List(l)) => l
+            .iter()
+            .filter_map(|i| match i {
+                BencodeValue::String(s) if !s.is_empty() => Some(String::from_utf8_lossy(s).into()),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
 fn parse_files_info(value: &BencodeValue) -> Result<Vec<PathInfo>> {
     match value {
         BencodeValue::List(l) => l
@@ -174,7 +271,7 @@ fn parse_files_info(value: &BencodeValue) -> Result<Vec<PathInfo>> {
                     };
                     Ok(PathInfo {
                         length: match d.get("length") {
-                            Some(BencodeValue::Int(v)) => *v as u64,
+                            Some(BencodeValue::Int(v)) => u64::try_from(*v).context("negative 'length'")?,
                             _ => return Err(anyhow!("'length' missing")),
                         },
                         path,
@@ -190,3 +287,40 @@ fn parse_files_info(value: &BencodeValue) -> Result<Vec<PathInfo>> {
         _ => Err(anyhow!("'files' is not a list")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metainfo(length: &str, pieces: usize) -> Vec<u8> {
+        format!(
+            "d4:infod6:length{}4:name1:a12:piece lengthi16384e6:pieces{}:{}ee",
+            length,
+            pieces * 20,
+            "a".repeat(pieces * 20)
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn should_validate_metainfo() {
+        let parsed = Metainfo::parse(&metainfo("i20000e", 2)).unwrap();
+        assert_eq!(parsed.info.file_info.total_length(), 20000);
+        assert!(Metainfo::parse(&metainfo("i20000e", 1)).is_err());
+        assert!(Metainfo::parse(&metainfo("i-1e", 0)).is_err());
+        assert!(Metainfo::parse(&metainfo("i20000", 2)).is_err());
+    }
+
+    #[test]
+    fn should_list_tracker_tiers() {
+        let mut parsed = Metainfo::parse(&metainfo("i20000e", 2)).unwrap();
+        assert!(!parsed.has_trackers());
+        parsed.announce = Some("a".into());
+        assert_eq!(parsed.tracker_tiers(), vec![vec!["a".to_string()]]);
+        parsed.announce_list = Some(vec![vec![], vec!["b".into(), "c".into()], vec!["d".into()]]);
+        assert_eq!(
+            parsed.tracker_tiers(),
+            vec![vec!["b".to_string(), "c".to_string()], vec!["d".to_string()]]
+        );
+    }
+}
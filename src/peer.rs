@@ -1,11 +1,16 @@
 use anyhow::{anyhow, ensure, Context, Result};
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use std::sync::Arc;
+use rand::{distributions::Alphanumeric, seq::SliceRandom, thread_rng, Rng};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncWriteExt, BufReader},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
+        TcpListener, TcpSocket, TcpStream,
     },
     select, spawn,
     sync::Mutex,
@@ -13,19 +18,40 @@ use tokio::{
 };
 
 use crate::{
-    bencode::{parse_bencoded, BencodeValue},
+    bencode::{self, parse_bencoded, BencodeValue},
+    config::Config,
+    dht::add_dht_node,
     extension::Extension,
     feature::Feature,
     hex::hex,
-    message::{read_message, Message},
+    holepunch::{HolepunchError, HolepunchMessage},
+    hook::{run_hook, HookEnv, HookEvent},
+    message::{read_handshake, read_message, Message},
     metainfo::Metainfo,
-    peer_metainfo::{PeerMetainfoMessage, METAINFO_PIECE_SIZE},
+    peer_metainfo::{MetainfoState, PeerMetainfoMessage, METAINFO_PIECE_SIZE},
     sha1,
-    state::{init_pieces, Block, Peer, PeerInfo, PeerStatus, Piece, State, TorrentStatus, BLOCK_SIZE},
-    torrent::write_piece,
+    state::{
+        init_pieces, validate_bitfield, Availability, Block, Peer, PeerInfo, PeerStatus, Piece, State, TorrentStatus,
+        BLOCK_SIZE,
+    },
+    stats::PeerSource,
+    torrent::{file_path, write_piece_retry},
     types::ByteString,
+    wire_dump::{self, Direction},
 };
 
+/// Client name and version from the Azureus-style peer id, e.g. `-TR2940-` is `TR 2940`
+fn client_name(peer_id: &[u8]) -> Option<String> {
+    match peer_id.get(..8)? {
+        [b'-', id @ .., b'-'] if id.iter().all(|c| c.is_ascii_alphanumeric()) => Some(format!(
+            "{} {}",
+            String::from_utf8_lossy(&id[..2]),
+            String::from_utf8_lossy(&id[2..])
+        )),
+        _ => None,
+    }
+}
+
 /// Generate random 20 byte string, starting with -<2 byte client name><4 byte client version>-
 pub fn generate_peer_id() -> ByteString {
     let rand = thread_rng().sample_iter(&Alphanumeric).take(12).collect::<Vec<_>>();
@@ -33,34 +59,26 @@ pub fn generate_peer_id() -> ByteString {
 }
 
 pub async fn handshake(peer: &PeerInfo, state: Arc<Mutex<State>>) -> Result<(TcpStream, Message)> {
-    let (info_hash, peer_id, peer_connect_timeout) = {
+    let (info_hash, peer_id, peer_connect_timeout, handshake_timeout, bind_address) = {
         let state = state.lock().await;
         (
             state.info_hash.clone(),
             state.peer_id.clone(),
             state.config.peer_connect_timeout,
+            state.config.handshake_timeout,
+            state.config.bind_address,
         )
     };
-    let mut stream = timeout(peer_connect_timeout, TcpStream::connect(peer.to_addr())).await??;
+    let mut stream = timeout(peer_connect_timeout, connect(peer, bind_address)).await??;
 
-    let handshake: Vec<u8> = Message::Handshake {
-        info_hash: info_hash.clone(),
-        peer_id: peer_id.clone(),
-        reserved: Feature::new_with(&[Feature::Dht, Feature::Extension]),
-    }
-    .into();
-
-    trace!("writing handshake {}", hex(&handshake.to_vec()));
-    stream.write_all(&handshake).await.context("write error")?;
-    stream.flush().await?;
-
-    let mut read_packet = [0; 68];
-    trace!("reading handshake");
-    stream.read_exact(&mut read_packet).await.context("read error")?;
-    let msg: Vec<u8> = read_packet.to_vec();
-    trace!("peer response: {}", hex(&msg));
-
-    let msg = Message::try_from(msg).context("handshake parse error")?;
+    let msg = timeout(handshake_timeout, async {
+        write_handshake(&mut stream, &info_hash, &peer_id).await?;
+        trace!("reading handshake");
+        read_handshake(&mut stream).await.context("handshake read error")
+    })
+    .await
+    .context("handshake timeout")??;
+    wire_dump::record(peer.to_addr(), Direction::In, &msg);
     if let Message::Handshake {
         info_hash: ref h_info_hash,
         ..
@@ -73,8 +91,133 @@ pub async fn handshake(peer: &PeerInfo, state: Arc<Mutex<State>>) -> Result<(Tcp
     }
 }
 
+async fn write_handshake(stream: &mut TcpStream, info_hash: &[u8], peer_id: &[u8]) -> Result<()> {
+    let msg = Message::Handshake {
+        info_hash: info_hash.to_vec(),
+        peer_id: peer_id.to_vec(),
+        reserved: Feature::new_with(&Feature::SUPPORTED),
+    };
+    if wire_dump::is_enabled() {
+        wire_dump::record(stream.peer_addr()?, Direction::Out, &msg);
+    }
+    let handshake: Vec<u8> = msg.into();
+
+    trace!("writing handshake {}", hex(&handshake.to_vec()));
+    stream.write_all(&handshake).await.context("write error")?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Bind listener for incoming peer connections, trying configured ports in order (or shuffled if `random_port`).
+/// Falls back to the OS-assigned port if none of the configured ports are available.
+/// Without bind address, dual-stack IPv6 listener is preferred so that IPv6 peers can connect too
+pub async fn bind_listener(config: &Config) -> Result<TcpListener> {
+    let ips = match config.bind_address {
+        Some(ip) => vec![ip],
+        None => vec![IpAddr::V6(Ipv6Addr::UNSPECIFIED), IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+    };
+    let mut ports = config.ports.clone();
+    if config.random_port {
+        ports.shuffle(&mut thread_rng());
+    }
+    for ip in &ips {
+        for port in &ports {
+            match TcpListener::bind(SocketAddr::new(*ip, *port)).await {
+                Ok(listener) => return Ok(listener),
+                Err(e) => debug!("unable to bind to {}: {}", SocketAddr::new(*ip, *port), e),
+            }
+        }
+    }
+    warn!("none of the ports {:?} are available, using random port", config.ports);
+    let mut res = Err(anyhow!("no addresses to bind to"));
+    for ip in ips {
+        res = TcpListener::bind(SocketAddr::new(ip, 0))
+            .await
+            .context(format!("unable to bind to {}", ip));
+        if res.is_ok() {
+            break;
+        }
+    }
+    res
+}
+
+/// Accept incoming peer connections
+pub async fn listen_loop(listener: TcpListener, state: Arc<Mutex<State>>) -> Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        debug!("incoming connection from {}", addr);
+        let task = spawn({
+            let state = state.clone();
+            async move {
+                if let Err(e) = accept_peer(stream, addr, state).await.context("incoming peer error") {
+                    debug!("{e:#}");
+                }
+            }
+        });
+        state
+            .lock()
+            .await
+            .peer_tasks
+            .insert(PeerInfo::from(addr), task.abort_handle());
+    }
+}
+
+async fn accept_peer(mut stream: TcpStream, addr: SocketAddr, state: Arc<Mutex<State>>) -> Result<()> {
+    let (info_hash, peer_id, handshake_timeout) = {
+        let state = state.lock().await;
+        ensure!(
+            state.active_peers() < state.config.max_connections,
+            "connection limit of {} is reached",
+            state.config.max_connections
+        );
+        (
+            state.info_hash.clone(),
+            state.peer_id.clone(),
+            state.config.handshake_timeout,
+        )
+    };
+    let msg = timeout(handshake_timeout, read_handshake(&mut stream))
+        .await
+        .context("handshake timeout")??;
+    wire_dump::record(addr, Direction::In, &msg);
+    match &msg {
+        Message::Handshake {
+            info_hash: h_info_hash, ..
+        } if h_info_hash == &info_hash => {}
+        _ => return Err(anyhow!("unexpected handshake")),
+    }
+    write_handshake(&mut stream, &info_hash, &peer_id).await?;
+    let peer = PeerInfo::from(addr);
+    handle_peer(peer, state, Some((stream, msg))).await
+}
+
+/// Connect to the peer, binding local socket to the bind address if specified
+async fn connect(peer: &PeerInfo, bind_address: Option<IpAddr>) -> Result<TcpStream> {
+    let bind_address = match bind_address {
+        Some(a) => a,
+        _ => return Ok(TcpStream::connect(peer.to_addr()).await?),
+    };
+    let addr = peer.to_addr();
+    ensure!(
+        addr.is_ipv4() == bind_address.is_ipv4(),
+        "peer address is not of bind address family"
+    );
+    let socket = if bind_address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket
+        .bind(SocketAddr::new(bind_address, 0))
+        .with_context(|| format!("unable to bind to {}", bind_address))?;
+    Ok(socket.connect(addr).await?)
+}
+
 pub async fn send_message(stream: &mut OwnedWriteHalf, message: Message) -> Result<()> {
     trace!(">>> sending message: {:?}", message);
+    if wire_dump::is_enabled() {
+        wire_dump::record(stream.peer_addr()?, Direction::Out, &message);
+    }
     let msg_p: Vec<u8> = message.into();
     trace!("raw message: {}", hex(&msg_p));
     stream.write_all(&msg_p).await?;
@@ -84,26 +227,45 @@ pub async fn send_message(stream: &mut OwnedWriteHalf, message: Message) -> Resu
 
 pub async fn peer_loop(state: Arc<Mutex<State>>) -> Result<()> {
     let config = state.lock().await.config.clone();
-    let mut handles = vec![];
     loop {
         debug!("reconnecting peers");
-        let peers: Vec<PeerInfo> = state
-            .lock()
-            .await
-            .peers
-            .values()
-            .filter(|p| p.status == PeerStatus::Disconnected)
-            .map(|p| p.info.clone())
-            .collect();
-        trace!("disconnected peers: {}", peers.len());
-        peers.into_iter().for_each(|p| {
-            let state = state.clone();
-            handles.push(spawn(async {
-                if let Err(e) = handle_peer(p, state).await.context("peer error") {
-                    debug!("{e:#}");
-                };
-            }));
-        });
+        let peers: Vec<PeerInfo> = {
+            let mut state = state.lock().await;
+            state.peers.retain(|_, p| {
+                let evict = p.status == PeerStatus::Disconnected && p.connect_fails >= config.max_connect_fails;
+                if evict {
+                    debug!("evicting peer {:?} after {} failed connects", p.info, p.connect_fails);
+                }
+                !evict
+            });
+            state.peer_tasks.prune();
+            let connecting = state
+                .peers
+                .values()
+                .filter(|p| p.status == PeerStatus::Connecting)
+                .count();
+            let slots = config
+                .max_connecting
+                .saturating_sub(connecting)
+                .min(config.max_connections.saturating_sub(state.active_peers()));
+            let mut candidates = state
+                .peers
+                .values()
+                .filter(|p| {
+                    p.status == PeerStatus::Disconnected
+                        && p.can_reconnect(&config)
+                        && !state.peer_tasks.is_live(&p.info)
+                })
+                .map(|p| p.info.clone())
+                .collect::<Vec<_>>();
+            state.registry.sort(&mut candidates);
+            candidates.truncate(slots);
+            candidates
+        };
+        trace!("reconnecting {} peers", peers.len());
+        for p in peers {
+            connect_peer(p, state.clone()).await;
+        }
 
         select!(
             _ = async {
@@ -114,6 +276,7 @@ pub async fn peer_loop(state: Arc<Mutex<State>>) -> Result<()> {
                     sleep(config.downloaded_check_wait).await
                 }
             } => {
+                state.lock().await.peer_tasks.abort_all();
                 return Ok(())
             },
             _ = sleep(config.reconnect_wait) => ()
@@ -121,59 +284,151 @@ pub async fn peer_loop(state: Arc<Mutex<State>>) -> Result<()> {
     }
 }
 
-pub async fn handle_peer(peer: PeerInfo, state: Arc<Mutex<State>>) -> Result<()> {
+/// Spawn outgoing connection task of the peer
+pub async fn connect_peer(peer: PeerInfo, state: Arc<Mutex<State>>) {
+    let task = spawn({
+        let (peer, state) = (peer.clone(), state.clone());
+        async {
+            if let Err(e) = handle_peer(peer, state, None).await.context("peer error") {
+                debug!("{e:#}");
+            };
+        }
+    });
+    state.lock().await.peer_tasks.insert(peer, task.abort_handle());
+}
+
+/// Handle peer connection. Outgoing connection is established if no incoming connection is specified
+pub async fn handle_peer(
+    peer: PeerInfo,
+    state: Arc<Mutex<State>>,
+    incoming: Option<(TcpStream, Message)>,
+) -> Result<()> {
     {
         debug!("connecting to peer: {:?}", peer);
         let mut state = state.lock().await;
+        if state.registry.is_banned(&peer) {
+            if let Some(p) = state.peers.get_mut(&peer) {
+                p.status = PeerStatus::Banned;
+            }
+            return Err(anyhow!("peer is banned in another torrent"));
+        }
         match state.peers.get_mut(&peer) {
-            Some(p) if p.status == PeerStatus::Connected => return Err(anyhow!("peer is already connected")),
-            Some(p) => p.status = PeerStatus::Connected,
+            Some(p) if matches!(p.status, PeerStatus::Connecting | PeerStatus::Connected) => {
+                return Err(anyhow!("peer is already connected"))
+            }
+            Some(p) if p.status == PeerStatus::Banned => return Err(anyhow!("peer is banned")),
+            Some(p) => {
+                p.status = PeerStatus::Connecting;
+                p.connect_fails += 1;
+                p.last_connect = Some(Instant::now());
+            }
             None => {
-                let mut p = Peer::new(peer.clone());
-                p.status = PeerStatus::Connected;
+                let mut p = Peer::new(peer.clone(), PeerSource::Incoming);
+                p.status = PeerStatus::Connecting;
                 state.peers.insert(peer.clone(), p);
+                state.evict_peers();
             }
         };
     };
 
-    let res = do_handle_peer(peer.clone(), state.clone()).await;
+    let res = do_handle_peer(peer.clone(), state.clone(), incoming).await;
 
     debug!("peer disconnected: {:?}", peer);
-    state.lock().await.peers.get_mut(&peer).context("no peer")?.status = if res.is_err() {
-        PeerStatus::Disconnected
-    } else {
-        PeerStatus::Done
-    };
+    let mut state = state.lock().await;
+    state.release_pieces(&peer);
+    let State {
+        peers,
+        availability,
+        registry,
+        info_hash,
+        ..
+    } = &mut *state;
+    let p = peers.get_mut(&peer).context("no peer")?;
+    registry.disconnected(&peer, info_hash, p.rate);
+    // availability only counts connected peers, bitfield is sent again on reconnect
+    if let Some(bitfield) = p.bitfield.take() {
+        availability.remove_bitfield(&bitfield);
+    }
+    p.requests_out.clear();
+    p.requests_in.clear();
+    p.revoked.clear();
+    if p.status != PeerStatus::Banned {
+        p.status = if res.is_err() {
+            PeerStatus::Disconnected
+        } else {
+            PeerStatus::Done
+        };
+    }
 
     res
 }
 
-pub async fn do_handle_peer(peer: PeerInfo, state: Arc<Mutex<State>>) -> Result<()> {
-    let (stream, handshake) = handshake(&peer, state.clone()).await.context("handshake error")?;
+pub async fn do_handle_peer(
+    peer: PeerInfo,
+    state: Arc<Mutex<State>>,
+    incoming: Option<(TcpStream, Message)>,
+) -> Result<()> {
+    let (stream, handshake) = match incoming {
+        Some(incoming) => incoming,
+        None => match handshake(&peer, state.clone()).await {
+            Ok(r) => r,
+            Err(e) => {
+                request_holepunch(&state, &peer).await;
+                return Err(e.context("handshake error"));
+            }
+        },
+    };
     info!("successfull handshake with peer {:?}", peer);
 
-    if let Some(p) = state.lock().await.peers.get_mut(&peer) {
-        p.status = PeerStatus::Connected;
+    let (peer_features, client) = match handshake {
+        Message::Handshake { reserved, peer_id, .. } => (Feature::parse(&reserved), client_name(&peer_id)),
+        _ => (BTreeSet::new(), None),
+    };
+    let features = Feature::negotiate(&peer_features);
+    debug!("peer features: {:?}, negotiated: {:?}", peer_features, features);
+    {
+        let mut state = state.lock().await;
+        state.registry.connected(&peer, &state.info_hash);
+        if let Some(p) = state.peers.get_mut(&peer) {
+            p.status = PeerStatus::Connected;
+            p.features = features.clone();
+            p.client = client;
+        }
     }
 
     let (r_stream, mut w_stream) = stream.into_split();
 
-    let supports_ext = match handshake {
-        Message::Handshake { reserved, .. } => Feature::Extension.enabled(&reserved),
-        _ => false,
-    };
-    if supports_ext {
+    if features.contains(&Feature::Extension) {
+        let upload_only = matches!(
+            state.lock().await.status,
+            TorrentStatus::Downloaded | TorrentStatus::Saved
+        );
+        let (port, reqq) = {
+            let state = state.lock().await;
+            (state.port, state.config.reqq)
+        };
+        let ext_handshake = Extension::handshake(
+            &[Extension::Metadata, Extension::Holepunch, Extension::UploadOnly],
+            upload_only,
+            port,
+            reqq,
+        );
         send_message(
             &mut w_stream,
             Message::Extended {
                 ext_id: 0,
-                payload: Some(Extension::handshake(&[Extension::Metadata]).encode()),
+                payload: Some(ext_handshake.encode()),
             },
         )
         .await?;
     }
+    if features.contains(&Feature::Dht) {
+        let port = state.lock().await.port;
+        send_message(&mut w_stream, Message::Port { port }).await?;
+    }
     send_message(&mut w_stream, Message::Unchoke).await?;
     send_message(&mut w_stream, Message::Interested).await?;
+    set_am_choked(&state, &peer, false).await;
 
     select!(
         r = {
@@ -198,43 +453,138 @@ async fn write_loop(mut stream: OwnedWriteHalf, peer: PeerInfo, state: Arc<Mutex
                 state.peers.get(&peer).cloned().context("no peer")?,
             )
         };
+        if p.status == PeerStatus::Banned {
+            return Err(anyhow!("peer is banned"));
+        }
+        if !p.holepunch_queue.is_empty() {
+            write_holepunch(&mut stream, &state, &p).await?;
+        }
+        write_cancels(&mut stream, &state, &peer).await?;
+        write_blocks(&mut stream, &state, &peer).await?;
         if config.respect_choke && p.choked {
             debug!("peer is choked, waiting");
-            sleep(config.choke_wait).await;
+            p.notify.wait(config.choke_wait).await;
             continue;
         }
 
         let status = state.lock().await.status.clone();
-        match status {
+        // whether there is nothing to do until notified
+        let idle = match status {
+            TorrentStatus::Paused => {
+                if !p.am_choked {
+                    debug!("torrent is paused, choking peer");
+                    send_message(&mut stream, Message::Choke).await?;
+                    set_am_choked(&state, &peer, true).await;
+                    // choked peer discards its pending requests
+                    if let Some(p) = state.lock().await.peers.get_mut(&peer) {
+                        p.requests_in.clear();
+                    }
+                }
+                true
+            }
+            _ if p.am_choked => {
+                debug!("torrent is resumed, unchoking peer");
+                send_message(&mut stream, Message::Unchoke).await?;
+                send_message(&mut stream, Message::Interested).await?;
+                set_am_choked(&state, &peer, false).await;
+                continue;
+            }
             TorrentStatus::Metainfo => {
-                write_metainfo(&mut stream, state.clone(), p).await?;
+                // metadata pieces are requested one at a time, next request is sent on reply
+                write_metainfo(&mut stream, state.clone(), p.clone()).await?;
+                true
             }
+            // request queue is full, do not reserve more pieces until blocks arrive
+            TorrentStatus::Downloading if p.free_requests(config.request_queue_time) == 0 => true,
             TorrentStatus::Downloading => {
-                let piece = state.lock().await.next_piece();
+                let piece = state.lock().await.next_piece(&peer);
                 match piece {
-                    Some(piece) => {
-                        write_piece_request(&mut stream, piece).await?;
+                    Some(piece) if piece.is_complete() => {
+                        debug!("piece {} is downloaded but not saved, retrying write", piece.index);
+                        if let Some(p) = state.lock().await.pieces.as_mut().unwrap().get_mut(&piece.index) {
+                            p.status = TorrentStatus::Downloaded;
+                        }
+                        if let Err(e) = write_piece_retry(piece.index, state.clone()).await {
+                            debug!("{e:#}");
+                        }
+                        false
                     }
-                    _ => {
-                        info!("torrent is downloaded");
-                        state.lock().await.status = TorrentStatus::Downloaded;
+                    Some(piece) => write_piece_request(&mut stream, &peer, &state, piece).await? == 0,
+                    // torrent is complete once the last piece is saved
+                    _ if state.lock().await.is_downloaded() => {
                         debug!("nothing else to do, disconnecting");
                         return Ok(());
                     }
-                };
+                    _ if p.is_idle_upload_only() => {
+                        debug!("upload only peer has no pieces we need, disconnecting");
+                        return Ok(());
+                    }
+                    _ => {
+                        trace!("peer has no pieces we need");
+                        true
+                    }
+                }
             }
             _ => {
                 debug!("nothing else to do, disconnecting");
                 return Ok(());
             }
         };
-        let wait = state.lock().await.config.piece_request_wait;
-        sleep(wait).await;
+        if idle {
+            p.notify.wait(config.choke_wait).await;
+        }
+    }
+}
+
+/// Ask a connected peer supporting holepunch extension to relay connection to the unreachable peer
+async fn request_holepunch(state: &Arc<Mutex<State>>, peer: &PeerInfo) {
+    let mut state = state.lock().await;
+    match state.peers.get_mut(peer) {
+        Some(p) if !p.holepunch_requested => p.holepunch_requested = true,
+        _ => return,
+    }
+    let relay = state.peers.values_mut().find(|p| {
+        p.status == PeerStatus::Connected && p.extension_map.contains_key(&Extension::Holepunch) && &p.info != peer
+    });
+    if let Some(relay) = relay {
+        debug!("requesting holepunch to {:?} via {:?}", peer, relay.info);
+        relay.queue_holepunch(HolepunchMessage::Rendezvous { addr: peer.clone() });
+    }
+}
+
+async fn write_holepunch(stream: &mut OwnedWriteHalf, state: &Arc<Mutex<State>>, p: &Peer) -> Result<()> {
+    let msgs = match state.lock().await.peers.get_mut(&p.info) {
+        Some(p) => p.holepunch_queue.drain(..).collect::<Vec<_>>(),
+        _ => return Ok(()),
+    };
+    let ext_id = match p.extension_map.get(&Extension::Holepunch) {
+        Some(id) => *id,
+        _ => return Ok(()),
+    };
+    for msg in msgs {
+        debug!("sending holepunch message {:?}", msg);
+        let msg = Message::Extended {
+            ext_id,
+            payload: Some(msg.try_into()?),
+        };
+        send_message(stream, msg).await?;
+    }
+    Ok(())
+}
+
+async fn set_am_choked(state: &Arc<Mutex<State>>, peer: &PeerInfo, am_choked: bool) {
+    if let Some(p) = state.lock().await.peers.get_mut(peer) {
+        p.am_choked = am_choked;
     }
 }
 
+/// Request next metainfo piece from the peer. Returns false if peer does not support metadata extension
 async fn write_metainfo(stream: &mut OwnedWriteHalf, state: Arc<Mutex<State>>, p: Peer) -> Result<()> {
-    if let Some(ext_id) = p.extension_map.get(&Extension::Metadata).copied() {
+    let ext_id = match p.extension_map.get(&Extension::Metadata) {
+        Some(id) => *id,
+        _ => return Ok(()),
+    };
+    {
         let metainfo = state.lock().await.metainfo.clone();
         if let Err(m_state) = metainfo {
             if let Some(i) = m_state.next_piece() {
@@ -248,26 +598,40 @@ async fn write_metainfo(stream: &mut OwnedWriteHalf, state: Arc<Mutex<State>>, p
                 send_message(stream, msg).await?;
             } else {
                 debug!("all metainfo pieces downloaded");
-                let mut state = state.lock().await;
                 let data = m_state.pieces.into_values().flat_map(|b| b.0).collect::<Vec<_>>();
-                if let (Some(info_dict), _) = parse_bencoded(data) {
-                    debug!("bencoded metainfo: {:?}", info_dict);
-                    // since peer metainfo protocol only transfers info dict, it needs
-                    // to be inserted into fake metainfo dict to parse properly
-                    let metainfo_dict = BencodeValue::Dict([("info".into(), info_dict)].into_iter().collect());
-                    match Metainfo::try_from(metainfo_dict) {
-                        Ok(metainfo) => {
-                            state.pieces = Some(init_pieces(&metainfo.info));
-                            state.metainfo = Ok(metainfo);
-                            state.status = TorrentStatus::Downloading;
-                            info!("metainfo is downloaded: {:?}", state.metainfo);
-                        }
-                        Err(e) => {
-                            panic!("unable to parse metainfo from bencoded: {:#}", e);
-                        }
+                let info_hash = state.lock().await.info_hash.clone();
+                let res = parse_info_dict(data, &info_hash).await;
+                let mut state = state.lock().await;
+                match res {
+                    Ok((metainfo, pieces)) => {
+                        // bitfields received before metainfo are counted now
+                        let mut availability = Availability::new(pieces.len());
+                        state
+                            .peers
+                            .values()
+                            .filter_map(|p| p.bitfield.as_deref())
+                            .for_each(|b| availability.add_bitfield(b));
+                        state.availability = availability;
+                        state.pieces = Some(pieces);
+                        state.metainfo = Ok(metainfo);
+                        state.status = if state.config.metainfo_only {
+                            // nothing else to download
+                            TorrentStatus::Downloaded
+                        } else {
+                            TorrentStatus::Downloading
+                        };
+                        state.notify_peers();
+                        info!("metainfo is downloaded: {:?}", state.metainfo);
+                        let env = HookEnv::new(&state, state.config.data_dir());
+                        let config = state.config.clone();
+                        spawn(async move { run_hook(&config, HookEvent::Metainfo, env).await });
+                    }
+                    Err(e) => {
+                        // any peer could have sent the bad piece, metainfo is fetched again from scratch
+                        warn!("invalid metainfo from peers: {e:#}");
+                        state.metainfo = Err(MetainfoState::default());
+                        return Err(e.context("invalid metainfo"));
                     }
-                } else {
-                    warn!("unable to parse bencoded metainfo");
                 }
             }
         } else {
@@ -277,31 +641,228 @@ async fn write_metainfo(stream: &mut OwnedWriteHalf, state: Arc<Mutex<State>>, p
     Ok(())
 }
 
-async fn write_piece_request(stream: &mut OwnedWriteHalf, piece: Piece) -> Result<()> {
+/// Parse info dict assembled from metadata pieces, checking it against the info hash
+async fn parse_info_dict(data: ByteString, info_hash: &[u8]) -> Result<(Metainfo, BTreeMap<u32, Piece>)> {
+    ensure!(
+        sha1::encode_blocking(data.clone()).await? == info_hash,
+        "info dict does not match info hash"
+    );
+    let info_dict = bencode::parse(&data)?;
+    // since peer metainfo protocol only transfers info dict, it needs
+    // to be inserted into fake metainfo dict to parse properly
+    let metainfo = Metainfo::try_from(BencodeValue::Dict([("info".into(), info_dict)].into_iter().collect()))?;
+    let pieces = init_pieces(&metainfo.info).context("malformed metainfo")?;
+    Ok((metainfo, pieces))
+}
+
+/// Serve block requests of the unchoked peer, one at a time so that cancels received meanwhile are respected.
+/// Requests of pieces we don't have are dropped
+async fn write_blocks(stream: &mut OwnedWriteHalf, state: &Arc<Mutex<State>>, peer: &PeerInfo) -> Result<()> {
+    loop {
+        let (request, locations, disk) = {
+            let mut state = state.lock().await;
+            let State {
+                pieces,
+                peers,
+                metainfo,
+                config,
+                disk,
+                ..
+            } = &mut *state;
+            let (pieces, info, p) = match (pieces.as_ref(), metainfo.as_ref(), peers.get_mut(peer)) {
+                (Some(pieces), Ok(metainfo), Some(p)) if !p.am_choked => (pieces, &metainfo.info, p),
+                _ => return Ok(()),
+            };
+            let request @ (piece_index, begin, length) = match p.requests_in.pop_first() {
+                Some(r) => r,
+                _ => return Ok(()),
+            };
+            let piece = match pieces.get(&piece_index) {
+                Some(piece) if piece.status == TorrentStatus::Saved => piece,
+                _ => {
+                    debug!("peer requested piece {} we don't have", piece_index);
+                    continue;
+                }
+            };
+            if length > BLOCK_SIZE || begin.saturating_add(length) > piece.length {
+                debug!("invalid request: {:?}", request);
+                continue;
+            }
+            let files = info.file_info.files();
+            let locations = piece
+                .file_locations
+                .iter()
+                .map(|f| {
+                    let path = file_path(config.data_dir(), info, files[f.file_index]);
+                    (path, f.offset as u64, f.length)
+                })
+                .collect::<Vec<_>>();
+            (request, locations, disk.clone())
+        };
+        let (piece_index, begin, length) = request;
+        let block = disk
+            .read_block(piece_index, &locations, begin as usize, length as usize)
+            .await?;
+        send_message(
+            stream,
+            Message::Piece {
+                piece_index,
+                begin,
+                block: Block(block),
+            },
+        )
+        .await?;
+        let mut state = state.lock().await;
+        state.stats.uploaded += length as u64;
+        if let Some(p) = state.peers.get_mut(peer) {
+            p.uploaded += length as u64;
+        }
+    }
+}
+
+/// Cancel requests of blocks that are no longer needed, e.g. received from another peer in endgame or piece is
+/// already saved
+async fn write_cancels(stream: &mut OwnedWriteHalf, state: &Arc<Mutex<State>>, peer: &PeerInfo) -> Result<()> {
+    let cancels = {
+        let mut state = state.lock().await;
+        let State { pieces, peers, .. } = &mut *state;
+        let (pieces, p) = match (pieces.as_ref(), peers.get_mut(peer)) {
+            (Some(pieces), Some(p)) => (pieces, p),
+            _ => return Ok(()),
+        };
+        let revoked = std::mem::take(&mut p.revoked);
+        let stale = p
+            .requests_out
+            .keys()
+            .filter_map(|(piece_index, block_index)| {
+                let piece = pieces.get(piece_index)?;
+                let needed = piece.status == TorrentStatus::Downloading
+                    && !piece.blocks.contains_key(block_index)
+                    && !revoked.contains(piece_index);
+                (!needed).then_some((piece, *block_index))
+            })
+            .collect::<Vec<_>>();
+        stale
+            .into_iter()
+            .map(|(piece, block_index)| {
+                p.requests_out.remove(&(piece.index, block_index));
+                Message::Cancel {
+                    piece_index: piece.index,
+                    begin: block_index * BLOCK_SIZE,
+                    length: piece.block_length(block_index),
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    for msg in cancels {
+        send_message(stream, msg).await?;
+    }
+    Ok(())
+}
+
+/// Request missing blocks of the piece that are not already in flight, never exceeding peer's `reqq`.
+/// Returns number of requested blocks
+async fn write_piece_request(
+    stream: &mut OwnedWriteHalf,
+    peer: &PeerInfo,
+    state: &Arc<Mutex<State>>,
+    piece: Piece,
+) -> Result<usize> {
     debug!("next request piece: {:?}", piece);
     let total_blocks = piece.total_blocks();
 
-    let block_idxs = (0..total_blocks)
-        .filter(|i| !piece.blocks.contains_key(i))
-        .collect::<Vec<_>>();
+    let block_idxs = {
+        let mut state = state.lock().await;
+        let request_timeout = state.config.request_timeout;
+        let queue_time = state.config.request_queue_time;
+        let p = state.peers.get_mut(peer).context("no peer")?;
+        p.requests_out.retain(|_, t| t.elapsed() < request_timeout);
+        let block_idxs = (0..total_blocks)
+            .filter(|i| !piece.blocks.contains_key(i) && !p.requests_out.contains_key(&(piece.index, *i)))
+            .take(p.free_requests(queue_time))
+            .collect::<Vec<_>>();
+        for i in &block_idxs {
+            p.requests_out.insert((piece.index, *i), Instant::now());
+        }
+        block_idxs
+    };
+    let count = block_idxs.len();
     for i in block_idxs {
         let request_msg = Message::Request {
             piece_index: piece.index,
             begin: i * BLOCK_SIZE,
-            length: if i == total_blocks - 1 && piece.length % BLOCK_SIZE != 0 {
-                piece.length % BLOCK_SIZE
-            } else {
-                BLOCK_SIZE
-            },
+            length: piece.block_length(i),
         };
         send_message(stream, request_msg).await?;
     }
-    Ok(())
+    Ok(count)
 }
 
-async fn read_loop(mut stream: OwnedReadHalf, peer: PeerInfo, state: Arc<Mutex<State>>) -> Result<()> {
+async fn read_loop(stream: OwnedReadHalf, peer: PeerInfo, state: Arc<Mutex<State>>) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    // bitfield received before metainfo is known can only be validated later
+    let mut bitfield_unchecked = false;
+    // haves not applied yet, so that bursts of them take the state lock and wake the writer once
+    let mut haves = vec![];
+    let (config, features) = {
+        let state = state.lock().await;
+        let p = state.peers.get(&peer).context("no peer")?;
+        (state.config.clone(), p.features.clone())
+    };
+    let connected = Instant::now();
+    let mut ext_handshake_pending = features.contains(&Feature::Extension);
+    // peer is penalized by its connect fails until it sends something other than keep alive
+    let mut progressed = false;
     loop {
-        match read_message(&mut stream).await {
+        let deadline = [
+            (
+                ext_handshake_pending,
+                config.ext_handshake_timeout,
+                "extended handshake",
+            ),
+            (!progressed, config.first_message_timeout, "message"),
+        ]
+        .into_iter()
+        .filter(|(pending, ..)| *pending)
+        .min_by_key(|(_, t, _)| *t);
+        let msg = match deadline {
+            Some((_, t, what)) => timeout(t.saturating_sub(connected.elapsed()), read_message(&mut stream))
+                .await
+                .map_err(|_| anyhow!("no {} from peer in {:?}", what, t))?,
+            None => read_message(&mut stream).await,
+        };
+        if let Ok(m) = &msg {
+            wire_dump::record(peer.to_addr(), Direction::In, m);
+            if matches!(m, Message::Extended { ext_id: 0, .. }) {
+                ext_handshake_pending = false;
+            }
+            if !progressed && !matches!(m, Message::KeepAlive) {
+                progressed = true;
+                if let Some(p) = state.lock().await.peers.get_mut(&peer) {
+                    p.connect_fails = 0;
+                }
+            }
+        }
+        if let Ok(Message::Have { piece_index }) = msg {
+            // haves past the limit can only pile up before metainfo is known and are dropped
+            if haves.len() < MAX_PENDING_HAVES {
+                haves.push(piece_index);
+            }
+            if !stream.buffer().is_empty() && haves.len() < MAX_PENDING_HAVES {
+                continue;
+            }
+        }
+        if !haves.is_empty() {
+            apply_haves(&state, &peer, &mut haves).await?;
+        }
+        if bitfield_unchecked {
+            let state = state.lock().await;
+            if let (Some(pieces), Some(p)) = (&state.pieces, state.peers.get(&peer)) {
+                validate_bitfield(p.bitfield.as_deref().unwrap_or_default(), pieces.len())?;
+                bitfield_unchecked = false;
+            }
+        }
+        match msg {
             Ok(Message::Choke) => match state.lock().await.peers.get_mut(&peer) {
                 Some(p) => p.choked = true,
                 _ => debug!("no peer {:?}", peer),
@@ -310,22 +871,78 @@ async fn read_loop(mut stream: OwnedReadHalf, peer: PeerInfo, state: Arc<Mutex<S
                 Some(p) => p.choked = false,
                 _ => debug!("no peer {:?}", peer),
             },
+            Ok(Message::Interested) => match state.lock().await.peers.get_mut(&peer) {
+                Some(p) => p.interested = true,
+                _ => debug!("no peer {:?}", peer),
+            },
+            Ok(Message::NotInterested) => match state.lock().await.peers.get_mut(&peer) {
+                Some(p) => p.interested = false,
+                _ => debug!("no peer {:?}", peer),
+            },
             Ok(Message::Piece {
                 piece_index,
                 begin,
                 block,
             }) => {
-                if let Err(e) = read_piece(state.clone(), piece_index, begin, block).await {
+                if let Err(e) = read_piece(state.clone(), &peer, piece_index, begin, block).await {
                     debug!("{e:#}");
                 }
             }
+            Ok(Message::Bitfield { bitfield }) => {
+                let mut state = state.lock().await;
+                match &state.pieces {
+                    Some(pieces) => validate_bitfield(&bitfield, pieces.len())?,
+                    None => bitfield_unchecked = true,
+                }
+                let State {
+                    peers, availability, ..
+                } = &mut *state;
+                match peers.get_mut(&peer) {
+                    Some(p) => {
+                        if let Some(prev) = &p.bitfield {
+                            availability.remove_bitfield(prev);
+                        }
+                        availability.add_bitfield(&bitfield);
+                        p.bitfield = Some(bitfield);
+                    }
+                    _ => debug!("no peer {:?}", peer),
+                }
+            }
+            Ok(Message::Have { .. }) => {}
             Ok(Message::Port { port }) => match state.lock().await.peers.get_mut(&peer) {
                 Some(p) => {
                     debug!("received port {}", port);
-                    p.dht_port = Some(port)
+                    p.dht_port = Some(port);
+                    let node = PeerInfo { ip: peer.ip, port };
+                    spawn(add_dht_node(state.clone(), node));
                 }
                 _ => debug!("no peer {:?}", peer),
             },
+            Ok(Message::Request {
+                piece_index,
+                begin,
+                length,
+            }) => {
+                let mut state = state.lock().await;
+                let reqq = state.config.reqq;
+                if let Some(p) = state.peers.get_mut(&peer) {
+                    // without fast extension there is no reject message, peer times out dropped requests
+                    if p.requests_in.len() >= reqq {
+                        debug!("peer exceeded reqq of {} requests, dropping request", reqq);
+                    } else {
+                        p.requests_in.insert((piece_index, begin, length));
+                    }
+                }
+            }
+            Ok(Message::Cancel {
+                piece_index,
+                begin,
+                length,
+            }) => {
+                if let Some(p) = state.lock().await.peers.get_mut(&peer) {
+                    p.requests_in.remove(&(piece_index, begin, length));
+                }
+            }
             Ok(Message::Extended {
                 ext_id,
                 payload: Some(payload),
@@ -342,23 +959,73 @@ async fn read_loop(mut stream: OwnedReadHalf, peer: PeerInfo, state: Arc<Mutex<S
                 return Err(e);
             }
         };
+        // any message can unblock the writer, e.g. unchoke, have, extended handshake or received block
+        if let Some(p) = state.lock().await.peers.get(&peer) {
+            p.notify.notify();
+        }
     }
 }
 
-async fn read_piece(state: Arc<Mutex<State>>, piece_index: u32, begin: u32, block: Block) -> Result<()> {
+/// Max number of haves buffered before they are applied
+const MAX_PENDING_HAVES: usize = 1024;
+
+/// Mark pieces as present in the peer's bitfield, counting newly announced ones in availability. Haves are kept
+/// until metainfo is known, since their indices cannot be validated before. Like an invalid bitfield, have of a
+/// piece out of range is a protocol error
+async fn apply_haves(state: &Arc<Mutex<State>>, peer: &PeerInfo, haves: &mut Vec<u32>) -> Result<()> {
+    let mut state = state.lock().await;
+    let State {
+        pieces,
+        peers,
+        availability,
+        ..
+    } = &mut *state;
+    let piece_count = match pieces {
+        Some(pieces) => pieces.len(),
+        _ => return Ok(()),
+    };
+    trace!("applying {} haves", haves.len());
+    let p = match peers.get_mut(peer) {
+        Some(p) => p,
+        _ => return Ok(()),
+    };
+    for index in haves.drain(..) {
+        if !p.has_piece_explicit(index) {
+            p.set_piece(index, piece_count).context("invalid have")?;
+            availability.add(index);
+        }
+    }
+    Ok(())
+}
+
+async fn read_piece(
+    state: Arc<Mutex<State>>,
+    peer: &PeerInfo,
+    piece_index: u32,
+    begin: u32,
+    block: Block,
+) -> Result<()> {
     let status = state.lock().await.status.clone();
     if status != TorrentStatus::Downloading {
         debug!("not accepting pieces with status {:?}", status);
         return Ok(());
     }
-    if begin % BLOCK_SIZE != 0 {
+    if !begin.is_multiple_of(BLOCK_SIZE) {
         return Err(anyhow!("block begin is not a multiple of block size"));
     }
     let block_index = begin / BLOCK_SIZE;
 
-    {
+    let piece_data = {
         let mut state = state.lock().await;
-        let piece = match state.pieces.as_mut().unwrap().get_mut(&piece_index) {
+        let State {
+            pieces, peers, stats, ..
+        } = &mut *state;
+        if let Some(p) = peers.get_mut(peer) {
+            let rtt = p.requests_out.remove(&(piece_index, block_index)).map(|t| t.elapsed());
+            p.record_block(block.0.len(), rtt);
+        }
+        let pieces = pieces.as_mut().unwrap();
+        let piece = match pieces.get_mut(&piece_index) {
             Some(p) => p,
             _ => {
                 debug!("no piece with index {:?}", piece_index);
@@ -367,54 +1034,40 @@ async fn read_piece(state: Arc<Mutex<State>>, piece_index: u32, begin: u32, bloc
         };
         if piece.status != TorrentStatus::Downloading {
             debug!("downloaded block of already completed piece, loss");
+            stats.duplicate_blocks += 1;
+            stats.wasted_duplicate += block.0.len() as u64;
             return Ok(());
         }
         let total_blocks = piece.total_blocks();
         if block_index != total_blocks - 1 && block.0.len() != BLOCK_SIZE as usize {
             debug!("block of unexpected size: {}", block.0.len());
+            stats.wasted_wrong_size += block.0.len() as u64;
             return Ok(());
         }
-        if piece.blocks.insert(block_index, block).is_some() {
+        if let Some(b) = piece.blocks.insert(block_index, block) {
             debug!("repeaded block download, loss");
+            stats.duplicate_blocks += 1;
+            stats.wasted_duplicate += b.0.len() as u64;
         };
+        piece.block_peers.insert(block_index, peer.clone());
         trace!("got block {}/{}", piece.blocks.len(), total_blocks);
-        if piece.blocks.len() as u32 == total_blocks {
-            let piece_data: Vec<u8> = piece.blocks.values().flat_map(|b| b.0.as_slice()).copied().collect();
-            let piece_hash = sha1::encode(piece_data);
-            if piece_hash != piece.hash.0 {
-                warn!("piece hash does not match: {:?}", piece);
-                trace!("{}", hex(&piece_hash));
-                trace!("{}", hex(&piece.hash.0));
-                return Ok(());
-            }
-            piece.status = TorrentStatus::Downloaded;
-            info!(
-                "piece {}/{}",
-                state
-                    .pieces
-                    .as_ref()
-                    .unwrap()
-                    .values()
-                    .filter(|p| p.status > TorrentStatus::Downloading)
-                    .count(),
-                state.pieces.as_ref().unwrap().len(),
-            );
+
+        if piece.blocks.len() as u32 != total_blocks {
+            return Ok(());
         }
-    }
+        piece
+            .blocks
+            .values()
+            .flat_map(|b| b.0.as_slice())
+            .copied()
+            .collect::<Vec<_>>()
+    };
 
-    let status = state
-        .lock()
-        .await
-        .pieces
-        .as_ref()
-        .unwrap()
-        .get(&piece_index)
-        .context("no piece")?
-        .status
-        .clone();
-    if status == TorrentStatus::Downloaded {
+    // hash outside of the state lock, so that other peers are not blocked meanwhile
+    let piece_hash = sha1::encode_blocking(piece_data).await?;
+    if complete_piece(&mut *state.lock().await, piece_index, piece_hash) {
         // TODO: async
-        spawn(write_piece(piece_index, state.clone()))
+        spawn(write_piece_retry(piece_index, state.clone()))
             .await?
             .context("error writing piece")?;
         debug!("piece saved");
@@ -422,31 +1075,113 @@ async fn read_piece(state: Arc<Mutex<State>>, piece_index: u32, begin: u32, bloc
     Ok(())
 }
 
+/// Mark the piece with all blocks received as downloaded if its hash matches, otherwise discard its blocks and
+/// penalize contributing peers. Returns whether piece is downloaded
+fn complete_piece(state: &mut State, piece_index: u32, piece_hash: ByteString) -> bool {
+    let State {
+        pieces,
+        peers,
+        config,
+        stats,
+        registry,
+        ..
+    } = state;
+    let pieces = pieces.as_mut().unwrap();
+    let piece = match pieces.get_mut(&piece_index) {
+        // piece can be completed by another peer while its hash is computed
+        Some(p) if p.status == TorrentStatus::Downloading && p.is_complete() => p,
+        _ => return false,
+    };
+    if piece_hash != piece.hash.0 {
+        warn!("piece hash does not match: {:?}", piece);
+        trace!("{}", hex(&piece_hash));
+        trace!("{}", hex(&piece.hash.0));
+        let contributors = piece.block_peers.values().cloned().collect::<BTreeSet<_>>();
+        stats.hash_fails += 1;
+        stats.wasted_hash_fail += piece.length as u64;
+        piece.blocks.clear();
+        piece.block_peers.clear();
+        piece.assigned = None;
+        let max_hash_fails = config.max_hash_fails;
+        for c in contributors {
+            if let Some(p) = peers.get_mut(&c) {
+                p.hash_fails += 1;
+                // hash fails in other torrents count too
+                if registry.hash_fail(&c, max_hash_fails) {
+                    p.status = PeerStatus::Banned;
+                }
+            }
+        }
+        return false;
+    }
+    piece.status = TorrentStatus::Downloaded;
+    stats.downloaded += piece.length as u64;
+    // other peers cancel their requests of this piece
+    peers.values().for_each(|p| p.notify.notify());
+    let contributions = piece
+        .block_peers
+        .iter()
+        .map(|(i, p)| (p.clone(), piece.blocks[i].0.len() as u64))
+        .collect::<Vec<_>>();
+    for (c, len) in contributions {
+        if let Some(p) = peers.get_mut(&c) {
+            p.downloaded += len;
+        }
+    }
+    info!(
+        "piece {}/{}",
+        pieces
+            .values()
+            .filter(|p| p.status > TorrentStatus::Downloading)
+            .count(),
+        pieces.len(),
+    );
+    true
+}
+
 async fn read_ext(state: Arc<Mutex<State>>, peer: &PeerInfo, ext_id: u8, payload: Vec<u8>) -> Result<()> {
     debug!("got extended message: #{}", ext_id);
     match ext_id {
         0 => {
             debug!("got extended handshake");
             match parse_bencoded(payload).0 {
-                Some(BencodeValue::Dict(dict)) => match dict.get("m") {
-                    Some(BencodeValue::Dict(m_d)) => {
-                        let ext_map = m_d
-                            .iter()
-                            .filter_map(|(k, v)| {
-                                let ext = Extension::try_from(k.as_str()).ok()?;
-                                let num = match v {
-                                    BencodeValue::Int(i) => *i as u8,
-                                    _ => return Err(anyhow!("ext id is not an int")).ok(),
-                                };
-                                Some((ext, num))
-                            })
-                            .collect();
-                        trace!("ext map: {:?}", ext_map);
-                        state.lock().await.peers.get_mut(peer).context("no peer")?.extension_map = ext_map;
-                        Ok(())
+                Some(BencodeValue::Dict(dict)) => {
+                    if let Some(BencodeValue::String(ip)) = dict.get("yourip") {
+                        if ip.len() == 4 {
+                            state.lock().await.external_ip = Some(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]));
+                        }
+                    }
+                    match dict.get("m") {
+                        Some(BencodeValue::Dict(m_d)) => {
+                            let ext_map = m_d
+                                .iter()
+                                .filter_map(|(k, v)| {
+                                    let ext = Extension::try_from(k.as_str()).ok()?;
+                                    let num = match v {
+                                        BencodeValue::Int(i) => *i as u8,
+                                        _ => return Err(anyhow!("ext id is not an int")).ok(),
+                                    };
+                                    Some((ext, num))
+                                })
+                                .collect();
+                            trace!("ext map: {:?}", ext_map);
+                            let mut state = state.lock().await;
+                            let p = state.peers.get_mut(peer).context("no peer")?;
+                            p.extension_map = ext_map;
+                            if let Some(BencodeValue::Int(upload_only)) = dict.get("upload_only") {
+                                p.upload_only = *upload_only != 0;
+                            }
+                            if let Some(BencodeValue::Int(reqq)) = dict.get("reqq") {
+                                p.reqq = usize::try_from(*reqq).ok().filter(|r| *r > 0);
+                            }
+                            if let Some(BencodeValue::String(v)) = dict.get("v") {
+                                p.client = Some(String::from_utf8_lossy(v).into_owned());
+                            }
+                            Ok(())
+                        }
+                        _ => Err(anyhow!("no `m` key")),
                     }
-                    _ => Err(anyhow!("no `m` key")),
-                },
+                }
                 _ => Err(anyhow!("parse error")),
             }
         }
@@ -454,6 +1189,13 @@ async fn read_ext(state: Arc<Mutex<State>>, peer: &PeerInfo, ext_id: u8, payload
             debug!("got extended message #{ext_id}");
             match Extension::try_from(ext_id as usize) {
                 Ok(Extension::Metadata) => read_ext_metadata(state, payload).await,
+                Ok(Extension::Holepunch) => read_ext_holepunch(state, peer, payload).await,
+                Ok(Extension::UploadOnly) => {
+                    let upload_only = *payload.first().context("empty upload_only message")? != 0;
+                    debug!("peer {:?} upload only: {}", peer, upload_only);
+                    state.lock().await.peers.get_mut(peer).context("no peer")?.upload_only = upload_only;
+                    Ok(())
+                }
                 _ => Err(anyhow!("unsupported extension id: #{}", ext_id)),
             }
         }
@@ -490,3 +1232,71 @@ async fn read_ext_metadata(state: Arc<Mutex<State>>, payload: Vec<u8>) -> Result
         Err(e) => Err(anyhow!("{e:#}")),
     }
 }
+
+async fn read_ext_holepunch(state: Arc<Mutex<State>>, peer: &PeerInfo, payload: Vec<u8>) -> Result<()> {
+    let msg = HolepunchMessage::try_from(payload)?;
+    debug!("got holepunch message {:?}", msg);
+    match msg {
+        HolepunchMessage::Rendezvous { addr } => {
+            let mut state = state.lock().await;
+            let error = match state.peers.get(&addr) {
+                _ if &addr == peer => Some(HolepunchError::NoSelf),
+                None => Some(HolepunchError::NoSuchPeer),
+                Some(p) if p.status != PeerStatus::Connected => Some(HolepunchError::NotConnected),
+                Some(p) if !p.extension_map.contains_key(&Extension::Holepunch) => Some(HolepunchError::NoSupport),
+                Some(_) => None,
+            };
+            if let Some(code) = error {
+                if let Some(p) = state.peers.get_mut(peer) {
+                    p.queue_holepunch(HolepunchMessage::Error { addr, code });
+                }
+                return Ok(());
+            }
+            if let Some(p) = state.peers.get_mut(&addr) {
+                p.queue_holepunch(HolepunchMessage::Connect { addr: peer.clone() });
+            }
+            if let Some(p) = state.peers.get_mut(peer) {
+                p.queue_holepunch(HolepunchMessage::Connect { addr });
+            }
+            Ok(())
+        }
+        HolepunchMessage::Connect { addr } => {
+            // peer loop connects to it on the next reconnect
+            let mut state = state.lock().await;
+            state.add_peers([addr.clone()], PeerSource::Holepunch);
+            if let Some(p) = state.peers.get_mut(&addr).filter(|p| p.status == PeerStatus::Done) {
+                p.status = PeerStatus::Disconnected;
+            }
+            Ok(())
+        }
+        HolepunchMessage::Error { addr, code } => Err(anyhow!("holepunch to {:?} failed: {:?}", addr, code)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_client_name() {
+        assert_eq!(client_name(b"-TR2940-k8hj0wgej6ch"), Some("TR 2940".into()));
+        assert_eq!(client_name(&generate_peer_id()), Some("ER 0000".into()));
+        assert_eq!(client_name(b"M4-3-6--xxxxxxxxxxxx"), None);
+        assert_eq!(client_name(b"-TR"), None);
+    }
+
+    #[tokio::test]
+    async fn should_parse_info_dict() {
+        let info = b"d6:lengthi20e4:name1:a12:piece lengthi20e6:pieces20:aaaaaaaaaaaaaaaaaaaae".to_vec();
+        let (metainfo, pieces) = parse_info_dict(info.clone(), &sha1::encode(info.clone()))
+            .await
+            .unwrap();
+        assert_eq!(metainfo.info.name, "a");
+        assert_eq!(pieces.len(), 1);
+        assert!(parse_info_dict(info, &[0; 20]).await.is_err());
+
+        // valid bencode, but not a valid info dict
+        let info = b"d6:lengthi-1e4:name1:a12:piece lengthi20e6:pieces20:aaaaaaaaaaaaaaaaaaaae".to_vec();
+        assert!(parse_info_dict(info.clone(), &sha1::encode(info)).await.is_err());
+    }
+}
@@ -61,7 +61,23 @@ impl From<PeerMetainfoMessage> for Vec<u8> {
                 .collect(),
             )
             .encode(),
-            PeerMetainfoMessage::Data { .. } => todo!(),
+            PeerMetainfoMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                let dict = BencodeValue::Dict(
+                    [
+                        ("msg_type".into(), msg_type),
+                        ("piece".into(), BencodeValue::from(piece as i64)),
+                        ("total_size".into(), BencodeValue::from(total_size as i64)),
+                    ]
+                    .into_iter()
+                    .collect(),
+                );
+                // piece data follows the dict
+                [dict.encode(), data.0].concat()
+            }
             PeerMetainfoMessage::Reject => {
                 BencodeValue::Dict([("msg_type".into(), msg_type)].into_iter().collect()).encode()
             }
@@ -106,3 +122,20 @@ impl TryFrom<Vec<u8>> for PeerMetainfoMessage {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_data_message() {
+        let msg = PeerMetainfoMessage::Data {
+            piece: 1,
+            total_size: 20000,
+            data: Block(vec![1, 2, 3]),
+        };
+        let bytes: Vec<u8> = msg.clone().into();
+        assert!(bytes.ends_with(b"ee\x01\x02\x03"));
+        assert_eq!(PeerMetainfoMessage::try_from(bytes).unwrap(), msg);
+    }
+}
@@ -1,31 +1,465 @@
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
     fs,
+    net::Ipv4Addr,
     path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, ensure, Context, Error, Result};
+use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 
-use crate::{state::PeerInfo, types::ByteString};
+use crate::{
+    dht::{generate_node_id, is_valid_node_id, NodeHealth},
+    metainfo::Bencoded,
+    sha1,
+    state::PeerInfo,
+    types::ByteString,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PersistState {
     pub path: PathBuf,
     pub peer_id: ByteString,
-    pub dht_peers: BTreeSet<PeerInfo>,
+    pub dht_peers: Vec<DhtNodeRecord>,
+    #[serde(default)]
+    pub dht_node_id: Option<ByteString>,
+    #[serde(default)]
+    pub external_ip: Option<Ipv4Addr>,
+    /// Map of torrents started before <hex info hash> -> <resume record>
+    #[serde(default)]
+    pub torrents: BTreeMap<String, ResumeRecord>,
+    /// Backend the state is saved with
+    #[serde(skip)]
+    pub backend: PersistBackend,
 }
 
-impl PersistState {
-    pub fn load(path: &Path) -> Result<Self> {
+/// Persist state as saved before DHT nodes were scored, migrated on load
+#[derive(Deserialize)]
+struct LegacyPersistState {
+    path: PathBuf,
+    peer_id: ByteString,
+    dht_peers: BTreeSet<PeerInfo>,
+    #[serde(default)]
+    dht_node_id: Option<ByteString>,
+    #[serde(default)]
+    external_ip: Option<Ipv4Addr>,
+    #[serde(default)]
+    torrents: BTreeMap<String, ResumeRecord>,
+}
+
+impl From<LegacyPersistState> for PersistState {
+    fn from(legacy: LegacyPersistState) -> Self {
+        let now = SystemTime::now();
+        PersistState {
+            path: legacy.path,
+            peer_id: legacy.peer_id,
+            dht_peers: legacy
+                .dht_peers
+                .into_iter()
+                .map(|info| DhtNodeRecord {
+                    info,
+                    last_seen: now,
+                    score: 0,
+                })
+                .collect(),
+            dht_node_id: legacy.dht_node_id,
+            external_ip: legacy.external_ip,
+            torrents: legacy.torrents,
+            backend: PersistBackend::Json,
+        }
+    }
+}
+
+/// DHT node not seen for this long is considered dead
+const DHT_NODE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Max number of stored DHT nodes, the best ones are kept
+const MAX_DHT_NODES: usize = 1000;
+
+/// DHT node failing this many queries more than it responds to is considered dead
+const MIN_DHT_NODE_SCORE: i32 = -3;
+
+const MAX_DHT_NODE_SCORE: i32 = 10;
+
+/// Known DHT node along with its responsiveness
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DhtNodeRecord {
+    pub info: PeerInfo,
+    /// Last time node responded to a query
+    pub last_seen: SystemTime,
+    /// Number of responses minus number of failed queries, within [MIN_DHT_NODE_SCORE, MAX_DHT_NODE_SCORE]
+    pub score: i32,
+}
+
+impl DhtNodeRecord {
+    fn is_dead(&self, now: SystemTime) -> bool {
+        self.score <= MIN_DHT_NODE_SCORE || now.duration_since(self.last_seen).unwrap_or_default() > DHT_NODE_TTL
+    }
+}
+
+/// Data needed to continue the torrent download
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeRecord {
+    pub metainfo: Bencoded,
+    /// Saved pieces as of the last snapshot, trusted on resume if torrent files are unchanged
+    #[serde(default)]
+    pub progress: Option<ResumeProgress>,
+}
+
+/// Bitfield of saved pieces along with the stamps of torrent files it was taken with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeProgress {
+    pub bitfield: ByteString,
+    /// Stamp of every torrent file in metainfo order, `None` if file does not exist
+    pub files: Vec<Option<FileStamp>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl FileStamp {
+    pub fn read(path: &Path) -> Option<FileStamp> {
+        let meta = fs::metadata(path).ok()?;
+        Some(FileStamp {
+            size: meta.len(),
+            modified: meta.modified().ok()?,
+        })
+    }
+}
+
+/// Magic bytes of the state archive, followed by 4 byte version, 20 byte SHA-1 of the payload and the payload
+const ARCHIVE_MAGIC: &[u8] = b"biter-state";
+const ARCHIVE_VERSION: u32 = 1;
+
+/// Storage format of the persist state
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd, Hash)]
+pub enum PersistBackend {
+    Json,
+    /// Compact bincode file
+    #[default]
+    Binary,
+    /// Embedded database, every torrent record is stored under its own key
+    #[cfg(feature = "sled")]
+    Sled,
+}
+
+impl PersistBackend {
+    /// Path of the store, derived from the path of the JSON state
+    pub fn path(&self, base: &Path) -> PathBuf {
+        match self {
+            PersistBackend::Json => base.to_path_buf(),
+            PersistBackend::Binary => base.with_extension("bin"),
+            #[cfg(feature = "sled")]
+            PersistBackend::Sled => base.with_extension("db"),
+        }
+    }
+
+    fn store(&self) -> &'static dyn PersistStore {
+        match self {
+            PersistBackend::Json => &JsonStore,
+            PersistBackend::Binary => &BinaryStore,
+            #[cfg(feature = "sled")]
+            PersistBackend::Sled => &SLED_STORE,
+        }
+    }
+}
+
+impl FromStr for PersistBackend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(PersistBackend::Json),
+            "binary" => Ok(PersistBackend::Binary),
+            #[cfg(feature = "sled")]
+            "sled" => Ok(PersistBackend::Sled),
+            _ => Err(anyhow!("unknown persist backend: {}", s)),
+        }
+    }
+}
+
+/// Storage of the persist state
+pub trait PersistStore {
+    fn load(&self, path: &Path) -> Result<PersistState>;
+    fn save(&self, path: &Path, state: &PersistState) -> Result<()>;
+}
+
+pub struct JsonStore;
+
+impl PersistStore for JsonStore {
+    fn load(&self, path: &Path) -> Result<PersistState> {
         let json = fs::read_to_string(path)?;
-        serde_json::from_str(&json).context("deserialize error")
+        match serde_json::from_str(&json) {
+            Ok(state) => Ok(state),
+            Err(e) => match serde_json::from_str::<LegacyPersistState>(&json) {
+                Ok(legacy) => {
+                    info!("migrating legacy persist state {}", path.display());
+                    Ok(legacy.into())
+                }
+                Err(_) => Err(Error::from(e).context("deserialize error")),
+            },
+        }
+    }
+
+    fn save(&self, path: &Path, state: &PersistState) -> Result<()> {
+        fs::create_dir_all(path.parent().context("no parent")?)?;
+        let json = serde_json::to_string(state).context("serialize error")?;
+        fs::write(path, json)?;
+        Ok(())
+    }
+}
+
+/// Bincode file prefixed with magic bytes and 4 byte layout version. Unlike JSON, bincode has no field names, so
+/// every change of [PersistState] fields needs a new version and an explicit migration from the previous one
+pub struct BinaryStore;
+
+impl BinaryStore {
+    const MAGIC: &'static [u8] = b"biter-bin";
+    const VERSION: u32 = 1;
+
+    fn decode(bytes: &[u8]) -> Result<PersistState> {
+        let (version, payload) = match bytes.strip_prefix(Self::MAGIC) {
+            Some(rest) => {
+                ensure!(rest.len() >= 4, "truncated binary state header");
+                let (version, payload) = rest.split_at(4);
+                (u32::from_be_bytes(version.try_into()?), payload)
+            }
+            // written before the header was introduced, with the layout of version 1
+            None => (0, bytes),
+        };
+        match version {
+            0 | 1 => bincode::deserialize(payload).context("deserialize error"),
+            v => Err(anyhow!("unsupported binary state version: {}", v)),
+        }
+    }
+}
+
+impl PersistStore for BinaryStore {
+    fn load(&self, path: &Path) -> Result<PersistState> {
+        let bytes = fs::read(path)?;
+        Self::decode(&bytes)
+    }
+
+    fn save(&self, path: &Path, state: &PersistState) -> Result<()> {
+        fs::create_dir_all(path.parent().context("no parent")?)?;
+        let payload = bincode::serialize(state).context("serialize error")?;
+        fs::write(path, [Self::MAGIC, &Self::VERSION.to_be_bytes(), &payload].concat())?;
+        Ok(())
     }
+}
+
+/// Sled database store. Databases are opened once and reused, since sled keeps the database locked until its last
+/// handle is dropped
+#[cfg(feature = "sled")]
+pub struct SledStore {
+    dbs: std::sync::Mutex<BTreeMap<PathBuf, sled::Db>>,
+}
 
-    pub fn save(&self) -> Result<()> {
-        fs::create_dir_all(self.path.parent().context("no parent")?)?;
-        let json = serde_json::to_string(&self).context("serialize error")?;
-        fs::write(&self.path, json)?;
+#[cfg(feature = "sled")]
+static SLED_STORE: SledStore = SledStore {
+    dbs: std::sync::Mutex::new(BTreeMap::new()),
+};
+
+#[cfg(feature = "sled")]
+impl SledStore {
+    const TORRENT_PREFIX: &'static str = "torrent/";
+
+    /// Database at the path, opened on first use
+    fn db(&self, path: &Path) -> Result<sled::Db> {
+        let mut dbs = self.dbs.lock().map_err(|_| anyhow!("sled store lock is poisoned"))?;
+        if let Some(db) = dbs.get(path) {
+            return Ok(db.clone());
+        }
+        let db = sled::open(path)?;
+        dbs.insert(path.to_path_buf(), db.clone());
+        Ok(db)
+    }
+}
+
+#[cfg(feature = "sled")]
+impl PersistStore for SledStore {
+    fn load(&self, path: &Path) -> Result<PersistState> {
+        ensure!(path.exists(), "no database at {}", path.display());
+        let db = self.db(path)?;
+        let get = |key: &str| -> Result<sled::IVec> { db.get(key)?.context(format!("no `{}` key", key)) };
+        let torrents = db
+            .scan_prefix(Self::TORRENT_PREFIX)
+            .map(|kv| {
+                let (k, v) = kv?;
+                let info_hash = String::from_utf8(k[Self::TORRENT_PREFIX.len()..].to_vec())?;
+                Ok((info_hash, bincode::deserialize(&v)?))
+            })
+            .collect::<Result<_>>()?;
+        Ok(PersistState {
+            path: path.to_path_buf(),
+            peer_id: bincode::deserialize(&get("peer_id")?)?,
+            dht_peers: bincode::deserialize(&get("dht_peers")?)?,
+            dht_node_id: bincode::deserialize(&get("dht_node_id")?)?,
+            external_ip: bincode::deserialize(&get("external_ip")?)?,
+            torrents,
+            backend: PersistBackend::Sled,
+        })
+    }
+
+    fn save(&self, path: &Path, state: &PersistState) -> Result<()> {
+        let db = self.db(path)?;
+        db.insert("peer_id", bincode::serialize(&state.peer_id)?)?;
+        db.insert("dht_peers", bincode::serialize(&state.dht_peers)?)?;
+        db.insert("dht_node_id", bincode::serialize(&state.dht_node_id)?)?;
+        db.insert("external_ip", bincode::serialize(&state.external_ip)?)?;
+        for k in db.scan_prefix(Self::TORRENT_PREFIX).keys() {
+            let k = k?;
+            let info_hash = String::from_utf8_lossy(&k[Self::TORRENT_PREFIX.len()..]).to_string();
+            if !state.torrents.contains_key(&info_hash) {
+                db.remove(k)?;
+            }
+        }
+        for (info_hash, record) in &state.torrents {
+            db.insert(
+                format!("{}{}", Self::TORRENT_PREFIX, info_hash),
+                bincode::serialize(record)?,
+            )?;
+        }
+        db.flush()?;
+        Ok(())
+    }
+}
+
+impl PersistState {
+    /// Load state of the backend. If it doesn't exist yet, state is migrated from the JSON file at `base` path
+    pub fn load(base: &Path, backend: PersistBackend) -> Result<Self> {
+        let path = backend.path(base);
+        let mut state = if backend != PersistBackend::Json && !path.exists() && base.is_file() {
+            info!("migrating persist state from {} to {}", base.display(), path.display());
+            JsonStore.load(base)?
+        } else {
+            backend.store().load(&path)?
+        };
+        state.path = path;
+        state.backend = backend;
+        Ok(state)
+    }
+
+    /// Stored DHT nodes, best ones first: ones with the highest score, seen most recently
+    pub fn dht_nodes(&self) -> Vec<PeerInfo> {
+        let mut nodes = self.dht_peers.iter().collect::<Vec<_>>();
+        nodes.sort_by_key(|n| (std::cmp::Reverse(n.score), std::cmp::Reverse(n.last_seen)));
+        nodes.into_iter().map(|n| n.info.clone()).collect()
+    }
+
+    /// Remember nodes that responded to a query, adding new ones. Returns whether any node is new
+    pub fn seen_dht_nodes(&mut self, nodes: impl IntoIterator<Item = PeerInfo>) -> bool {
+        let now = SystemTime::now();
+        let mut new = false;
+        for info in nodes {
+            match self.dht_peers.iter_mut().find(|n| n.info == info) {
+                Some(n) => {
+                    n.last_seen = now;
+                    n.score = (n.score + 1).min(MAX_DHT_NODE_SCORE);
+                }
+                None => {
+                    self.dht_peers.push(DhtNodeRecord {
+                        info,
+                        last_seen: now,
+                        score: 0,
+                    });
+                    new = true;
+                }
+            }
+        }
+        new
+    }
+
+    /// Update scores of stored nodes with query results
+    pub fn update_dht_nodes(&mut self, health: &NodeHealth) {
+        self.seen_dht_nodes(
+            health
+                .responded
+                .iter()
+                .filter(|n| self.dht_peers.iter().any(|r| &r.info == *n))
+                .cloned()
+                .collect::<Vec<_>>(),
+        );
+        for n in self.dht_peers.iter_mut().filter(|n| health.failed.contains(&n.info)) {
+            n.score = (n.score - 1).max(MIN_DHT_NODE_SCORE);
+        }
+    }
+
+    /// Drop dead DHT nodes and the worst ones over [MAX_DHT_NODES]
+    pub fn prune_dht_nodes(&mut self) {
+        let now = SystemTime::now();
+        let before = self.dht_peers.len();
+        self.dht_peers.retain(|n| !n.is_dead(now));
+        self.dht_peers
+            .sort_by_key(|n| (std::cmp::Reverse(n.score), std::cmp::Reverse(n.last_seen)));
+        self.dht_peers.truncate(MAX_DHT_NODES);
+        if self.dht_peers.len() < before {
+            debug!("pruned {} dht nodes", before - self.dht_peers.len());
+        }
+    }
+
+    /// DHT node id, generated from external ip if it is known
+    pub fn dht_node_id(&mut self) -> ByteString {
+        self.dht_node_id
+            .get_or_insert_with(|| match self.external_ip {
+                Some(ip) => generate_node_id(ip),
+                None => thread_rng().gen::<[u8; 20]>().to_vec(),
+            })
+            .clone()
+    }
+
+    /// Update external ip, rotating DHT node id if it is no longer valid for the new ip
+    pub fn set_external_ip(&mut self, ip: Ipv4Addr) {
+        if self.external_ip == Some(ip) && self.dht_node_id.as_ref().is_some_and(|id| is_valid_node_id(id, ip)) {
+            return;
+        }
+        debug!("external ip changed: {:?} -> {}", self.external_ip, ip);
+        self.external_ip = Some(ip);
+        self.dht_node_id = Some(generate_node_id(ip));
+    }
+
+    /// Write state archive to be imported on another machine
+    pub fn export(&self, path: &Path) -> Result<()> {
+        let payload = bincode::serialize(self).context("serialize error")?;
+        let archive = [
+            ARCHIVE_MAGIC,
+            &ARCHIVE_VERSION.to_be_bytes(),
+            &sha1::encode(payload.clone()),
+            &payload,
+        ]
+        .concat();
+        fs::write(path, archive).context(format!("unable to write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Read state archive, verifying its checksum. Imported state is saved to `path` with the `backend`
+    pub fn import(archive_path: &Path, path: PathBuf, backend: PersistBackend) -> Result<Self> {
+        let archive = fs::read(archive_path).context(format!("unable to read {}", archive_path.display()))?;
+        let header_len = ARCHIVE_MAGIC.len() + 4 + 20;
+        ensure!(
+            archive.len() >= header_len && archive.starts_with(ARCHIVE_MAGIC),
+            "not a state archive"
+        );
+        let (version, rest) = archive[ARCHIVE_MAGIC.len()..].split_at(4);
+        let version = u32::from_be_bytes(version.try_into()?);
+        ensure!(version == ARCHIVE_VERSION, "unsupported archive version: {}", version);
+        let (checksum, payload) = rest.split_at(20);
+        ensure!(sha1::encode(payload.to_vec()) == checksum, "archive checksum mismatch");
+        let mut state: PersistState = bincode::deserialize(payload).context("deserialize error")?;
+        state.path = path;
+        state.backend = backend;
+        Ok(state)
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        self.prune_dht_nodes();
+        self.backend.store().save(&self.path, self)?;
         debug!("persist state written: {:?}", self);
         Ok(())
     }
@@ -38,3 +472,179 @@ impl Drop for PersistState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::hex::hex;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("biter-test-{}", hex(&thread_rng().gen::<[u8; 8]>())))
+            .join("biter")
+    }
+
+    fn state(path: PathBuf, backend: PersistBackend) -> PersistState {
+        PersistState {
+            path,
+            peer_id: b"-ER0000-000000000000".to_vec(),
+            dht_peers: vec![DhtNodeRecord {
+                info: PeerInfo {
+                    ip: [1, 2, 3, 4].into(),
+                    port: 6881,
+                },
+                last_seen: SystemTime::now(),
+                score: 0,
+            }],
+            dht_node_id: None,
+            external_ip: Some([5, 6, 7, 8].into()),
+            torrents: BTreeMap::from([(
+                "00".into(),
+                ResumeRecord {
+                    metainfo: Bencoded(b"de".to_vec()),
+                    progress: Some(ResumeProgress {
+                        bitfield: vec![0xa0],
+                        files: vec![
+                            Some(FileStamp {
+                                size: 3,
+                                modified: SystemTime::UNIX_EPOCH,
+                            }),
+                            None,
+                        ],
+                    }),
+                },
+            )]),
+            backend,
+        }
+    }
+
+    #[test]
+    fn should_round_trip_binary_state() {
+        let base = temp_path();
+        let path = PersistBackend::Binary.path(&base);
+        state(path.clone(), PersistBackend::Binary).save().unwrap();
+        let loaded = PersistState::load(&base, PersistBackend::Binary).unwrap();
+        assert_eq!(loaded.path, path);
+        assert_eq!(loaded.peer_id, b"-ER0000-000000000000");
+        assert_eq!(loaded.dht_peers.len(), 1);
+        assert_eq!(loaded.external_ip, Some([5, 6, 7, 8].into()));
+        assert_eq!(loaded.torrents["00"].metainfo.0, b"de");
+        assert_eq!(loaded.torrents["00"].progress.as_ref().unwrap().files.len(), 2);
+        let _ = fs::remove_dir_all(base.parent().unwrap());
+    }
+
+    #[test]
+    fn should_decode_binary_state_versions() {
+        let base = temp_path();
+        let payload = bincode::serialize(&state(base.clone(), PersistBackend::Binary)).unwrap();
+        let headerless = BinaryStore::decode(&payload).unwrap();
+        assert_eq!(headerless.torrents.len(), 1);
+        let v1 = [BinaryStore::MAGIC, &1u32.to_be_bytes(), &payload].concat();
+        assert_eq!(BinaryStore::decode(&v1).unwrap().torrents.len(), 1);
+        let v2 = [BinaryStore::MAGIC, &2u32.to_be_bytes(), &payload].concat();
+        assert!(BinaryStore::decode(&v2).is_err());
+        assert!(BinaryStore::decode(BinaryStore::MAGIC).is_err());
+        drop(headerless);
+        let _ = fs::remove_dir_all(base.parent().unwrap());
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn should_round_trip_sled_state() {
+        let base = temp_path();
+        let path = PersistBackend::Sled.path(&base);
+        let mut saved = state(path.clone(), PersistBackend::Sled);
+        saved.save().unwrap();
+        saved.save().unwrap();
+        drop(saved);
+        let loaded = PersistState::load(&base, PersistBackend::Sled).unwrap();
+        assert_eq!(loaded.dht_peers.len(), 1);
+        assert_eq!(loaded.torrents["00"].metainfo.0, b"de");
+        drop(loaded);
+        let _ = fs::remove_dir_all(base.parent().unwrap());
+    }
+
+    #[test]
+    fn should_export_and_import_state() {
+        let base = temp_path();
+        let archive = base.with_extension("archive");
+        fs::create_dir_all(base.parent().unwrap()).unwrap();
+        state(base.clone(), PersistBackend::Json).export(&archive).unwrap();
+        let imported = PersistState::import(&archive, base.clone(), PersistBackend::Json).unwrap();
+        assert_eq!(imported.torrents["00"].metainfo.0, b"de");
+        assert_eq!(imported.dht_peers.len(), 1);
+
+        let mut bytes = fs::read(&archive).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        fs::write(&archive, bytes).unwrap();
+        assert!(PersistState::import(&archive, base.clone(), PersistBackend::Json).is_err());
+        drop(imported);
+        let _ = fs::remove_dir_all(base.parent().unwrap());
+    }
+
+    #[test]
+    fn should_prune_dht_nodes() {
+        let base = temp_path();
+        let mut state = state(base.clone(), PersistBackend::Json);
+        let node = |port: u16| PeerInfo {
+            ip: [1, 2, 3, 4].into(),
+            port,
+        };
+        assert!(state.seen_dht_nodes([node(1), node(2), node(3)]));
+        state.update_dht_nodes(&NodeHealth {
+            responded: BTreeSet::from([node(2), node(4)]),
+            failed: BTreeSet::from([node(3)]),
+        });
+        state
+            .dht_peers
+            .iter_mut()
+            .find(|n| n.info == node(6881))
+            .unwrap()
+            .last_seen -= DHT_NODE_TTL * 2;
+        for _ in 0..3 {
+            state.update_dht_nodes(&NodeHealth {
+                responded: BTreeSet::new(),
+                failed: BTreeSet::from([node(1)]),
+            });
+        }
+        assert_eq!(state.dht_nodes(), vec![node(2), node(6881), node(3), node(1)]);
+        state.prune_dht_nodes();
+        assert_eq!(state.dht_nodes(), vec![node(2), node(3)]);
+        drop(state);
+        let _ = fs::remove_dir_all(base.parent().unwrap());
+    }
+
+    #[test]
+    fn should_migrate_json_state() {
+        let base = temp_path();
+        state(base.clone(), PersistBackend::Json).save().unwrap();
+        let loaded = PersistState::load(&base, PersistBackend::Binary).unwrap();
+        assert_eq!(loaded.backend, PersistBackend::Binary);
+        assert_eq!(loaded.path, PersistBackend::Binary.path(&base));
+        assert_eq!(loaded.torrents.len(), 1);
+        drop(loaded);
+        assert!(PersistBackend::Binary.path(&base).is_file());
+        let _ = fs::remove_dir_all(base.parent().unwrap());
+    }
+
+    #[test]
+    fn should_load_legacy_json_state() {
+        let base = temp_path();
+        fs::create_dir_all(base.parent().unwrap()).unwrap();
+        let json = r#"{"path":"x","peer_id":[1,2],"dht_peers":[{"ip":"1.2.3.4","port":6881}]}"#;
+        fs::write(&base, json).unwrap();
+        let loaded = PersistState::load(&base, PersistBackend::Json).unwrap();
+        assert_eq!(loaded.peer_id, vec![1, 2]);
+        assert_eq!(
+            loaded.dht_nodes(),
+            vec![PeerInfo {
+                ip: [1, 2, 3, 4].into(),
+                port: 6881
+            }]
+        );
+        drop(loaded);
+        let _ = fs::remove_dir_all(base.parent().unwrap());
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use tokio::process::Command;
+
+/// Device names Windows reserves in every directory, regardless of extension
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Directory of the persist state: `$XDG_STATE_HOME/biter` (`~/.local/state/biter` by default) on unix,
+/// `%LOCALAPPDATA%\biter` on Windows. State of earlier versions, always kept in `~/.local/state/biter`, is used
+/// when there is no state in `$XDG_STATE_HOME` yet
+pub fn state_dir() -> Result<PathBuf> {
+    if cfg!(windows) {
+        return Ok(dirs::data_local_dir().context("no local data directory")?.join("biter"));
+    }
+    let legacy = dirs::home_dir()
+        .context("no home directory")?
+        .join(".local")
+        .join("state")
+        .join("biter");
+    let path = match env::var_os("XDG_STATE_HOME").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir.join("biter"),
+        _ => return Ok(legacy),
+    };
+    if !has_state(&path) && has_state(&legacy) {
+        return Ok(legacy);
+    }
+    Ok(path)
+}
+
+/// Whether state of any persist backend exists at the base path, i.e. a file named after it with any extension
+fn has_state(base: &Path) -> bool {
+    let (Some(dir), Some(name)) = (base.parent(), base.file_name()) else {
+        return false;
+    };
+    fs::read_dir(dir).is_ok_and(|entries| {
+        entries
+            .flatten()
+            .any(|e| Path::new(&e.file_name()).file_stem() == Some(name))
+    })
+}
+
+/// Relative path built from untrusted path components of the metainfo, each made safe with [safe_component]
+pub fn safe_path(path: &Path) -> PathBuf {
+    path.components()
+        .map(|c| safe_component(&c.as_os_str().to_string_lossy()))
+        .collect()
+}
+
+/// File name that can be written on this platform and does not escape its directory
+pub fn safe_component(name: &str) -> String {
+    sanitize_component(name, cfg!(windows))
+}
+
+/// Path separators are replaced, `.` and `..` are escaped. With `windows`, characters and device names Windows does
+/// not allow in file names are escaped too, as well as trailing dots and spaces
+fn sanitize_component(name: &str, windows: bool) -> String {
+    let mut name = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' => '_',
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' if windows => '_',
+            c if windows && c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    if windows {
+        let trimmed = name.trim_end_matches(['.', ' ']).len();
+        name.replace_range(trimmed.., &"_".repeat(name.len() - trimmed));
+        let stem = name.split('.').next().unwrap_or_default();
+        if WINDOWS_RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+            name.insert(0, '_');
+        }
+    }
+    match name.as_str() {
+        "" | "." | ".." => name.replace('.', "_") + "_",
+        _ => name,
+    }
+}
+
+/// Command running the shell command line: `sh -c` on unix, `cmd /C` on Windows
+pub fn shell_command(command: &str) -> Command {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    cmd.arg(command);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_sanitize_component() {
+        assert_eq!(sanitize_component("a/b\\c", false), "a_b_c");
+        assert_eq!(sanitize_component("..", false), "___");
+        assert_eq!(sanitize_component("", false), "_");
+        assert_eq!(sanitize_component("a:b?.txt", false), "a:b?.txt");
+        assert_eq!(sanitize_component("a:b?.txt", true), "a_b_.txt");
+        assert_eq!(sanitize_component("con.txt", true), "_con.txt");
+        assert_eq!(sanitize_component("console", true), "console");
+        assert_eq!(sanitize_component("name. ", true), "name__");
+        assert_eq!(
+            safe_path(Path::new("../a/./b")),
+            ["___", "a", "b"].iter().collect::<PathBuf>()
+        );
+    }
+
+    #[test]
+    fn should_detect_state() {
+        let dir = env::temp_dir().join(format!("biter-test-platform-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert!(!has_state(&dir.join("biter")));
+        fs::write(dir.join("biter.bin"), []).unwrap();
+        assert!(has_state(&dir.join("biter")));
+        assert!(!has_state(&dir.join("other")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
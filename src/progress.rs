@@ -0,0 +1,353 @@
+use std::{collections::BTreeSet, net::SocketAddr, path::PathBuf, time::Instant};
+
+use serde::Serialize;
+
+use crate::{
+    feature::Feature,
+    state::{PeerStatus, State, TorrentStatus},
+};
+
+/// Torrent state as seen by the user
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TorrentState {
+    FetchingMetadata,
+    Downloading,
+    Seeding,
+    Paused,
+}
+
+impl From<&TorrentStatus> for TorrentState {
+    fn from(value: &TorrentStatus) -> Self {
+        match value {
+            TorrentStatus::Metainfo => TorrentState::FetchingMetadata,
+            TorrentStatus::Downloading => TorrentState::Downloading,
+            TorrentStatus::Downloaded | TorrentStatus::Saved => TorrentState::Seeding,
+            TorrentStatus::Paused => TorrentState::Paused,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FileProgress {
+    pub path: PathBuf,
+    pub length: u64,
+    /// Bytes of the file saved to disk
+    pub completed: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Progress {
+    pub state: TorrentState,
+    pub total_length: Option<u64>,
+    /// Bytes of saved pieces
+    pub completed: u64,
+    /// Bitfield of saved pieces, high bit of the first byte is piece 0
+    pub pieces: Vec<u8>,
+    pub pieces_total: usize,
+    pub pieces_completed: usize,
+    /// Download rate in bytes per second since the previous sample
+    pub download_rate: f64,
+    /// Upload rate in bytes per second since the previous sample
+    pub upload_rate: f64,
+    pub peers: usize,
+    pub peers_connected: usize,
+    /// Metainfo has no trackers, peers are discovered with DHT only
+    pub trackerless: bool,
+}
+
+/// Downloaded and uploaded byte counters at the time of the sample, used to compute rates
+#[derive(Clone, Debug)]
+pub struct RateSample {
+    pub time: Instant,
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+impl RateSample {
+    pub fn new(state: &State) -> RateSample {
+        RateSample {
+            time: Instant::now(),
+            downloaded: state.stats.downloaded,
+            uploaded: state.stats.uploaded,
+        }
+    }
+}
+
+/// Per-file progress. Empty if metainfo is not known yet
+pub fn file_progress(state: &State) -> Vec<FileProgress> {
+    let (metainfo, pieces) = match (&state.metainfo, &state.pieces) {
+        (Ok(m), Some(ps)) => (m, ps),
+        _ => return vec![],
+    };
+    let mut files = metainfo
+        .info
+        .file_info
+        .files()
+        .into_iter()
+        .map(|f| FileProgress {
+            path: f.path.clone(),
+            length: f.length,
+            completed: 0,
+        })
+        .collect::<Vec<_>>();
+    pieces
+        .values()
+        .filter(|p| p.status == TorrentStatus::Saved)
+        .flat_map(|p| p.file_locations.iter())
+        .for_each(|fl| {
+            if let Some(f) = files.get_mut(fl.file_index) {
+                f.completed += fl.length as u64;
+            }
+        });
+    files
+}
+
+/// Torrent progress, with rates computed since the `prev` sample
+pub fn progress(state: &State, prev: &RateSample) -> Progress {
+    let now = RateSample::new(state);
+    let elapsed = now.time.duration_since(prev.time).as_secs_f64();
+    let rate = |now: u64, prev: u64| {
+        if elapsed > 0. {
+            now.saturating_sub(prev) as f64 / elapsed
+        } else {
+            0.
+        }
+    };
+    let pieces = state.pieces.as_ref();
+    let saved = |i: &u32| {
+        pieces
+            .and_then(|ps| ps.get(i))
+            .is_some_and(|p| p.status == TorrentStatus::Saved)
+    };
+    let pieces_total = pieces.map_or(0, |ps| ps.len());
+    let bitfield = (0..pieces_total.div_ceil(8))
+        .map(|byte| {
+            (0..8)
+                .filter(|bit| saved(&((byte * 8 + bit) as u32)))
+                .fold(0u8, |acc, bit| acc | (0x80 >> bit))
+        })
+        .collect();
+    let saved_pieces = || {
+        pieces
+            .into_iter()
+            .flat_map(|ps| ps.values())
+            .filter(|p| p.status == TorrentStatus::Saved)
+    };
+    Progress {
+        state: TorrentState::from(&state.status),
+        total_length: state.metainfo.as_ref().ok().map(|m| m.info.file_info.total_length()),
+        completed: saved_pieces().map(|p| p.length as u64).sum(),
+        pieces: bitfield,
+        pieces_total,
+        pieces_completed: saved_pieces().count(),
+        download_rate: rate(now.downloaded, prev.downloaded),
+        upload_rate: rate(now.uploaded, prev.uploaded),
+        peers: state.peers.len(),
+        peers_connected: state
+            .peers
+            .values()
+            .filter(|p| p.status == PeerStatus::Connected)
+            .count(),
+        trackerless: state.metainfo.as_ref().is_ok_and(|m| !m.has_trackers()),
+    }
+}
+
+/// Piece state as shown in the piece map
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PieceState {
+    Missing,
+    /// Some blocks are received
+    Downloading,
+    /// Blocks are requested from peers or the piece is requested from a web seed
+    InFlight,
+    /// Every block is received, but piece is not saved yet
+    Downloaded,
+    Saved,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PieceProgress {
+    pub state: PieceState,
+    /// Number of connected peers having the piece
+    pub peers: usize,
+}
+
+/// State and availability of every piece. Empty if metainfo is not known yet
+pub fn piece_map(state: &State) -> Vec<PieceProgress> {
+    let pieces = match &state.pieces {
+        Some(ps) => ps,
+        _ => return vec![],
+    };
+    let connected = state
+        .peers
+        .values()
+        .filter(|p| p.status == PeerStatus::Connected)
+        .collect::<Vec<_>>();
+    let requested = connected
+        .iter()
+        .flat_map(|p| p.requests_out.keys().map(|(piece, _)| *piece))
+        .collect::<BTreeSet<_>>();
+    pieces
+        .values()
+        .map(|p| PieceProgress {
+            state: match p.status {
+                TorrentStatus::Saved => PieceState::Saved,
+                TorrentStatus::Downloaded => PieceState::Downloaded,
+                _ if p.webseed || requested.contains(&p.index) => PieceState::InFlight,
+                _ if !p.blocks.is_empty() => PieceState::Downloading,
+                _ => PieceState::Missing,
+            },
+            peers: state.availability.get(p.index) as usize,
+        })
+        .collect()
+}
+
+/// Render piece map as rows of `width` characters, one per piece:
+///  - `#` saved
+///  - `+` downloaded, not saved yet
+///  - `>` in flight
+///  - `~` partially downloaded, not requested
+///  - `.`, `1`-`9`, `*` missing piece available from none, 1-9 or more connected peers
+pub fn render_piece_map(pieces: &[PieceProgress], width: usize) -> String {
+    let cell = |p: &PieceProgress| match p.state {
+        PieceState::Saved => '#',
+        PieceState::Downloaded => '+',
+        PieceState::InFlight => '>',
+        PieceState::Downloading => '~',
+        PieceState::Missing => match p.peers {
+            0 => '.',
+            n @ 1..=9 => char::from_digit(n as u32, 10).unwrap(),
+            _ => '*',
+        },
+    };
+    pieces
+        .chunks(width.max(1))
+        .map(|row| row.iter().map(cell).collect::<String>())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PeerProgress {
+    pub addr: SocketAddr,
+    pub client: Option<String>,
+    /// Connection flags, see [peer_list]
+    pub flags: String,
+    /// Smoothed download rate from the peer in bytes per second
+    pub download_rate: u64,
+    /// Bytes of verified blocks received from the peer
+    pub downloaded: u64,
+    /// Bytes of blocks sent to the peer
+    pub uploaded: u64,
+    /// Fraction of pieces the peer has, unknown until metainfo is known and bitfield is received
+    pub progress: Option<f64>,
+}
+
+/// Connected peers. Flags are:
+///  - `c` peer chokes us
+///  - `i` peer is interested in our pieces
+///  - `C` we choke peer
+///  - `e` peer supports extension protocol
+///  - `u` peer is upload only
+pub fn peer_list(state: &State) -> Vec<PeerProgress> {
+    let piece_count = state.pieces.as_ref().map(|ps| ps.len());
+    state
+        .peers
+        .values()
+        .filter(|p| p.status == PeerStatus::Connected)
+        .map(|p| PeerProgress {
+            addr: p.info.to_addr(),
+            client: p.client.clone(),
+            flags: [
+                (p.choked, 'c'),
+                (p.interested, 'i'),
+                (p.am_choked, 'C'),
+                (p.features.contains(&Feature::Extension), 'e'),
+                (p.upload_only, 'u'),
+            ]
+            .into_iter()
+            .filter_map(|(set, f)| set.then_some(f))
+            .collect(),
+            download_rate: p.rate,
+            downloaded: p.downloaded,
+            uploaded: p.uploaded,
+            progress: match (piece_count, &p.bitfield) {
+                (Some(count), Some(_)) if count > 0 => {
+                    Some((0..count as u32).filter(|i| p.has_piece(*i)).count() as f64 / count as f64)
+                }
+                _ => None,
+            },
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TrackerProgress {
+    pub url: String,
+    /// Seconds since the last announce, if any
+    pub last_announce: Option<u64>,
+    /// Announce interval in seconds
+    pub interval: Option<i64>,
+    pub seeders: Option<i64>,
+    pub leechers: Option<i64>,
+    /// Failure reason or request error of the last announce
+    pub last_error: Option<String>,
+}
+
+/// Announce status of the trackers announced to
+pub fn tracker_list(state: &State) -> Vec<TrackerProgress> {
+    state
+        .tracker_states
+        .iter()
+        .map(|(url, t)| TrackerProgress {
+            url: url.clone(),
+            last_announce: t.last_announce.map(|a| a.elapsed().as_secs()),
+            interval: t.interval,
+            seeders: t.seeders,
+            leechers: t.leechers,
+            last_error: t.last_error.clone(),
+        })
+        .collect()
+}
+
+/// Render peer list as a table, one peer per line
+pub fn render_peer_list(peers: &[PeerProgress]) -> String {
+    peers
+        .iter()
+        .map(|p| {
+            format!(
+                "{:<47} {:<20} {:<5} {:>9.1} KiB/s {:>6} KiB down {:>6} KiB up {:>5}",
+                p.addr.to_string(),
+                p.client.as_deref().unwrap_or("?"),
+                p.flags,
+                p.download_rate as f64 / 1024.,
+                p.downloaded >> 10,
+                p.uploaded >> 10,
+                p.progress.map_or("?".into(), |p| format!("{:.0}%", p * 100.))
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_render_piece_map() {
+        let piece = |state: PieceState, peers: usize| PieceProgress { state, peers };
+        let pieces = vec![
+            piece(PieceState::Saved, 3),
+            piece(PieceState::Downloaded, 3),
+            piece(PieceState::InFlight, 1),
+            piece(PieceState::Downloading, 0),
+            piece(PieceState::Missing, 0),
+            piece(PieceState::Missing, 4),
+            piece(PieceState::Missing, 12),
+        ];
+        assert_eq!(render_piece_map(&pieces, 4), "#+>~\n.4*");
+    }
+}
@@ -0,0 +1,138 @@
+use core::fmt;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+
+use crate::{state::PeerInfo, types::ByteString};
+
+/// What torrents of the session know about the peer
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerRecord {
+    /// Info hashes of the torrents peer is connected in
+    pub torrents: BTreeSet<ByteString>,
+    /// Number of pieces peer contributed to that failed hash check, in any torrent
+    pub hash_fails: usize,
+    pub banned: bool,
+    /// Best download rate seen from the peer, bytes per second
+    pub rate: u64,
+}
+
+/// Session-level registry of peers keyed by address, so that a peer appearing in several swarms is treated as one:
+/// bans and quality seen in one torrent apply to the others. Connection is bound to a single info hash by the
+/// handshake, so each torrent still connects to the peer on its own
+#[derive(Clone, Default)]
+pub struct PeerRegistry(Arc<Mutex<BTreeMap<PeerInfo, PeerRecord>>>);
+
+impl PeerRegistry {
+    pub fn get(&self, peer: &PeerInfo) -> Option<PeerRecord> {
+        self.lock().get(peer).cloned()
+    }
+
+    pub fn is_banned(&self, peer: &PeerInfo) -> bool {
+        self.lock().get(peer).is_some_and(|r| r.banned)
+    }
+
+    /// Record that the peer is connected in the torrent
+    pub fn connected(&self, peer: &PeerInfo, info_hash: &[u8]) {
+        let mut peers = self.lock();
+        let record = peers.entry(peer.clone()).or_default();
+        record.torrents.insert(info_hash.to_vec());
+        if record.torrents.len() > 1 {
+            debug!("peer {:?} is connected in {} torrents", peer, record.torrents.len());
+        }
+    }
+
+    /// Record that the peer is disconnected from the torrent, keeping its download rate
+    pub fn disconnected(&self, peer: &PeerInfo, info_hash: &[u8], rate: u64) {
+        if let Some(record) = self.lock().get_mut(peer) {
+            record.torrents.remove(info_hash);
+            record.rate = record.rate.max(rate);
+        }
+    }
+
+    /// Count piece hash fail the peer contributed to, banning it after `max_hash_fails` in all torrents.
+    /// Returns whether peer is banned
+    pub fn hash_fail(&self, peer: &PeerInfo, max_hash_fails: usize) -> bool {
+        let mut peers = self.lock();
+        let record = peers.entry(peer.clone()).or_default();
+        record.hash_fails += 1;
+        if record.hash_fails >= max_hash_fails && !record.banned {
+            warn!("banning peer {:?} after {} hash fails", peer, record.hash_fails);
+            record.banned = true;
+        }
+        record.banned
+    }
+
+    /// Order peers to connect to: peers connected in other torrents are known to be reachable and go first, then
+    /// faster ones. Peers unknown to the session keep their order
+    pub fn sort(&self, peers: &mut [PeerInfo]) {
+        let records = self.lock();
+        peers.sort_by_key(|p| {
+            Reverse(
+                records
+                    .get(p)
+                    .map(|r| (!r.torrents.is_empty(), r.rate))
+                    .unwrap_or_default(),
+            )
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<PeerInfo, PeerRecord>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for PeerRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<peer registry {}>", self.lock().len())
+    }
+}
+
+impl PartialEq for PeerRegistry {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> PeerInfo {
+        PeerInfo {
+            ip: [1, 2, 3, 4].into(),
+            port,
+        }
+    }
+
+    #[test]
+    fn should_share_peer_across_torrents() {
+        let registry = PeerRegistry::default();
+        let (a, b) = (vec![1; 20], vec![2; 20]);
+        registry.connected(&peer(1), &a);
+        registry.connected(&peer(1), &b);
+        registry.disconnected(&peer(1), &a, 100);
+        registry.disconnected(&peer(2), &a, 100);
+        assert_eq!(
+            registry.get(&peer(1)),
+            Some(PeerRecord {
+                torrents: [b].into(),
+                hash_fails: 0,
+                banned: false,
+                rate: 100,
+            })
+        );
+        assert_eq!(registry.get(&peer(2)), None);
+
+        let mut peers = vec![peer(3), peer(2), peer(1)];
+        registry.sort(&mut peers);
+        assert_eq!(peers, vec![peer(1), peer(3), peer(2)]);
+
+        assert!(!registry.hash_fail(&peer(2), 2));
+        assert!(registry.hash_fail(&peer(2), 2));
+        assert!(registry.is_banned(&peer(2)));
+        assert!(!registry.is_banned(&peer(1)));
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{sync::Mutex, time::sleep};
+
+use crate::state::{Peer, PeerInfo, PeerStatus, Piece, State, TorrentStatus};
+
+/// Piece is moved to another peer only if that peer is expected to finish it this many times faster
+const SLOW_FACTOR: u32 = 2;
+
+/// Piece moved from a slow peer to a faster one
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reassignment {
+    pub piece: u32,
+    pub from: PeerInfo,
+    pub to: PeerInfo,
+}
+
+/// Periodically rebalance pieces in flight between connected peers based on their download rates and latencies,
+/// see [plan_reassignments]
+pub async fn scheduler_loop(state: Arc<Mutex<State>>) {
+    loop {
+        let wait = {
+            let mut state = state.lock().await;
+            if state.status == TorrentStatus::Downloading {
+                reassign(&mut state);
+            }
+            state.config.schedule_wait
+        };
+        sleep(wait).await;
+    }
+}
+
+fn reassign(state: &mut State) {
+    let queue_time = state.config.request_queue_time;
+    let State { pieces, peers, .. } = state;
+    let pieces = match pieces.as_mut() {
+        Some(pieces) => pieces,
+        None => return,
+    };
+    let connected = peers.values().filter(|p| p.status == PeerStatus::Connected);
+    debug!(
+        "aggregate download rate: {:.1} KiB/s, peer rtts: {:?}",
+        connected.clone().map(|p| p.rate).sum::<u64>() as f64 / 1024.,
+        connected.filter_map(|p| p.rtt).collect::<Vec<_>>()
+    );
+    let plan = plan_reassignments(pieces, peers, queue_time);
+    for r in &plan {
+        debug!("moving piece {} from {:?} to {:?}", r.piece, r.from, r.to);
+        if let Some(piece) = pieces.get_mut(&r.piece) {
+            piece.assigned = Some(r.to.clone());
+        }
+        if let Some(p) = peers.get_mut(&r.from) {
+            p.revoked.insert(r.piece);
+            p.notify.notify();
+        }
+        if let Some(p) = peers.get(&r.to) {
+            p.notify.notify();
+        }
+    }
+}
+
+/// Plan moving pieces from peers that are slow to finish them to peers with spare request capacity, expected to
+/// finish them at least [SLOW_FACTOR] times faster. Pieces in flight for less than `queue_time` are left alone.
+/// Every fast peer takes at most one piece per plan, the slowest pieces are moved first
+pub fn plan_reassignments(
+    pieces: &BTreeMap<u32, Piece>,
+    peers: &BTreeMap<PeerInfo, Peer>,
+    queue_time: Duration,
+) -> Vec<Reassignment> {
+    let mut slow = pieces
+        .values()
+        .filter(|pc| pc.status == TorrentStatus::Downloading && !pc.webseed && !pc.is_complete())
+        .filter_map(|pc| {
+            let holder = peers.get(pc.assigned.as_ref()?)?;
+            let oldest = holder
+                .requests_out
+                .iter()
+                .filter(|((i, _), _)| *i == pc.index)
+                .map(|(_, t)| t.elapsed())
+                .max()?;
+            if oldest < queue_time {
+                return None;
+            }
+            let unrequested = (0..pc.total_blocks())
+                .filter(|i| !pc.blocks.contains_key(i) && !holder.requests_out.contains_key(&(pc.index, *i)))
+                .count();
+            // peer is stalled if its oldest request takes much longer than its queue is expected to drain
+            let eta = holder
+                .eta(unrequested)
+                .filter(|_| oldest < holder.eta(0).unwrap_or_default() * SLOW_FACTOR + queue_time);
+            Some((pc, eta))
+        })
+        .collect::<Vec<_>>();
+    // unknown eta means peer is stalled or its rate is not known yet
+    slow.sort_by_key(|(_, eta)| eta.map(std::cmp::Reverse));
+
+    let mut taken = BTreeSet::new();
+    let mut plan = vec![];
+    for (pc, eta) in slow {
+        let remaining = pc.total_blocks() as usize - pc.blocks.len();
+        let fastest = peers
+            .values()
+            .filter(|p| {
+                p.status == PeerStatus::Connected
+                    && !p.choked
+                    && Some(&p.info) != pc.assigned.as_ref()
+                    && !taken.contains(&p.info)
+                    && p.has_piece(pc.index)
+                    && p.free_requests(queue_time) > 0
+            })
+            .filter_map(|p| Some((p, p.eta(remaining)?)))
+            .filter(|(_, to_eta)| eta.is_none_or(|eta| *to_eta * SLOW_FACTOR < eta))
+            .min_by_key(|(_, to_eta)| *to_eta);
+        if let Some((to, _)) = fastest {
+            taken.insert(to.info.clone());
+            plan.push(Reassignment {
+                piece: pc.index,
+                from: pc.assigned.clone().unwrap(),
+                to: to.info.clone(),
+            });
+        }
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Instant};
+
+    use super::*;
+    use crate::{
+        metainfo::{FileInfo, Info, PathInfo},
+        state::{init_pieces, PieceHash, BLOCK_SIZE},
+        stats::PeerSource,
+    };
+
+    fn peer(port: u16, rate: u64) -> Peer {
+        let mut p = Peer::new(
+            PeerInfo {
+                ip: [1, 2, 3, 4].into(),
+                port,
+            },
+            PeerSource::Dht,
+        );
+        p.status = PeerStatus::Connected;
+        p.choked = false;
+        p.rate = rate;
+        p
+    }
+
+    #[test]
+    fn should_move_piece_from_slow_peer() {
+        let info = Info {
+            piece_length: 16 * BLOCK_SIZE as u64,
+            pieces: vec![PieceHash(vec![0; 20]); 2],
+            name: "test".into(),
+            file_info: FileInfo::Multi(vec![PathInfo {
+                length: 32 * BLOCK_SIZE as u64,
+                path: PathBuf::from("0"),
+                md5_sum: None,
+            }]),
+            private: None,
+        };
+        let mut pieces = init_pieces(&info).unwrap();
+        let queue_time = Duration::from_secs(1);
+        let mut slow = peer(1, 1 << 10);
+        let fast = peer(2, 1 << 20);
+        let mut fresh = peer(3, 1 << 10);
+        let requested = Instant::now() - queue_time * 2;
+        for i in 0..4 {
+            slow.requests_out.insert((0, i), requested);
+        }
+        pieces.get_mut(&0).unwrap().assigned = Some(slow.info.clone());
+        pieces.get_mut(&1).unwrap().assigned = Some(fresh.info.clone());
+        fresh.requests_out.insert((1, 0), Instant::now());
+        let mut peers = [slow.clone(), fast.clone(), fresh]
+            .into_iter()
+            .map(|p| (p.info.clone(), p))
+            .collect::<BTreeMap<_, _>>();
+
+        assert_eq!(
+            plan_reassignments(&pieces, &peers, queue_time),
+            vec![Reassignment {
+                piece: 0,
+                from: slow.info.clone(),
+                to: fast.info.clone(),
+            }]
+        );
+
+        peers.get_mut(&fast.info).unwrap().rate = 1 << 10;
+        assert!(plan_reassignments(&pieces, &peers, queue_time).is_empty());
+    }
+}
@@ -0,0 +1,173 @@
+use core::fmt;
+use std::{str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, Context, Error};
+use rand::{seq::IteratorRandom, thread_rng};
+
+use crate::state::{Availability, Piece};
+
+/// Strategy of choosing the next piece to request
+pub trait PieceSelector: Send + Sync {
+    /// Choose one of the candidate pieces: not yet assigned pieces the peer has, ordered by index
+    fn select(&self, candidates: &[&Piece], availability: &Availability) -> Option<u32>;
+}
+
+/// Built-in piece selection strategies
+#[derive(Clone, Debug, PartialEq, PartialOrd, Hash)]
+pub enum PieceSelection {
+    Random,
+    /// Pieces available from the least number of connected peers first
+    RarestFirst,
+    /// Pieces in order
+    Sequential,
+    /// Random pieces out of the first `window` candidates, so that the beginning of the torrent is available early
+    Streaming {
+        window: usize,
+    },
+}
+
+impl PieceSelection {
+    pub fn selector(&self) -> Selector {
+        Selector(match self {
+            PieceSelection::Random => Arc::new(RandomSelector),
+            PieceSelection::RarestFirst => Arc::new(RarestFirstSelector),
+            PieceSelection::Sequential => Arc::new(SequentialSelector),
+            PieceSelection::Streaming { window } => Arc::new(StreamingSelector { window: *window }),
+        })
+    }
+}
+
+impl FromStr for PieceSelection {
+    type Err = Error;
+
+    /// Format: random | rarest-first | sequential | streaming[:<window>]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.split_once(':') {
+            None if s == "random" => PieceSelection::Random,
+            None if s == "rarest-first" => PieceSelection::RarestFirst,
+            None if s == "sequential" => PieceSelection::Sequential,
+            None if s == "streaming" => PieceSelection::Streaming { window: 8 },
+            Some(("streaming", window)) => PieceSelection::Streaming {
+                window: window.parse().context("invalid streaming window")?,
+            },
+            _ => return Err(anyhow!("unknown piece selection: {}", s)),
+        })
+    }
+}
+
+/// Shared piece selector
+#[derive(Clone)]
+pub struct Selector(pub Arc<dyn PieceSelector>);
+
+impl fmt::Debug for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<selector>")
+    }
+}
+
+impl PartialEq for Selector {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+pub struct RandomSelector;
+
+impl PieceSelector for RandomSelector {
+    fn select(&self, candidates: &[&Piece], _: &Availability) -> Option<u32> {
+        candidates.iter().choose(&mut thread_rng()).map(|p| p.index)
+    }
+}
+
+pub struct RarestFirstSelector;
+
+impl PieceSelector for RarestFirstSelector {
+    fn select(&self, candidates: &[&Piece], availability: &Availability) -> Option<u32> {
+        let with_availability = candidates
+            .iter()
+            .map(|p| (p.index, availability.get(p.index)))
+            .collect::<Vec<_>>();
+        let rarest = with_availability.iter().map(|(_, a)| *a).min()?;
+        with_availability
+            .into_iter()
+            .filter(|(_, a)| *a == rarest)
+            .choose(&mut thread_rng())
+            .map(|(i, _)| i)
+    }
+}
+
+pub struct SequentialSelector;
+
+impl PieceSelector for SequentialSelector {
+    fn select(&self, candidates: &[&Piece], _: &Availability) -> Option<u32> {
+        candidates.first().map(|p| p.index)
+    }
+}
+
+pub struct StreamingSelector {
+    pub window: usize,
+}
+
+impl PieceSelector for StreamingSelector {
+    fn select(&self, candidates: &[&Piece], _: &Availability) -> Option<u32> {
+        candidates
+            .iter()
+            .take(self.window.max(1))
+            .choose(&mut thread_rng())
+            .map(|p| p.index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::state::{PieceHash, TorrentStatus};
+
+    fn piece(index: u32) -> Piece {
+        Piece {
+            hash: PieceHash(vec![]),
+            index,
+            length: 1,
+            blocks: BTreeMap::new(),
+            status: TorrentStatus::Downloading,
+            file_locations: vec![],
+            assigned: None,
+            block_peers: BTreeMap::new(),
+            webseed: false,
+        }
+    }
+
+    #[test]
+    fn should_select_sequential() {
+        let pieces = [piece(2), piece(5), piece(7)];
+        let candidates = pieces.iter().collect::<Vec<_>>();
+        assert_eq!(
+            SequentialSelector.select(&candidates, &Availability::default()),
+            Some(2)
+        );
+        assert_eq!(SequentialSelector.select(&[], &Availability::default()), None);
+    }
+
+    #[test]
+    fn should_select_rarest() {
+        let pieces = [piece(0), piece(1), piece(2)];
+        let candidates = pieces.iter().collect::<Vec<_>>();
+        let mut availability = Availability::new(8);
+        [0b1110_0000, 0b1010_0000, 0b1010_0000]
+            .into_iter()
+            .for_each(|b| availability.add_bitfield(&[b]));
+        assert_eq!(RarestFirstSelector.select(&candidates, &availability), Some(1));
+    }
+
+    #[test]
+    fn should_select_within_streaming_window() {
+        let pieces = (0..10).map(piece).collect::<Vec<_>>();
+        let candidates = pieces.iter().collect::<Vec<_>>();
+        let selector = StreamingSelector { window: 3 };
+        for _ in 0..20 {
+            assert!(selector.select(&candidates, &Availability::default()).unwrap() < 3);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::Url;
+use tokio::sync::Mutex;
+
+use crate::{
+    config::Config,
+    hex::{from_hex, hex},
+    metainfo::Metainfo,
+    persist::PersistState,
+    registry::PeerRegistry,
+    torrent::{metainfo_from_str, start_torrent, Torrent},
+    tracker_udp::UdpService,
+    types::ByteString,
+    webseed::{magnet_web_seeds, WebSeed},
+};
+
+/// Entry point for adding torrents from in-memory sources, sharing config, persist state, UDP tracker client and
+/// peer registry between them
+#[derive(Clone)]
+pub struct Session {
+    pub config: Config,
+    pub p_state: Arc<Mutex<PersistState>>,
+    pub udp: UdpService,
+    /// Peers known to torrents of the session, see [PeerRegistry]
+    pub registry: PeerRegistry,
+}
+
+impl Session {
+    pub fn new(config: Config, p_state: Arc<Mutex<PersistState>>) -> Session {
+        Session {
+            config,
+            p_state,
+            udp: UdpService::default(),
+            registry: PeerRegistry::default(),
+        }
+    }
+
+    /// Start download of the magnet link, metainfo is fetched from peers
+    pub async fn add_magnet(&self, magnet: &str) -> Result<Torrent> {
+        debug!("parsing magnet: {}", magnet);
+        let uri = Url::parse(magnet).context("magnet uri parsing error")?;
+        let xt = uri
+            .query_pairs()
+            .find(|(k, _)| k == "xt")
+            .context("no `info_hash` query param")?
+            .1
+            .to_string();
+        trace!("xt: {}", xt);
+        let info_hash = parse_btih(xt.split("urn:btih:").last().context("invalid magnet")?)?;
+        info!("magnet info hash: {}", hex(&info_hash));
+        self.add(info_hash, None, magnet_web_seeds(&uri)).await
+    }
+
+    /// Fetch metainfo of the magnet link from peers, without downloading torrent data
+    pub async fn fetch_metainfo(&self, magnet: &str) -> Result<Metainfo> {
+        let session = Session {
+            config: Config {
+                metainfo_only: true,
+                ..self.config.clone()
+            },
+            ..self.clone()
+        };
+        let torrent = session.add_magnet(magnet).await?;
+        torrent.wait().await?;
+        torrent.metainfo().await.context("no metainfo")
+    }
+
+    /// Start download of the torrent known only by info hash, metainfo is fetched from peers
+    pub async fn add_info_hash(&self, info_hash: ByteString) -> Result<Torrent> {
+        self.add(info_hash, None, BTreeSet::new()).await
+    }
+
+    /// Start download of the bencoded .torrent data
+    pub async fn add_torrent_bytes(&self, bencoded: ByteString) -> Result<Torrent> {
+        let (_, metainfo) = metainfo_from_str(bencoded)?;
+        self.add_metainfo(metainfo).await
+    }
+
+    /// Start download of the parsed metainfo
+    pub async fn add_metainfo(&self, metainfo: Metainfo) -> Result<Torrent> {
+        let info_hash = metainfo.info_hash()?;
+        self.add(info_hash, Some(metainfo), BTreeSet::new()).await
+    }
+
+    async fn add(
+        &self,
+        info_hash: ByteString,
+        metainfo: Option<Metainfo>,
+        web_seeds: BTreeSet<WebSeed>,
+    ) -> Result<Torrent> {
+        start_torrent(info_hash, metainfo, web_seeds, self).await
+    }
+}
+
+/// Info hash of the magnet, either 40 character hex or 32 character base32
+fn parse_btih(btih: &str) -> Result<ByteString> {
+    match btih.len() {
+        40 if btih.chars().all(|c| c.is_ascii_hexdigit()) => Ok(from_hex(&btih.to_lowercase())),
+        32 => {
+            let (mut buf, mut bits, mut info_hash) = (0u32, 0, vec![]);
+            for c in btih.to_ascii_uppercase().bytes() {
+                let v = match c {
+                    b'A'..=b'Z' => c - b'A',
+                    b'2'..=b'7' => c - b'2' + 26,
+                    _ => return Err(anyhow!("invalid base32 info hash: {}", btih)),
+                };
+                buf = (buf << 5) | v as u32;
+                bits += 5;
+                if bits >= 8 {
+                    bits -= 8;
+                    info_hash.push((buf >> bits) as u8);
+                }
+            }
+            Ok(info_hash)
+        }
+        _ => Err(anyhow!("invalid info hash: {}", btih)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_btih() {
+        let info_hash = (0..20).collect::<Vec<u8>>();
+        assert_eq!(
+            parse_btih("000102030405060708090A0b0c0d0e0f10111213").unwrap(),
+            info_hash
+        );
+        assert_eq!(parse_btih("AAAQEAYEAUDAOCAJBIFQYDIOB4IBCEQT").unwrap(), info_hash);
+        assert!(parse_btih("000102030405060708090a0b0c0d0e0f1011121").is_err());
+        assert!(parse_btih("zz0102030405060708090a0b0c0d0e0f10111213").is_err());
+        assert!(parse_btih("AAAQEAYEAUDAOCAJBIFQYDIOB4IBCEQ1").is_err());
+        assert!(parse_btih("").is_err());
+    }
+}
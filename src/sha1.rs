@@ -1,9 +1,57 @@
+use std::{num::NonZeroUsize, thread};
+
+use anyhow::Result;
 use sha1::{Digest, Sha1};
+use tokio::task::spawn_blocking;
 
 use crate::types::ByteString;
 
+/// SHA-1 hash of the value. Hardware SHA extensions are detected at runtime, assembly implementation is used with
+/// `sha1-asm` feature
 pub fn encode(value: ByteString) -> ByteString {
+    encode_slice(&value)
+}
+
+fn encode_slice(value: &[u8]) -> ByteString {
     let mut sha = Sha1::default();
     sha.update(value);
     sha.finalize().to_vec()
 }
+
+/// Hash on the blocking thread pool, so that hashing of large values does not stall async tasks
+pub async fn encode_blocking(value: ByteString) -> Result<ByteString> {
+    Ok(spawn_blocking(move || encode(value)).await?)
+}
+
+/// Hash every value, spreading them across available CPU cores. Hashes are in the order of values
+pub fn encode_batch(values: &[ByteString]) -> Vec<ByteString> {
+    let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let chunk = values.len().div_ceil(threads).max(1);
+    thread::scope(|s| {
+        values
+            .chunks(chunk)
+            .map(|c| s.spawn(|| c.iter().map(|v| encode_slice(v)).collect::<Vec<_>>()))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(|h| h.join().expect("hashing thread panicked"))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex::hex;
+
+    #[test]
+    fn should_encode_batch() {
+        let values = (0..10u8).map(|i| vec![i; i as usize * 1000]).collect::<Vec<_>>();
+        let hashes = encode_batch(&values);
+        assert_eq!(hashes, values.iter().cloned().map(encode).collect::<Vec<_>>());
+        assert_eq!(
+            hex(&encode(b"abc".to_vec())),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert!(encode_batch(&[]).is_empty());
+    }
+}
@@ -1,43 +1,280 @@
 use core::fmt;
-use std::collections::BTreeMap;
+use std::{
+    cmp,
+    collections::{BTreeMap, BTreeSet},
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::Notify, task::AbortHandle, time::timeout};
 
-use anyhow::{ensure, Error};
-use rand::{seq::IteratorRandom, thread_rng};
+use anyhow::{anyhow, ensure, Error, Result};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     config::Config,
+    disk::Disk,
     extension::Extension,
+    feature::Feature,
     hex::hex,
+    holepunch::HolepunchMessage,
     metainfo::{Info, Metainfo},
     peer_metainfo::MetainfoState,
-    tracker::TrackerResponseSuccess,
+    registry::PeerRegistry,
+    selector::Selector,
+    stats::{PeerSource, Stats},
+    tracker::TrackerState,
+    tracker_udp::UdpService,
     types::ByteString,
+    webseed::WebSeed,
 };
 
 pub const BLOCK_SIZE: u32 = 1 << 14;
 
+/// Min number of outstanding requests to the peer, used until its download rate is known
+pub const MIN_REQUESTS: usize = 4;
+
+/// Period over which peer download rate is measured
+pub const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Assumed `reqq` of peers that do not advertise it, see [BEP-10](https://www.bittorrent.org/beps/bep_0010.html)
+pub const DEFAULT_REQQ: usize = 250;
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct State {
     pub config: Config,
     pub info_hash: Vec<u8>,
     pub peer_id: Vec<u8>,
+    /// Port we are listening for peer connections on
+    pub port: u16,
+    pub dht_node_id: Vec<u8>,
+    /// DHT nodes that responded to ping and are not persisted yet
+    pub dht_nodes: BTreeSet<PeerInfo>,
     pub peers: BTreeMap<PeerInfo, Peer>,
     pub status: TorrentStatus,
     pub metainfo: Result<Metainfo, MetainfoState>,
-    pub tracker_response: Option<TrackerResponseSuccess>,
+    /// Announce status of every tracker, by announce URL
+    pub tracker_states: BTreeMap<String, TrackerState>,
+    /// Tracker tiers in announce order, responding trackers are moved to the front of their tier, see
+    /// [crate::tracker::announce_tiers]
+    pub tracker_tiers: Vec<Vec<String>>,
     pub pieces: Option<BTreeMap<u32, Piece>>,
+    /// Our external ip, as reported by peers and DHT nodes
+    pub external_ip: Option<Ipv4Addr>,
+    pub disk: Disk,
+    pub stats: Stats,
+    pub selector: Selector,
+    /// UDP tracker client shared with other torrents of the session
+    pub udp: UdpService,
+    /// Peers known to torrents of the session
+    pub registry: PeerRegistry,
+    /// Time the download is started
+    pub started: Instant,
+    /// Web seeds specified outside of metainfo, e.g. in magnet link
+    pub web_seeds: BTreeSet<WebSeed>,
+    pub peer_tasks: PeerTasks,
+    pub availability: Availability,
 }
 
 impl State {
-    pub fn next_piece(&mut self) -> Option<Piece> {
-        self.pieces
-            .as_ref()?
+    /// Next piece to request from the peer.
+    /// Peer keeps downloading its assigned piece until all its blocks are requested, then gets an unassigned piece it
+    /// has, chosen by the piece selector, so that fast peers can have several pieces in flight.
+    /// When there are no unassigned pieces left (endgame), pieces assigned to the slowest peers are duplicated, unless
+    /// waste ratio exceeds `max_waste_percent`.
+    /// When piece buffer is over `max_piece_buffer`, no new pieces are requested and complete unsaved pieces are
+    /// handed out to be flushed to disk.
+    /// Pieces in flight may later be moved to faster peers, see [crate::scheduler::plan_reassignments]
+    pub fn next_piece(&mut self, peer: &PeerInfo) -> Option<Piece> {
+        let over_budget = self.buffered() >= self.config.max_piece_buffer;
+        let State {
+            pieces,
+            peers,
+            selector,
+            ..
+        } = self;
+        let pieces = pieces.as_mut()?;
+        let p = peers.get(peer)?;
+
+        if let Some(piece) = pieces.values().find(|pc| {
+            pc.status == TorrentStatus::Downloading
+                && pc.assigned.as_ref() == Some(peer)
+                && p.has_unrequested_blocks(pc)
+        }) {
+            return Some(piece.clone());
+        }
+
+        if over_budget {
+            trace!("piece buffer is full, throttling requests");
+            return pieces
+                .values_mut()
+                .find(|pc| pc.status == TorrentStatus::Downloading && pc.assigned.is_none() && pc.is_complete())
+                .map(|piece| {
+                    piece.assigned = Some(peer.clone());
+                    piece.clone()
+                });
+        }
+
+        let candidates = pieces
+            .values()
+            .filter(|pc| {
+                pc.status == TorrentStatus::Downloading && pc.assigned.is_none() && !pc.webseed && p.has_piece(pc.index)
+            })
+            .collect::<Vec<_>>();
+        if let Some(piece) = selector
+            .0
+            .select(&candidates, &self.availability)
+            .and_then(|i| pieces.get_mut(&i))
+        {
+            piece.assigned = Some(peer.clone());
+            return Some(piece.clone());
+        }
+
+        if self.stats.waste_ratio() * 100. > self.config.max_waste_percent as f64 {
+            trace!("waste ratio is over the limit, not duplicating requests");
+            return None;
+        }
+        pieces
             .values()
-            .filter(|p| p.status == TorrentStatus::Downloading)
-            .choose(&mut thread_rng())
+            .filter(|pc| pc.status == TorrentStatus::Downloading && p.has_piece(pc.index))
+            .min_by_key(|pc| pc.assigned.as_ref().and_then(|a| peers.get(a)).map(|a| a.downloaded))
             .cloned()
     }
+
+    /// Next piece to download from a web seed: the first piece no peer is downloading
+    pub fn next_webseed_piece(&mut self) -> Option<Piece> {
+        let piece = self.pieces.as_mut()?.values_mut().find(|pc| {
+            pc.status == TorrentStatus::Downloading && pc.assigned.is_none() && !pc.webseed && pc.blocks.is_empty()
+        })?;
+        piece.webseed = true;
+        Some(piece.clone())
+    }
+
+    /// Add peers to the peer pool, refreshing ones that are already known. Returns number of new peers
+    pub fn add_peers(&mut self, peers: impl IntoIterator<Item = PeerInfo>, source: PeerSource) -> usize {
+        let mut new = 0;
+        for info in peers {
+            match self.peers.get_mut(&info) {
+                Some(p) => p.last_seen = Instant::now(),
+                None => {
+                    self.peers.insert(info.clone(), Peer::new(info, source.clone()));
+                    new += 1;
+                }
+            }
+        }
+        self.evict_peers();
+        new
+    }
+
+    /// Evict least promising idle peers while peer pool is over `max_peers`: ones with the most failed connects, from
+    /// the least reliable source, seen the longest time ago
+    pub fn evict_peers(&mut self) {
+        let excess = self.peers.len().saturating_sub(self.config.max_peers);
+        if excess == 0 {
+            return;
+        }
+        let mut idle = self
+            .peers
+            .values()
+            .filter(|p| matches!(p.status, PeerStatus::Disconnected | PeerStatus::Done))
+            .collect::<Vec<_>>();
+        idle.sort_by_key(|p| (cmp::Reverse(p.connect_fails), p.source.priority(), p.last_seen));
+        let evicted = idle
+            .into_iter()
+            .take(excess)
+            .map(|p| p.info.clone())
+            .collect::<Vec<_>>();
+        debug!("evicting {} peers from the peer pool", evicted.len());
+        for p in evicted {
+            self.peers.remove(&p);
+        }
+    }
+
+    /// Size of blocks held in memory, waiting for their pieces to be verified and saved
+    pub fn buffered(&self) -> usize {
+        self.pieces
+            .as_ref()
+            .map(|ps| ps.values().flat_map(|p| p.blocks.values()).map(|b| b.0.len()).sum())
+            .unwrap_or(0)
+    }
+
+    /// Return pieces assigned to the peer to the request pool
+    pub fn release_pieces(&mut self, peer: &PeerInfo) {
+        if let Some(pieces) = self.pieces.as_mut() {
+            pieces
+                .values_mut()
+                .filter(|pc| pc.assigned.as_ref() == Some(peer))
+                .for_each(|pc| pc.assigned = None);
+        }
+        self.notify_peers();
+    }
+
+    /// Wake writers of every peer, e.g. when torrent status changes
+    pub fn notify_peers(&self) {
+        self.peers.values().for_each(|p| p.notify.notify());
+    }
+
+    /// Stop requesting pieces, keeping peers and progress. Returns false if torrent is not active
+    pub fn pause(&mut self) -> bool {
+        if !matches!(self.status, TorrentStatus::Metainfo | TorrentStatus::Downloading) {
+            return false;
+        }
+        self.status = TorrentStatus::Paused;
+        self.notify_peers();
+        true
+    }
+
+    /// Continue paused torrent from where it stopped. Returns false if torrent is not paused
+    pub fn resume(&mut self) -> bool {
+        if self.status != TorrentStatus::Paused {
+            return false;
+        }
+        self.status = if self.metainfo.is_ok() {
+            TorrentStatus::Downloading
+        } else {
+            TorrentStatus::Metainfo
+        };
+        self.notify_peers();
+        true
+    }
+
+    /// Bitfield of saved pieces, `None` if metainfo is not known
+    pub fn saved_bitfield(&self) -> Option<Vec<u8>> {
+        let pieces = self.pieces.as_ref()?;
+        let len = self.metainfo.as_ref().ok()?.info.pieces.len();
+        let mut bitfield = vec![0u8; len.div_ceil(8)];
+        for p in pieces.values().filter(|p| p.status == TorrentStatus::Saved) {
+            bitfield[p.index as usize / 8] |= 0x80 >> (p.index % 8);
+        }
+        Some(bitfield)
+    }
+
+    /// Number of peers connected or being connected to
+    pub fn active_peers(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|p| matches!(p.status, PeerStatus::Connecting | PeerStatus::Connected))
+            .count()
+    }
+
+    /// Bytes of pieces not saved yet, zero if metainfo is not known
+    pub fn left(&self) -> u64 {
+        self.pieces
+            .iter()
+            .flat_map(|ps| ps.values())
+            .filter(|p| p.status != TorrentStatus::Saved)
+            .map(|p| p.length as u64)
+            .sum()
+    }
+
+    /// Whether every piece is downloaded
+    pub fn is_downloaded(&self) -> bool {
+        self.pieces
+            .as_ref()
+            .is_some_and(|ps| ps.values().all(|p| p.status > TorrentStatus::Downloading))
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, Hash)]
@@ -46,6 +283,7 @@ pub enum TorrentStatus {
     Downloading,
     Downloaded,
     Saved,
+    Paused,
 }
 
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
@@ -57,12 +295,32 @@ pub struct Piece {
     pub blocks: BTreeMap<u32, Block>,
     pub status: TorrentStatus,
     pub file_locations: Vec<FileLocation>,
+    /// Peer this piece is requested from
+    pub assigned: Option<PeerInfo>,
+    /// Map of blocks <block index> -> <peer the block is received from>
+    pub block_peers: BTreeMap<u32, PeerInfo>,
+    /// Whether piece is being downloaded from a web seed
+    pub webseed: bool,
 }
 
 impl Piece {
     pub fn total_blocks(&self) -> u32 {
         self.length.div_ceil(BLOCK_SIZE)
     }
+
+    /// Whether every block of the piece is received
+    pub fn is_complete(&self) -> bool {
+        self.blocks.len() as u32 == self.total_blocks()
+    }
+
+    /// Length of the block, last block can be shorter than `BLOCK_SIZE`
+    pub fn block_length(&self, index: u32) -> u32 {
+        if index == self.total_blocks() - 1 && !self.length.is_multiple_of(BLOCK_SIZE) {
+            self.length % BLOCK_SIZE
+        } else {
+            BLOCK_SIZE
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, PartialOrd, Hash)]
@@ -94,10 +352,175 @@ pub struct Peer {
     pub bitfield: Option<Vec<u8>>,
     pub dht_port: Option<u16>,
     pub extension_map: BTreeMap<Extension, u8>,
+    /// Bytes of verified pieces' blocks received from the peer
+    pub downloaded: u64,
+    /// Number of failed hash checks of pieces the peer contributed blocks to
+    pub hash_fails: usize,
+    /// Holepunch messages to send to the peer
+    pub holepunch_queue: Vec<HolepunchMessage>,
+    /// Whether holepunch to this peer was requested via relay peer
+    pub holepunch_requested: bool,
+    /// Whether peer is a partial seed not interested in downloading, see BEP-21
+    pub upload_only: bool,
+    pub source: PeerSource,
+    /// Number of consecutive connection attempts that did not result in a handshake
+    pub connect_fails: u32,
+    pub last_connect: Option<Instant>,
+    /// Last time peer was reported by its source
+    pub last_seen: Instant,
+    /// Max number of outstanding requests the peer accepts, advertised as `reqq` in extended handshake
+    pub reqq: Option<usize>,
+    /// Blocks requested from the peer and not yet received <piece index, block index> -> <request time>
+    pub requests_out: BTreeMap<(u32, u32), Instant>,
+    /// Requests received from the peer and not yet served or cancelled <piece index, begin, length>
+    pub requests_in: BTreeSet<(u32, u32, u32)>,
+    /// Pieces moved to another peer by the scheduler, outstanding requests of which are to be cancelled
+    pub revoked: BTreeSet<u32>,
+    /// Wakes peer's writer when something it waits on changes
+    pub notify: PeerNotify,
+    /// Smoothed time between block request and its arrival
+    pub rtt: Option<Duration>,
+    /// Smoothed download rate in bytes per second
+    pub rate: u64,
+    /// Start of the current rate measurement window
+    pub rate_window_start: Instant,
+    /// Bytes received in the current rate measurement window
+    pub rate_window_bytes: u64,
+    /// Features supported by both us and the peer, negotiated in the handshake
+    pub features: BTreeSet<Feature>,
+    /// Client name, from extended handshake or peer id
+    pub client: Option<String>,
+    /// Bytes of blocks sent to the peer
+    pub uploaded: u64,
+}
+
+/// Notification for the peer's writer. Notifications sent while writer is busy are not lost, but coalesced into one
+#[derive(Clone, Default)]
+pub struct PeerNotify(Arc<Notify>);
+
+impl PeerNotify {
+    pub fn notify(&self) {
+        self.0.notify_one()
+    }
+
+    /// Wait for notification, at most `wait`
+    pub async fn wait(&self, wait: Duration) {
+        let _ = timeout(wait, self.0.notified()).await;
+    }
+}
+
+impl fmt::Debug for PeerNotify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<notify>")
+    }
+}
+
+impl PartialEq for PeerNotify {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for PeerNotify {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Arc::as_ptr(&self.0).partial_cmp(&Arc::as_ptr(&other.0))
+    }
+}
+
+impl Hash for PeerNotify {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.0).hash(state)
+    }
+}
+
+/// Number of connected peers having each piece, updated incrementally from bitfields and haves.
+/// Peers that haven't sent bitfield are not counted. Sized by the piece count once metainfo is known, so that indices
+/// sent by peers never grow it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Availability(Vec<u32>);
+
+impl Availability {
+    pub fn new(piece_count: usize) -> Availability {
+        Availability(vec![0; piece_count])
+    }
+
+    pub fn get(&self, index: u32) -> u32 {
+        self.0.get(index as usize).copied().unwrap_or(0)
+    }
+
+    /// Count the piece, out of range indices are ignored
+    pub fn add(&mut self, index: u32) {
+        if let Some(a) = self.0.get_mut(index as usize) {
+            *a += 1;
+        }
+    }
+
+    pub fn add_bitfield(&mut self, bitfield: &[u8]) {
+        Availability::set_bits(bitfield).for_each(|i| self.add(i));
+    }
+
+    pub fn remove_bitfield(&mut self, bitfield: &[u8]) {
+        Availability::set_bits(bitfield).for_each(|i| {
+            if let Some(a) = self.0.get_mut(i as usize) {
+                *a = a.saturating_sub(1);
+            }
+        });
+    }
+
+    fn set_bits(bitfield: &[u8]) -> impl Iterator<Item = u32> + '_ {
+        bitfield
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b != 0)
+            .flat_map(|(byte, b)| {
+                (0..8)
+                    .filter(move |bit| b & (0x80 >> bit) != 0)
+                    .map(move |bit| (byte * 8 + bit) as u32)
+            })
+    }
+}
+
+/// Connection tasks of peers, so that every peer is handled by at most one task and all of them are aborted on
+/// shutdown
+#[derive(Clone, Default)]
+pub struct PeerTasks(BTreeMap<PeerInfo, AbortHandle>);
+
+impl PeerTasks {
+    pub fn insert(&mut self, peer: PeerInfo, task: AbortHandle) {
+        self.0.insert(peer, task);
+    }
+
+    /// Whether the peer has a running task
+    pub fn is_live(&self, peer: &PeerInfo) -> bool {
+        self.0.get(peer).is_some_and(|t| !t.is_finished())
+    }
+
+    /// Forget finished tasks
+    pub fn prune(&mut self) {
+        self.0.retain(|_, t| !t.is_finished());
+    }
+
+    pub fn abort_all(&mut self) {
+        debug!("aborting {} peer tasks", self.0.len());
+        self.0.values().for_each(|t| t.abort());
+        self.0.clear();
+    }
+}
+
+impl fmt::Debug for PeerTasks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} peer tasks>", self.0.len())
+    }
+}
+
+impl PartialEq for PeerTasks {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.keys().eq(other.0.keys())
+    }
 }
 
 impl Peer {
-    pub fn new(info: PeerInfo) -> Peer {
+    pub fn new(info: PeerInfo, source: PeerSource) -> Peer {
         Peer {
             info,
             status: PeerStatus::Disconnected,
@@ -108,42 +531,211 @@ impl Peer {
             bitfield: None,
             dht_port: None,
             extension_map: BTreeMap::new(),
+            downloaded: 0,
+            hash_fails: 0,
+            holepunch_queue: vec![],
+            holepunch_requested: false,
+            upload_only: false,
+            source,
+            connect_fails: 0,
+            last_connect: None,
+            last_seen: Instant::now(),
+            reqq: None,
+            requests_out: BTreeMap::new(),
+            requests_in: BTreeSet::new(),
+            revoked: BTreeSet::new(),
+            notify: PeerNotify::default(),
+            rtt: None,
+            rate: 0,
+            rate_window_start: Instant::now(),
+            rate_window_bytes: 0,
+            features: BTreeSet::new(),
+            client: None,
+            uploaded: 0,
+        }
+    }
+
+    /// Record received block of `length` bytes, updating rtt and download rate estimates
+    pub fn record_block(&mut self, length: usize, rtt: Option<Duration>) {
+        if let Some(rtt) = rtt {
+            self.rtt = Some(match self.rtt {
+                Some(srtt) => (srtt * 7 + rtt) / 8,
+                None => rtt,
+            });
+        }
+        self.rate_window_bytes += length as u64;
+        let elapsed = self.rate_window_start.elapsed();
+        if elapsed >= RATE_WINDOW {
+            let rate = (self.rate_window_bytes as f64 / elapsed.as_secs_f64()) as u64;
+            self.rate = if self.rate == 0 {
+                rate
+            } else {
+                (self.rate * 3 + rate) / 4
+            };
+            self.rate_window_start = Instant::now();
+            self.rate_window_bytes = 0;
         }
     }
+
+    /// Number of outstanding requests to keep: enough blocks to cover `queue_time` (or two round trips, whichever is
+    /// longer) at the peer's download rate, within peer's `reqq`
+    pub fn target_requests(&self, queue_time: Duration) -> usize {
+        let reqq = self.reqq.unwrap_or(DEFAULT_REQQ);
+        let time = queue_time.max(self.rtt.unwrap_or_default() * 2);
+        let target = (self.rate as f64 * time.as_secs_f64() / BLOCK_SIZE as f64) as usize;
+        target.clamp(MIN_REQUESTS.min(reqq), reqq)
+    }
+
+    /// Time to download `blocks` more blocks once outstanding requests are received, `None` if rate is unknown
+    pub fn eta(&self, blocks: usize) -> Option<Duration> {
+        if self.rate == 0 {
+            return None;
+        }
+        let bytes = (self.requests_out.len() + blocks) as u64 * BLOCK_SIZE as u64;
+        Some(Duration::from_secs_f64(bytes as f64 / self.rate as f64) + self.rtt.unwrap_or_default())
+    }
+
+    /// Queue holepunch message to be sent by the peer's writer
+    pub fn queue_holepunch(&mut self, msg: HolepunchMessage) {
+        self.holepunch_queue.push(msg);
+        self.notify.notify();
+    }
+
+    /// Number of blocks that can be requested from the peer without exceeding its target queue
+    pub fn free_requests(&self, queue_time: Duration) -> usize {
+        self.target_requests(queue_time).saturating_sub(self.requests_out.len())
+    }
+
+    /// Whether the piece has blocks that are neither received nor requested from the peer
+    pub fn has_unrequested_blocks(&self, piece: &Piece) -> bool {
+        (0..piece.total_blocks())
+            .any(|i| !piece.blocks.contains_key(&i) && !self.requests_out.contains_key(&(piece.index, i)))
+    }
+
+    /// Whether enough time passed since the last connection attempt. Wait grows exponentially with every failed
+    /// attempt, up to `max_reconnect_wait`
+    pub fn can_reconnect(&self, config: &Config) -> bool {
+        let wait = config
+            .reconnect_wait
+            .saturating_mul(1 << self.connect_fails.saturating_sub(1).min(16))
+            .min(config.max_reconnect_wait);
+        self.last_connect.is_none_or(|t| t.elapsed() >= wait)
+    }
+
+    /// Whether upload only peer can be disconnected. Blocks in flight may still fail hash check and need to be
+    /// requested again, so peer is kept until they are received
+    pub fn is_idle_upload_only(&self) -> bool {
+        self.upload_only && self.requests_out.is_empty()
+    }
+
+    /// Whether peer has the piece. Peers that haven't sent bitfield are assumed to have every piece
+    pub fn has_piece(&self, index: u32) -> bool {
+        match &self.bitfield {
+            Some(bitfield) => bitfield
+                .get(index as usize / 8)
+                .is_some_and(|b| b & (0x80 >> (index % 8)) != 0),
+            None => true,
+        }
+    }
+
+    /// Whether peer has the piece according to its bitfield and haves
+    pub fn has_piece_explicit(&self, index: u32) -> bool {
+        self.bitfield.is_some() && self.has_piece(index)
+    }
+
+    /// Mark the piece as present, bitfield is never grown past `piece_count` pieces
+    pub fn set_piece(&mut self, index: u32, piece_count: usize) -> Result<()> {
+        ensure!(
+            (index as usize) < piece_count,
+            "piece index {} is out of {} pieces",
+            index,
+            piece_count
+        );
+        let bitfield = self.bitfield.get_or_insert_with(|| vec![0; piece_count.div_ceil(8)]);
+        let byte = bitfield
+            .get_mut(index as usize / 8)
+            .ok_or_else(|| anyhow!("bitfield does not match {} pieces", piece_count))?;
+        *byte |= 0x80 >> (index % 8);
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, Hash)]
 pub enum PeerStatus {
     Disconnected,
+    /// Connection is being established or handshake is in progress
+    Connecting,
     Connected,
     Done,
+    /// Peer repeatedly sent corrupt data and is never connected again
+    Banned,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct PeerInfo {
-    pub ip: String,
+    pub ip: IpAddr,
     pub port: u16,
 }
 
 impl PeerInfo {
-    pub fn to_addr(&self) -> String {
-        format!("{}:{}", self.ip, self.port)
+    pub fn to_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+}
+
+impl From<SocketAddr> for PeerInfo {
+    /// IPv4-mapped IPv6 addresses are converted to IPv4, so that the same peer is not known under two addresses
+    fn from(value: SocketAddr) -> Self {
+        PeerInfo {
+            ip: value.ip().to_canonical(),
+            port: value.port(),
+        }
     }
 }
 
 impl TryFrom<&[u8]> for PeerInfo {
     type Error = Error;
 
+    /// Compact peer info: 4 byte IPv4 or 16 byte IPv6 address, followed by 2 byte port
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        ensure!(value.len() == 6, "expected 6 byte slice");
+        let ip = match value.len() {
+            6 => IpAddr::from(<[u8; 4]>::try_from(&value[0..4])?),
+            18 => IpAddr::from(<[u8; 16]>::try_from(&value[0..16])?).to_canonical(),
+            _ => return Err(anyhow!("expected 6 or 18 byte slice")),
+        };
+        let port_start = value.len() - 2;
         Ok(PeerInfo {
-            ip: value[0..4].iter().map(|b| b.to_string()).collect::<Vec<_>>().join("."),
-            port: u16::from_be_bytes(value[4..6].try_into()?),
+            ip,
+            port: u16::from_be_bytes(value[port_start..].try_into()?),
         })
     }
 }
 
-pub fn init_pieces(info: &Info) -> BTreeMap<u32, Piece> {
+/// Whether every piece is saved to disk, so that the torrent is downloaded
+pub fn all_saved(pieces: &BTreeMap<u32, Piece>) -> bool {
+    pieces.values().all(|p| p.status == TorrentStatus::Saved)
+}
+
+/// Check that bitfield has exactly one bit per piece and its spare bits are cleared
+pub fn validate_bitfield(bitfield: &[u8], piece_count: usize) -> Result<()> {
+    ensure!(
+        bitfield.len() == piece_count.div_ceil(8),
+        "bitfield length {} does not match {} pieces",
+        bitfield.len(),
+        piece_count
+    );
+    let spare_bits = bitfield.len() * 8 - piece_count;
+    if let Some(last) = bitfield.last() {
+        ensure!(
+            last & ((1u16 << spare_bits) - 1) as u8 == 0,
+            "bitfield spare bits are set"
+        );
+    }
+    Ok(())
+}
+
+/// Map pieces to file locations. Fails if pieces and files do not cover each other exactly
+pub fn init_pieces(info: &Info) -> Result<BTreeMap<u32, Piece>> {
     let files_start = info
         .file_info
         .files()
@@ -155,23 +747,29 @@ pub fn init_pieces(info: &Info) -> BTreeMap<u32, Piece> {
         })
         .collect::<Vec<_>>();
     let total_len = info.file_info.total_length();
-    if info.pieces.len() != total_len.div_ceil(info.piece_length) as usize {
-        warn!(
+    ensure!(info.piece_length > 0, "piece length is zero");
+    let expected_pieces = total_len.div_ceil(info.piece_length) as usize;
+    if total_len == 0 && info.pieces.len() <= 1 {
+        // some clients produce a single piece hash for torrents consisting of empty files only
+        debug!("torrent has no data, {} info pieces", info.pieces.len());
+    } else {
+        ensure!(
+            info.pieces.len() == expected_pieces,
             "total length/piece size/piece count inconsistent: {} info pieces, {} expected",
             info.pieces.len(),
-            total_len.div_ceil(info.piece_length)
+            expected_pieces
         );
     }
-    info.pieces
+    let pieces = info
+        .pieces
         .iter()
         .cloned()
         .enumerate()
         .flat_map(|(i, p)| {
-            let length: u64 = if i == info.pieces.len() - 1 {
-                total_len % info.piece_length
-            } else {
-                info.piece_length
-            };
+            let length = cmp::min(
+                info.piece_length,
+                total_len.saturating_sub(i as u64 * info.piece_length),
+            );
             let file_locations: Vec<_> = files_start
                 .iter()
                 .copied()
@@ -202,7 +800,6 @@ pub fn init_pieces(info: &Info) -> BTreeMap<u32, Piece> {
                 debug!("piece does not map to any files: {:?}", p);
                 return vec![];
             }
-            // TODO: verify files' location integrity
             vec![(
                 i as u32,
                 Piece {
@@ -212,10 +809,65 @@ pub fn init_pieces(info: &Info) -> BTreeMap<u32, Piece> {
                     blocks: BTreeMap::new(),
                     status: TorrentStatus::Downloading,
                     file_locations,
+                    assigned: None,
+                    block_peers: BTreeMap::new(),
+                    webseed: false,
                 },
             )]
         })
-        .collect()
+        .collect();
+    verify_file_locations(info, &pieces)?;
+    Ok(pieces)
+}
+
+/// Verify that every piece is contiguously covered by its file locations and every file is contiguously covered by
+/// pieces
+fn verify_file_locations(info: &Info, pieces: &BTreeMap<u32, Piece>) -> Result<()> {
+    for p in pieces.values() {
+        let mut piece_offset = 0;
+        for fl in &p.file_locations {
+            ensure!(
+                fl.piece_offset == piece_offset,
+                "piece {} file location gap at offset {}, expected {}",
+                p.index,
+                fl.piece_offset,
+                piece_offset
+            );
+            piece_offset += fl.length;
+        }
+        ensure!(
+            piece_offset == p.length as usize,
+            "piece {} file locations length {} does not match piece length {}",
+            p.index,
+            piece_offset,
+            p.length
+        );
+    }
+    for (f_i, f) in info.file_info.files().iter().enumerate() {
+        let mut offset = 0;
+        for fl in pieces
+            .values()
+            .flat_map(|p| p.file_locations.iter())
+            .filter(|fl| fl.file_index == f_i)
+        {
+            ensure!(
+                fl.offset == offset,
+                "file {:?} piece gap at offset {}, expected {}",
+                f.path,
+                fl.offset,
+                offset
+            );
+            offset += fl.length;
+        }
+        ensure!(
+            offset as u64 == f.length,
+            "file {:?} is covered by pieces up to {}, file length is {}",
+            f.path,
+            offset,
+            f.length
+        );
+    }
+    Ok(())
 }
 
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
@@ -225,3 +877,200 @@ pub struct FileLocation {
     pub piece_offset: usize,
     pub length: usize,
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::metainfo::{FileInfo, PathInfo};
+
+    fn info(piece_length: u64, piece_count: usize, file_lengths: &[u64]) -> Info {
+        Info {
+            piece_length,
+            pieces: vec![PieceHash(vec![0; 20]); piece_count],
+            name: "test".into(),
+            file_info: FileInfo::Multi(
+                file_lengths
+                    .iter()
+                    .enumerate()
+                    .map(|(i, length)| PathInfo {
+                        length: *length,
+                        path: PathBuf::from(i.to_string()),
+                        md5_sum: None,
+                    })
+                    .collect(),
+            ),
+            private: None,
+        }
+    }
+
+    #[test]
+    fn should_set_piece() {
+        let mut peer = Peer::new(
+            PeerInfo {
+                ip: [1, 2, 3, 4].into(),
+                port: 1,
+            },
+            PeerSource::Manual,
+        );
+        assert!(peer.set_piece(10, 11).is_ok());
+        assert_eq!(peer.bitfield, Some(vec![0, 0b0010_0000]));
+        assert!(peer.set_piece(11, 11).is_err());
+        assert!(peer.set_piece(u32::MAX, 11).is_err());
+        assert_eq!(peer.bitfield, Some(vec![0, 0b0010_0000]));
+    }
+
+    #[test]
+    fn should_count_availability() {
+        let mut availability = Availability::new(16);
+        availability.add_bitfield(&[0b1010_0000, 0b0000_0001]);
+        availability.add_bitfield(&[0b1000_0000]);
+        availability.add(2);
+        assert_eq!(
+            (0..16).map(|i| availability.get(i)).collect::<Vec<_>>(),
+            vec![2, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]
+        );
+        availability.remove_bitfield(&[0b1010_0000, 0b0000_0001]);
+        assert_eq!(availability.get(0), 1);
+        assert_eq!(availability.get(2), 1);
+        assert_eq!(availability.get(15), 0);
+        assert_eq!(availability.get(100), 0);
+
+        availability.add(u32::MAX);
+        availability.add_bitfield(&[0, 0, 0xff]);
+        assert_eq!(availability, {
+            let mut a = Availability::new(16);
+            a.add_bitfield(&[0b1010_0000]);
+            a
+        });
+    }
+
+    #[test]
+    fn should_validate_bitfield() {
+        assert!(validate_bitfield(&[0xff, 0b1110_0000], 11).is_ok());
+        assert!(validate_bitfield(&[0xff, 0xff], 16).is_ok());
+        assert!(validate_bitfield(&[], 0).is_ok());
+        assert!(validate_bitfield(&[0xff, 0b1111_0000], 11).is_err());
+        assert!(validate_bitfield(&[0xff], 11).is_err());
+        assert!(validate_bitfield(&[0xff, 0, 0], 11).is_err());
+    }
+
+    #[test]
+    fn should_check_all_saved() {
+        let mut pieces = init_pieces(&info(1 << 15, 2, &[1 << 16])).unwrap();
+        assert!(!all_saved(&pieces));
+        pieces.get_mut(&0).unwrap().status = TorrentStatus::Saved;
+        assert!(!all_saved(&pieces));
+        pieces.get_mut(&1).unwrap().status = TorrentStatus::Downloaded;
+        assert!(!all_saved(&pieces));
+        pieces.get_mut(&1).unwrap().status = TorrentStatus::Saved;
+        assert!(all_saved(&pieces));
+    }
+
+    #[test]
+    fn should_keep_upload_only_peer_with_requests_in_flight() {
+        let mut peer = Peer::new(
+            PeerInfo::from(SocketAddr::from(([1, 2, 3, 4], 6881))),
+            PeerSource::Tracker,
+        );
+        assert!(!peer.is_idle_upload_only());
+        peer.upload_only = true;
+        assert!(peer.is_idle_upload_only());
+        peer.requests_out.insert((0, 0), Instant::now());
+        assert!(!peer.is_idle_upload_only());
+    }
+
+    #[test]
+    fn should_compute_block_length() {
+        let piece =
+            init_pieces(&info(BLOCK_SIZE as u64 * 2 + 10, 1, &[BLOCK_SIZE as u64 * 2 + 10])).unwrap()[&0].clone();
+        assert_eq!(piece.total_blocks(), 3);
+        assert_eq!(piece.block_length(0), BLOCK_SIZE);
+        assert_eq!(piece.block_length(2), 10);
+    }
+
+    #[test]
+    fn should_limit_requests_by_reqq() {
+        let queue_time = Duration::from_secs(3);
+        let mut peer = Peer::new(
+            PeerInfo::from(SocketAddr::from(([1, 2, 3, 4], 6881))),
+            PeerSource::Tracker,
+        );
+        peer.rate = 1 << 30;
+        assert_eq!(peer.free_requests(queue_time), DEFAULT_REQQ);
+        peer.reqq = Some(2);
+        peer.requests_out.insert((0, 0), Instant::now());
+        assert_eq!(peer.free_requests(queue_time), 1);
+        peer.requests_out.insert((0, 1), Instant::now());
+        peer.requests_out.insert((0, 2), Instant::now());
+        assert_eq!(peer.free_requests(queue_time), 0);
+    }
+
+    #[test]
+    fn should_adapt_requests_to_rate() {
+        let queue_time = Duration::from_secs(3);
+        let mut peer = Peer::new(
+            PeerInfo::from(SocketAddr::from(([1, 2, 3, 4], 6881))),
+            PeerSource::Tracker,
+        );
+        assert_eq!(peer.target_requests(queue_time), MIN_REQUESTS);
+        peer.rate = 10 * BLOCK_SIZE as u64;
+        assert_eq!(peer.target_requests(queue_time), 30);
+        peer.rtt = Some(Duration::from_secs(2));
+        assert_eq!(peer.target_requests(queue_time), 40);
+    }
+
+    #[test]
+    fn should_init_pieces_of_exact_multiple_length() {
+        let pieces = init_pieces(&info(1 << 15, 2, &[1 << 16])).unwrap();
+        assert_eq!(pieces.len(), 2);
+        for p in pieces.values() {
+            assert_eq!(p.length, 1 << 15);
+            assert_eq!(p.total_blocks(), 2);
+        }
+    }
+
+    #[test]
+    fn should_init_shorter_last_piece() {
+        let pieces = init_pieces(&info(1 << 15, 2, &[(1 << 15) + 100])).unwrap();
+        assert_eq!(pieces[&0].length, 1 << 15);
+        assert_eq!(pieces[&1].length, 100);
+        assert_eq!(pieces[&1].total_blocks(), 1);
+    }
+
+    #[test]
+    fn should_init_single_block_piece() {
+        let pieces = init_pieces(&info(1 << 18, 1, &[BLOCK_SIZE as u64])).unwrap();
+        assert_eq!(pieces.len(), 1);
+        assert_eq!(pieces[&0].length, BLOCK_SIZE);
+        assert_eq!(pieces[&0].total_blocks(), 1);
+    }
+
+    #[test]
+    fn should_init_pieces_spanning_files() {
+        let pieces = init_pieces(&info(1 << 15, 3, &[100, 1 << 15, 1 << 15])).unwrap();
+        assert_eq!(pieces[&0].file_locations.len(), 2);
+        assert_eq!(pieces[&1].file_locations.len(), 2);
+        assert_eq!(pieces[&2].length, 100);
+        assert_eq!(pieces[&2].file_locations.len(), 1);
+    }
+
+    #[test]
+    fn should_fail_on_uncovered_files() {
+        assert!(init_pieces(&info(1 << 15, 1, &[1 << 15, 100])).is_err());
+    }
+
+    #[test]
+    fn should_fail_on_piece_count_mismatch() {
+        assert!(init_pieces(&info(1 << 15, 3, &[1 << 16])).is_err());
+        assert!(init_pieces(&info(1 << 15, 1, &[1 << 16])).is_err());
+        assert!(init_pieces(&info(0, 1, &[1 << 16])).is_err());
+    }
+
+    #[test]
+    fn should_init_no_pieces_for_empty_files() {
+        assert!(init_pieces(&info(1 << 15, 1, &[0, 0])).unwrap().is_empty());
+        assert!(init_pieces(&info(1 << 15, 0, &[0])).unwrap().is_empty());
+    }
+}
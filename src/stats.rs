@@ -0,0 +1,160 @@
+use core::fmt;
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::Serialize;
+
+use crate::{
+    progress::{tracker_list, TrackerProgress},
+    state::State,
+};
+
+/// Torrent transfer counters, updated during download
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Stats {
+    /// Bytes of verified blocks
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Bytes of blocks received more than once, e.g. requested from several peers in endgame
+    pub wasted_duplicate: u64,
+    /// Bytes of blocks of unexpected size
+    pub wasted_wrong_size: u64,
+    /// Bytes of pieces that failed hash check
+    pub wasted_hash_fail: u64,
+    pub duplicate_blocks: u64,
+    pub hash_fails: u64,
+}
+
+impl Stats {
+    /// Bytes of received blocks that were discarded
+    pub fn wasted(&self) -> u64 {
+        self.wasted_duplicate + self.wasted_wrong_size + self.wasted_hash_fail
+    }
+
+    /// Wasted bytes relative to verified bytes
+    pub fn waste_ratio(&self) -> f64 {
+        if self.downloaded == 0 {
+            return 0.;
+        }
+        self.wasted() as f64 / self.downloaded as f64
+    }
+}
+
+/// Where the peer address is discovered from
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Incoming,
+    Holepunch,
+    /// Added by the user
+    Manual,
+}
+
+impl PeerSource {
+    /// How likely a peer from the source is reachable, higher is better
+    pub fn priority(&self) -> u8 {
+        match self {
+            PeerSource::Dht => 0,
+            PeerSource::Tracker => 1,
+            PeerSource::Holepunch => 2,
+            PeerSource::Incoming => 3,
+            PeerSource::Manual => 4,
+        }
+    }
+}
+
+/// Summary of the torrent download, printed on exit
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Summary {
+    #[serde(flatten)]
+    pub stats: Stats,
+    pub wasted: u64,
+    /// Number of peers that contributed verified blocks
+    pub peers_used: usize,
+    pub elapsed_secs: f64,
+    /// Average download rate in bytes per second
+    pub avg_rate: f64,
+    /// Number of known peers by source
+    pub peer_sources: BTreeMap<PeerSource, usize>,
+    pub trackers: Vec<TrackerProgress>,
+}
+
+impl Summary {
+    pub fn new(state: &State, elapsed: Duration) -> Summary {
+        let mut peer_sources = BTreeMap::new();
+        for p in state.peers.values() {
+            *peer_sources.entry(p.source.clone()).or_default() += 1;
+        }
+        let elapsed_secs = elapsed.as_secs_f64();
+        Summary {
+            stats: state.stats.clone(),
+            wasted: state.stats.wasted(),
+            peers_used: state.peers.values().filter(|p| p.downloaded > 0).count(),
+            elapsed_secs,
+            avg_rate: if elapsed_secs > 0. {
+                state.stats.downloaded as f64 / elapsed_secs
+            } else {
+                0.
+            },
+            peer_sources,
+            trackers: tracker_list(state),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sources = self
+            .peer_sources
+            .iter()
+            .map(|(s, c)| format!("{:?}: {}", s, c).to_lowercase())
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(f, "downloaded: {} bytes", self.stats.downloaded)?;
+        writeln!(f, "uploaded: {} bytes", self.stats.uploaded)?;
+        writeln!(
+            f,
+            "wasted: {} bytes (duplicate: {}, wrong size: {}, hash fail: {})",
+            self.wasted, self.stats.wasted_duplicate, self.stats.wasted_wrong_size, self.stats.wasted_hash_fail
+        )?;
+        writeln!(f, "duplicate blocks: {}", self.stats.duplicate_blocks)?;
+        writeln!(f, "hash fails: {}", self.stats.hash_fails)?;
+        writeln!(f, "peers used: {}", self.peers_used)?;
+        writeln!(f, "average rate: {:.1} KiB/s", self.avg_rate / 1024.)?;
+        write!(f, "peer sources: {}", sources)?;
+        for t in &self.trackers {
+            let count = |c: Option<i64>| c.map_or("?".into(), |c| c.to_string());
+            write!(
+                f,
+                "\ntracker {}: {} seeders, {} leechers",
+                t.url,
+                count(t.seeders),
+                count(t.leechers)
+            )?;
+            if let Some(e) = &t.last_error {
+                write!(f, ", last error: {}", e)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_compute_waste_ratio() {
+        let stats = Stats {
+            downloaded: 1000,
+            wasted_duplicate: 50,
+            wasted_wrong_size: 20,
+            wasted_hash_fail: 30,
+            ..Default::default()
+        };
+        assert_eq!(stats.wasted(), 100);
+        assert_eq!(stats.waste_ratio(), 0.1);
+        assert_eq!(Stats::default().waste_ratio(), 0.);
+    }
+}
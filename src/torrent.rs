@@ -1,12 +1,18 @@
 use anyhow::{anyhow, ensure, Context, Result};
-use std::collections::BTreeSet;
-use std::io::SeekFrom;
+use rand::{seq::SliceRandom, thread_rng};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::Path;
 use std::time::Instant;
-use std::{fs, path::PathBuf, sync::Arc};
-use tokio::fs::File;
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
-use tokio::{spawn, sync::Mutex};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{
+    net::TcpListener,
+    spawn,
+    sync::Mutex,
+    task::{spawn_blocking, JoinHandle},
+    time::sleep,
+};
 
 use crate::hex::hex;
 use crate::peer_metainfo::MetainfoState;
@@ -16,101 +22,469 @@ use crate::{
     abort::EnsureAbort,
     bencode::{parse_bencoded, BencodeValue},
     config::Config,
-    dht::find_peers,
-    metainfo::Metainfo,
-    peer::peer_loop,
-    persist::PersistState,
+    dht::{dht_loop, Dht},
+    disk::Disk,
+    hook::{run_hook, HookEnv, HookEvent},
+    limits::fd_budget,
+    metainfo::{Info, Metainfo, PathInfo},
+    peer::{bind_listener, connect_peer, listen_loop, peer_loop},
+    persist::{FileStamp, PersistState, ResumeProgress, ResumeRecord},
+    platform::{safe_component, safe_path},
+    progress::{
+        file_progress, peer_list, piece_map, progress, render_peer_list, render_piece_map, tracker_list, FileProgress,
+        PeerProgress, PieceProgress, Progress, RateSample, TrackerProgress,
+    },
+    scheduler::scheduler_loop,
+    session::Session,
     sha1,
-    state::{Peer, PeerInfo, State, TorrentStatus},
-    tracker::tracker_loop,
+    state::{all_saved, validate_bitfield, Availability, PeerInfo, PeerStatus, PeerTasks, Piece, State, TorrentStatus},
+    stats::{PeerSource, Stats, Summary},
+    tracker::{tracker_announce_event, tracker_loop, TrackerEvent},
+    webseed::{webseed_loop, WebSeed},
 };
 
-pub async fn download_torrent(
+/// Start torrent download in background, returning its handle once peers are discovered and listener is bound
+pub async fn start_torrent(
     info_hash: ByteString,
     metainfo: Option<Metainfo>,
-    config: &Config,
-    p_state: Arc<Mutex<PersistState>>,
-) -> Result<()> {
+    web_seeds: BTreeSet<WebSeed>,
+    session: &Session,
+) -> Result<Torrent> {
+    let config = &session.config;
     let started = Instant::now();
-    let (dht_peers, peer_id) = {
-        let p_state = p_state.lock().await;
-        (p_state.dht_peers.iter().cloned().collect(), p_state.peer_id.clone())
-    };
-    let peers = find_peers(
-        dht_peers,
-        peer_id.clone(),
-        info_hash.to_vec(),
-        config.dht_min_peers,
-        config.dht_chunk,
-    )
-    .await?;
+    let env = HookEnv {
+        info_hash: hex(&info_hash),
+        name: metainfo.as_ref().map(|m| m.info.name.clone()),
+        size: metainfo.as_ref().map(|m| m.info.file_info.total_length()),
+        ..Default::default()
+    };
+    let (state, listener) = match init_torrent(info_hash, metainfo, web_seeds, session, started).await {
+        Ok(r) => r,
+        Err(e) => {
+            let env = HookEnv {
+                duration: started.elapsed(),
+                ..env
+            };
+            run_hook(config, HookEvent::Error(format!("{e:#}")), env).await;
+            return Err(e);
+        }
+    };
+    let torrent = Torrent::new(state.clone()).await;
+    let config = config.clone();
+    let p_state = session.p_state.clone();
+    let task = spawn(async move {
+        let res = run_torrent(state.clone(), listener, p_state).await;
+        if let Err(e) = &res {
+            let env = HookEnv {
+                duration: started.elapsed(),
+                ..env
+            };
+            run_hook(&config, HookEvent::Error(format!("{e:#}")), env).await;
+        }
+        res
+    });
+    *torrent.task.lock().await = Some(task);
+    Ok(torrent)
+}
+
+/// Discover peers and initialize torrent state, verifying pieces of the resumed download
+async fn init_torrent(
+    info_hash: ByteString,
+    metainfo: Option<Metainfo>,
+    web_seeds: BTreeSet<WebSeed>,
+    session: &Session,
+    started: Instant,
+) -> Result<(Arc<Mutex<State>>, TcpListener)> {
+    let Session {
+        config,
+        p_state,
+        udp,
+        registry,
+    } = session;
+    let record = p_state.lock().await.torrents.get(&hex(&info_hash)).cloned();
+    let resumed = record.is_some();
+    let progress = record.as_ref().and_then(|r| r.progress.clone());
+    let metainfo = match (metainfo, record) {
+        (Some(metainfo), _) => Some(metainfo),
+        (None, Some(record)) => {
+            info!("resuming download");
+            let (record_info_hash, metainfo) = metainfo_from_str(record.metainfo.0).context("resume record error")?;
+            ensure!(record_info_hash == info_hash, "resume record info hash mismatch");
+            Some(metainfo)
+        }
+        _ => None,
+    };
+    let (dht_peers, node_id) = {
+        let mut p_state = p_state.lock().await;
+        (p_state.dht_nodes(), p_state.dht_node_id())
+    };
+    let mut dht = Dht::new(node_id.clone(), dht_peers, config.bind_address, config.dht_chunk);
+    let res = dht.get_peers(info_hash.to_vec(), config.dht_min_peers).await;
+    p_state.lock().await.update_dht_nodes(&dht.health);
+    let (peers, external_ip) = res?;
     info!("discovered {} dht peers", peers.len());
+    if let Some(ip) = external_ip {
+        p_state.lock().await.set_external_ip(ip);
+    }
 
-    let pieces = metainfo.as_ref().map(|m| init_pieces(&m.info));
-    let status = if metainfo.is_some() {
-        TorrentStatus::Downloading
-    } else {
-        TorrentStatus::Metainfo
+    let listener = bind_listener(config).await?;
+    let port = listener.local_addr()?.port();
+    info!("listening on port {}", port);
+
+    let budget = fd_budget(config);
+    // torrent completed in a previous run is already moved out of `incomplete_dir`
+    let moved = match (&config.incomplete_dir, &metainfo) {
+        (Some(dir), Some(metainfo)) => {
+            resumed
+                && !torrent_dir(dir, &metainfo.info).exists()
+                && torrent_dir(&config.download_dir, &metainfo.info).exists()
+        }
+        _ => false,
+    };
+    let config = &Config {
+        max_connections: budget.connections,
+        incomplete_dir: config.incomplete_dir.clone().filter(|_| !moved),
+        ..config.clone()
     };
-    let state = State {
+    let disk = Disk::new(config.read_cache_size, budget.files);
+    let mut pieces = metainfo
+        .as_ref()
+        .map(|m| init_pieces(&m.info))
+        .transpose()
+        .context("malformed metainfo")?;
+    if let (true, Some(metainfo), Some(pieces)) = (resumed, &metainfo, &mut pieces) {
+        let info = &metainfo.info;
+        let dir = config.data_dir();
+        let fast_resumed = match progress {
+            Some(progress) if progress.files == file_stamps(info, dir) => {
+                fast_resume(info, pieces, &progress.bitfield, &disk, dir, config.resume_spot_checks).await
+            }
+            Some(_) => {
+                info!("torrent files changed since the last run");
+                false
+            }
+            None => false,
+        };
+        if !fast_resumed {
+            check_pieces(info, pieces, &disk, dir).await;
+        }
+    }
+    let status = match &pieces {
+        Some(ps) if ps.values().all(|p| p.status == TorrentStatus::Saved) => TorrentStatus::Downloaded,
+        Some(_) if config.metainfo_only => TorrentStatus::Downloaded,
+        Some(_) => TorrentStatus::Downloading,
+        None => TorrentStatus::Metainfo,
+    };
+    let availability = pieces
+        .as_ref()
+        .map(|ps| Availability::new(ps.len()))
+        .unwrap_or_default();
+    let mut state = State {
         config: config.clone(),
         metainfo: metainfo.ok_or(MetainfoState::default()),
-        tracker_response: None,
+        tracker_states: BTreeMap::new(),
+        tracker_tiers: vec![],
         info_hash,
         peer_id: p_state.lock().await.peer_id.to_vec(),
+        port,
+        dht_node_id: node_id,
+        dht_nodes: BTreeSet::new(),
+        disk,
         pieces,
-        peers: peers.into_iter().map(|p| (p.clone(), Peer::new(p))).collect(),
+        peers: BTreeMap::new(),
         status,
+        external_ip,
+        stats: Stats::default(),
+        selector: config.piece_selection.selector(),
+        udp: udp.clone(),
+        registry: registry.clone(),
+        started,
+        web_seeds,
+        peer_tasks: PeerTasks::default(),
+        availability,
     };
+    if state.metainfo.is_ok() {
+        run_hook(config, HookEvent::Metainfo, HookEnv::new(&state, config.data_dir())).await;
+    }
+    state.add_peers(peers, PeerSource::Dht);
     let state = Arc::new(Mutex::new(state));
     trace!("init state: {:?}", state);
+    Ok((state, listener))
+}
 
+/// Run torrent download until it is complete and moved to the download directory
+async fn run_torrent(state: Arc<Mutex<State>>, listener: TcpListener, p_state: Arc<Mutex<PersistState>>) -> Result<()> {
     let peer_loop_h = spawn(peer_loop(state.clone()));
-    // TODO: DHT discover loop
+    let listen_loop_h = spawn(listen_loop(listener, state.clone()));
+    let dht_loop_h = spawn(dht_loop(state.clone(), p_state.clone()));
     let tracker_loop_h = spawn(tracker_loop(state.clone()));
+    let progress_loop_h = spawn(progress_loop(Torrent::new(state.clone()).await));
+    let resume_record_h = spawn(save_resume_record(state.clone(), p_state.clone()));
+    let webseed_loop_h = spawn(webseed_loop(state.clone()));
+    let scheduler_loop_h = spawn(scheduler_loop(state.clone()));
+    #[cfg(unix)]
+    let signal_loop_h = spawn(signal_loop(state.clone()));
     info!("connecting to peers");
     peer_loop_h.await??;
+    let _ = listen_loop_h.ensure_abort().await;
+    #[cfg(unix)]
+    let _ = signal_loop_h.ensure_abort().await;
+    let _ = dht_loop_h.ensure_abort().await;
     let _ = tracker_loop_h.ensure_abort().await;
+    let _ = progress_loop_h.ensure_abort().await;
+    let _ = resume_record_h.ensure_abort().await;
+    let _ = webseed_loop_h.ensure_abort().await;
+    let _ = scheduler_loop_h.ensure_abort().await;
 
     let state = state.lock().await;
-    debug!("verifying downloaded pieces");
-    ensure!(
-        state.pieces.as_ref().unwrap().len() == state.metainfo.as_ref().unwrap().info.pieces.len(),
-        "pieces length mismatch"
-    );
-    let incomplete = state
-        .pieces
-        .as_ref()
-        .unwrap()
-        .values()
-        .filter(|p| p.status != TorrentStatus::Saved)
-        .count();
-    if incomplete > 0 {
-        return Err(anyhow!("{} incomplete pieces", incomplete));
-    }
-
-    let mut dht_peers: BTreeSet<PeerInfo> = state
-        .peers
-        .values()
-        .filter(|p| p.dht_port.is_some())
-        .map(|p| PeerInfo {
-            ip: p.info.ip.clone(),
-            port: p.dht_port.unwrap(),
-        })
-        .collect();
-    debug!("discovered {} dht nodes: {:?}", dht_peers.len(), dht_peers);
-    p_state.lock().await.dht_peers.append(&mut dht_peers);
+    let config = &state.config;
+    let started = state.started;
+    if config.metainfo_only {
+        info!("metainfo is fetched");
+    } else {
+        debug!("verifying downloaded pieces");
+        let incomplete = state
+            .pieces
+            .as_ref()
+            .unwrap()
+            .values()
+            .filter(|p| p.status != TorrentStatus::Saved)
+            .count();
+        if incomplete > 0 {
+            return Err(anyhow!("{} incomplete pieces", incomplete));
+        }
+        create_empty_files(&state).await?;
+        move_completed(&state).await?;
+        run_hook(config, HookEvent::Complete, HookEnv::new(&state, &config.download_dir)).await;
+    }
+
+    let dht_nodes = state.dht_nodes.clone();
+    debug!("discovered {} dht nodes: {:?}", dht_nodes.len(), dht_nodes);
+    let mut p_state = p_state.lock().await;
+    p_state.seen_dht_nodes(dht_nodes);
+    if let Some(ip) = state.external_ip {
+        p_state.set_external_ip(ip);
+    }
 
     info!("done in {}s", started.elapsed().as_secs());
+    let summary = Summary::new(&state, started.elapsed());
+    if config.stats_json {
+        println!("{}", serde_json::to_string(&summary)?);
+    } else {
+        info!("summary:\n{}", summary);
+    }
     Ok(())
 }
 
+/// Handle to the running torrent, used to query its progress
+#[derive(Clone)]
+pub struct Torrent {
+    state: Arc<Mutex<State>>,
+    rate_sample: Arc<Mutex<RateSample>>,
+    /// Download task, if torrent is started with [start_torrent]
+    task: Arc<Mutex<Option<JoinHandle<Result<()>>>>>,
+}
+
+impl Torrent {
+    pub async fn new(state: Arc<Mutex<State>>) -> Torrent {
+        let rate_sample = RateSample::new(&*state.lock().await);
+        Torrent {
+            state,
+            rate_sample: Arc::new(Mutex::new(rate_sample)),
+            task: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub async fn metainfo(&self) -> Option<Metainfo> {
+        self.state.lock().await.metainfo.clone().ok()
+    }
+
+    /// Wait until the download is complete. Returns immediately if torrent is not started or is already awaited
+    pub async fn wait(&self) -> Result<()> {
+        let task = self.task.lock().await.take();
+        match task {
+            Some(task) => task.await?,
+            None => Ok(()),
+        }
+    }
+
+    /// Per-file progress
+    pub async fn files(&self) -> Vec<FileProgress> {
+        file_progress(&*self.state.lock().await)
+    }
+
+    /// Connected peers
+    pub async fn peers(&self) -> Vec<PeerProgress> {
+        peer_list(&*self.state.lock().await)
+    }
+
+    /// Add peer known by address and connect to it, e.g. on LAN or when trackers are down
+    pub async fn add_peer(&self, addr: SocketAddr) {
+        let peer = PeerInfo::from(addr);
+        {
+            let mut state = self.state.lock().await;
+            state.add_peers([peer.clone()], PeerSource::Manual);
+            if state.peer_tasks.is_live(&peer) {
+                return;
+            }
+        }
+        info!("connecting to added peer {}", addr);
+        connect_peer(peer, self.state.clone()).await;
+    }
+
+    /// Announce status of the trackers
+    pub async fn trackers(&self) -> Vec<TrackerProgress> {
+        tracker_list(&*self.state.lock().await)
+    }
+
+    /// State and availability of every piece
+    pub async fn pieces(&self) -> Vec<PieceProgress> {
+        piece_map(&*self.state.lock().await)
+    }
+
+    /// Torrent progress, rates are computed since the previous call
+    pub async fn progress(&self) -> Progress {
+        let state = self.state.lock().await;
+        let mut rate_sample = self.rate_sample.lock().await;
+        let progress = progress(&state, &rate_sample);
+        *rate_sample = RateSample::new(&state);
+        progress
+    }
+}
+
+/// Pieces per row of the logged piece map
+const PIECE_MAP_WIDTH: usize = 64;
+
+/// Periodically log torrent progress
+async fn progress_loop(torrent: Torrent) -> Result<()> {
+    let (wait, show_piece_map, show_peer_list) = {
+        let state = torrent.state.lock().await;
+        (
+            state.config.progress_wait,
+            state.config.piece_map,
+            state.config.peer_list,
+        )
+    };
+    loop {
+        sleep(wait).await;
+        let progress = torrent.progress().await;
+        info!(
+            "{:?}: {}/{} pieces, {:.1} KiB/s, {}/{} peers connected{}",
+            progress.state,
+            progress.pieces_completed,
+            progress.pieces_total,
+            progress.download_rate / 1024.,
+            progress.peers_connected,
+            progress.peers,
+            if progress.trackerless {
+                ", no trackers, DHT only"
+            } else {
+                ""
+            }
+        );
+        debug!("files: {:?}", torrent.files().await);
+        if show_peer_list {
+            info!("peers:\n{}", render_peer_list(&torrent.peers().await));
+        }
+        if show_piece_map {
+            info!(
+                "pieces:\n{}",
+                render_piece_map(&torrent.pieces().await, PIECE_MAP_WIDTH)
+            );
+        }
+    }
+}
+
+/// Stop requesting pieces and choke every peer, keeping peer state and progress
+pub async fn pause(state: Arc<Mutex<State>>) {
+    let (paused, announce_on_pause) = {
+        let mut state = state.lock().await;
+        (state.pause(), state.config.announce_on_pause)
+    };
+    if !paused {
+        debug!("torrent is not active, ignoring pause");
+        return;
+    }
+    info!("torrent is paused");
+    if announce_on_pause {
+        if let Err(e) = tracker_announce_event(state, TrackerEvent::Stopped).await {
+            debug!("{e:#}");
+        }
+    }
+}
+
+pub async fn resume(state: Arc<Mutex<State>>) {
+    let (resumed, announce_on_pause) = {
+        let mut state = state.lock().await;
+        (state.resume(), state.config.announce_on_pause)
+    };
+    if !resumed {
+        debug!("torrent is not paused, ignoring resume");
+        return;
+    }
+    info!("torrent is resumed");
+    if announce_on_pause {
+        if let Err(e) = tracker_announce_event(state, TrackerEvent::Started).await {
+            debug!("{e:#}");
+        }
+    }
+}
+
+/// Toggle torrent pause on `SIGUSR1`
+#[cfg(unix)]
+async fn signal_loop(state: Arc<Mutex<State>>) -> Result<()> {
+    let mut signal = signal(SignalKind::user_defined1())?;
+    loop {
+        signal.recv().await;
+        debug!("received SIGUSR1");
+        if state.lock().await.status == TorrentStatus::Paused {
+            resume(state.clone()).await;
+        } else {
+            pause(state.clone()).await;
+        }
+    }
+}
+
+/// Write piece, retrying with exponential backoff. If every attempt fails, piece is returned to the request pool
+/// keeping its blocks, so that write is retried again later
+pub async fn write_piece_retry(piece_idx: u32, state: Arc<Mutex<State>>) -> Result<()> {
+    let (retries, mut wait) = {
+        let config = &state.lock().await.config;
+        (config.write_retries, config.write_retry_wait)
+    };
+    let mut attempt = 0;
+    loop {
+        match write_piece(piece_idx, state.clone()).await {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt < retries => {
+                warn!("piece {} write error, retrying in {:?}: {e:#}", piece_idx, wait);
+                sleep(wait).await;
+                wait *= 2;
+                attempt += 1;
+            }
+            Err(e) => {
+                error!("storage error, unable to write piece {}: {e:#}", piece_idx);
+                let mut state = state.lock().await;
+                if let Some(p) = state.pieces.as_mut().unwrap().get_mut(&piece_idx) {
+                    p.status = TorrentStatus::Downloading;
+                    p.assigned = None;
+                }
+                // peers finish once every piece is downloaded, they are reconnected to download the piece again
+                for p in state.peers.values_mut().filter(|p| p.status == PeerStatus::Done) {
+                    p.status = PeerStatus::Disconnected;
+                }
+                return Err(e);
+            }
+        }
+    }
+}
+
 // TODO: initialize every file with `.part` suffix
 // if every file piece is written, remove suffix from the filename
 pub async fn write_piece(piece_idx: u32, state: Arc<Mutex<State>>) -> Result<()> {
-    let metainfo = {
+    let (metainfo, dir) = {
         let state = state.lock().await;
-        state.metainfo.clone()
+        (state.metainfo.clone(), state.config.data_dir().to_path_buf())
     };
     // TODO: drain data instead of cloning
     let piece = {
@@ -124,16 +498,16 @@ pub async fn write_piece(piece_idx: u32, state: Arc<Mutex<State>>) -> Result<()>
             .cloned()
             .unwrap()
     };
+    let (disk, verify_after_write) = {
+        let state = state.lock().await;
+        (state.disk.clone(), state.config.verify_after_write)
+    };
     debug!("writing piece: {:?}", piece.file_locations);
-    for f in piece.file_locations {
-        let path = PathBuf::from("download")
-            .join(&metainfo.as_ref().unwrap().info.name)
-            .join(
-                metainfo.as_ref().unwrap().info.file_info.files()[f.file_index]
-                    .path
-                    .clone(),
-            );
-        tokio::fs::create_dir_all(&path.parent().context("no parent")?).await?;
+    let info = &metainfo.as_ref().unwrap().info;
+    let mut locations = vec![];
+    for f in &piece.file_locations {
+        let file = info.file_info.files()[f.file_index];
+        let path = file_path(&dir, info, file);
         let data = piece
             .blocks
             .values()
@@ -142,23 +516,252 @@ pub async fn write_piece(piece_idx: u32, state: Arc<Mutex<State>>) -> Result<()>
             .take(f.length)
             .collect::<Vec<_>>();
         ensure!(data.len() == f.length);
-        trace!("witing {} bytes at {} of {}", data.len(), f.offset, path.display());
-        let mut file = File::options().create(true).write(true).open(path).await?;
-        file.seek(SeekFrom::Start(f.offset as u64)).await?;
-        file.write_all(&data).await?;
+        disk.write(&path, file.length, f.offset as u64, &data).await?;
+        locations.push((path, f.offset as u64, f.length));
+    }
+    if verify_after_write {
+        let written = disk.read_locations(&locations).await.context("read back error")?;
+        ensure!(
+            sha1::encode_blocking(written).await? == piece.hash.0,
+            "piece {} is corrupted on disk",
+            piece_idx
+        );
+        trace!("piece {} is verified on disk", piece_idx);
+    }
 
-        let mut state = state.lock().await;
-        let p = state.pieces.as_mut().unwrap().get_mut(&piece_idx).unwrap();
-        p.status = TorrentStatus::Saved;
-        p.blocks.clear();
+    let mut state = state.lock().await;
+    let p = state.pieces.as_mut().unwrap().get_mut(&piece_idx).unwrap();
+    p.status = TorrentStatus::Saved;
+    p.blocks.clear();
+    // last piece can be saved after every peer is disconnected, with no peer left to notice completion
+    if state.status == TorrentStatus::Downloading && all_saved(state.pieces.as_ref().unwrap()) {
+        info!("torrent is downloaded");
+        state.status = TorrentStatus::Downloaded;
     }
+    // freed piece buffer may allow new requests
+    state.notify_peers();
     Ok(())
 }
 
-pub fn metainfo_from_path(path: &Path) -> Result<(ByteString, Metainfo)> {
-    debug!("reading torrent file: {:?}", path);
-    let bencoded = fs::read(path).context("no metadata file")?;
-    metainfo_from_str(bencoded)
+/// Save resume record once metainfo is known, so that download can be continued after restart. Progress is
+/// snapshotted periodically afterwards
+async fn save_resume_record(state: Arc<Mutex<State>>, p_state: Arc<Mutex<PersistState>>) -> Result<()> {
+    loop {
+        let (info_hash, metainfo, dir, wait) = {
+            let state = state.lock().await;
+            (
+                state.info_hash.clone(),
+                state.metainfo.clone(),
+                state.config.data_dir().to_path_buf(),
+                state.config.downloaded_check_wait,
+            )
+        };
+        let metainfo = match metainfo {
+            Ok(metainfo) => metainfo,
+            _ => {
+                sleep(wait).await;
+                continue;
+            }
+        };
+        // bitfield is taken before stamps, so that pieces written in between can only cause stamp mismatch
+        let bitfield = state.lock().await.saved_bitfield();
+        let files = file_stamps(&metainfo.info, &dir);
+        {
+            let mut p_state = p_state.lock().await;
+            let record = ResumeRecord {
+                metainfo: metainfo.bencoded,
+                progress: bitfield.map(|bitfield| ResumeProgress { bitfield, files }),
+            };
+            let prev = p_state.torrents.insert(hex(&info_hash), record.clone());
+            if prev.is_none_or(|p| p.progress != record.progress) {
+                p_state.save()?;
+            }
+        }
+        sleep(state.lock().await.config.resume_save_wait).await;
+    }
+}
+
+/// Stamp of every torrent file, see [ResumeProgress]
+fn file_stamps(info: &Info, dir: &Path) -> Vec<Option<FileStamp>> {
+    info.file_info
+        .files()
+        .iter()
+        .map(|f| FileStamp::read(&file_path(dir, info, f)))
+        .collect()
+}
+
+/// Mark pieces of the saved `bitfield` as saved, re-hashing a random sample of `spot_checks` of them. Returns false
+/// leaving pieces untouched if bitfield is malformed or any sampled piece does not match its hash
+async fn fast_resume(
+    info: &Info,
+    pieces: &mut BTreeMap<u32, Piece>,
+    bitfield: &[u8],
+    disk: &Disk,
+    dir: &Path,
+    spot_checks: usize,
+) -> bool {
+    if validate_bitfield(bitfield, info.pieces.len()).is_err() {
+        debug!("malformed resume bitfield");
+        return false;
+    }
+    let saved = pieces
+        .keys()
+        .copied()
+        .filter(|i| bitfield[*i as usize / 8] & (0x80 >> (i % 8)) != 0)
+        .collect::<Vec<_>>();
+    let sample = saved
+        .choose_multiple(&mut thread_rng(), spot_checks)
+        .copied()
+        .collect::<Vec<_>>();
+    info!("spot checking {} of {} saved pieces", sample.len(), saved.len());
+    let mut data = vec![];
+    for i in &sample {
+        match read_piece_data(info, &pieces[i], disk, dir).await {
+            Some(d) => data.push(d),
+            None => {
+                info!("piece {} is missing, falling back to full check", i);
+                return false;
+            }
+        }
+    }
+    let hashes = match spawn_blocking(move || sha1::encode_batch(&data)).await {
+        Ok(hashes) => hashes,
+        Err(e) => {
+            warn!("piece check error: {e:#}");
+            return false;
+        }
+    };
+    if let Some((i, _)) = sample.iter().zip(hashes).find(|(i, h)| *h != pieces[i].hash.0) {
+        info!("piece {} hash mismatch, falling back to full check", i);
+        return false;
+    }
+    for i in &saved {
+        pieces.get_mut(i).unwrap().status = TorrentStatus::Saved;
+    }
+    info!("{}/{} pieces are already downloaded", saved.len(), pieces.len());
+    true
+}
+
+/// Number of pieces read from disk before hashing them in parallel
+const CHECK_BATCH: usize = 64;
+
+/// Hash read pieces in parallel, marking the matching ones as saved
+async fn check_batch(batch: Vec<(&mut Piece, Vec<u8>)>) {
+    let (pieces, data): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+    let hashes = match spawn_blocking(move || sha1::encode_batch(&data)).await {
+        Ok(hashes) => hashes,
+        Err(e) => {
+            warn!("piece check error: {e:#}");
+            return;
+        }
+    };
+    for (piece, hash) in pieces.into_iter().zip(hashes) {
+        if hash == piece.hash.0 {
+            piece.status = TorrentStatus::Saved;
+        }
+    }
+}
+
+/// Read piece data from torrent files, `None` if any part of it is missing
+async fn read_piece_data(info: &Info, piece: &Piece, disk: &Disk, dir: &Path) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(piece.length as usize);
+    for f in &piece.file_locations {
+        let file = info.file_info.files()[f.file_index];
+        data.extend(
+            disk.read(&file_path(dir, info, file), f.offset as u64, f.length)
+                .await
+                .ok()?,
+        );
+    }
+    (data.len() == piece.length as usize).then_some(data)
+}
+
+/// Mark pieces that are already written to disk by the previous download as saved
+async fn check_pieces(info: &Info, pieces: &mut BTreeMap<u32, Piece>, disk: &Disk, dir: &Path) {
+    info!("checking downloaded pieces");
+    let mut batch = vec![];
+    for piece in pieces.values_mut() {
+        if let Some(data) = read_piece_data(info, piece, disk, dir).await {
+            batch.push((piece, data));
+        }
+        if batch.len() >= CHECK_BATCH {
+            check_batch(std::mem::take(&mut batch)).await;
+        }
+    }
+    check_batch(batch).await;
+    info!(
+        "{}/{} pieces are already downloaded",
+        pieces.values().filter(|p| p.status == TorrentStatus::Saved).count(),
+        pieces.len()
+    );
+}
+
+/// Directory of the torrent files within the directory
+pub fn torrent_dir(dir: &Path, info: &Info) -> PathBuf {
+    dir.join(safe_component(&info.name))
+}
+
+/// Path of the torrent file within the directory. Names from metainfo are made safe to write on this platform
+pub fn file_path(dir: &Path, info: &Info, file: &PathInfo) -> PathBuf {
+    torrent_dir(dir, info).join(safe_path(&file.path))
+}
+
+/// Create zero-length files, since no piece maps to them
+async fn create_empty_files(state: &State) -> Result<()> {
+    let info = &state.metainfo.as_ref().map_err(|_| anyhow!("no metainfo"))?.info;
+    for file in info.file_info.files().into_iter().filter(|f| f.length == 0) {
+        let path = file_path(state.config.data_dir(), info, file);
+        debug!("creating empty file: {}", path.display());
+        state.disk.create_empty(&path).await?;
+    }
+    Ok(())
+}
+
+/// Move files of the verified torrent from `incomplete_dir` to `download_dir`
+async fn move_completed(state: &State) -> Result<()> {
+    let incomplete_dir = match &state.config.incomplete_dir {
+        Some(dir) if !same_path(dir, &state.config.download_dir) => dir,
+        _ => return Ok(()),
+    };
+    let info = &state.metainfo.as_ref().map_err(|_| anyhow!("no metainfo"))?.info;
+    info!("moving torrent to {}", state.config.download_dir.display());
+    for file in info.file_info.files() {
+        let from = file_path(incomplete_dir, info, file);
+        let to = file_path(&state.config.download_dir, info, file);
+        debug!("moving {} to {}", from.display(), to.display());
+        state.disk.move_file(&from, &to).await?;
+    }
+    remove_empty_dirs(&torrent_dir(incomplete_dir, info)).await;
+    Ok(())
+}
+
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Remove the directory and its subdirectories that are left empty, files are never removed
+async fn remove_empty_dirs(dir: &Path) {
+    let mut dirs = vec![dir.to_path_buf()];
+    let mut i = 0;
+    while let Some(d) = dirs.get(i).cloned() {
+        if let Ok(mut entries) = tokio::fs::read_dir(&d).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                if entry.file_type().await.is_ok_and(|t| t.is_dir()) {
+                    dirs.push(entry.path());
+                }
+            }
+        }
+        i += 1;
+    }
+    // deepest first, non-empty ones fail to be removed
+    for d in dirs.iter().rev() {
+        if let Err(e) = tokio::fs::remove_dir(d).await {
+            debug!("not removing {}: {e}", d.display());
+        }
+    }
 }
 
 pub fn metainfo_from_str(bencoded: ByteString) -> Result<(ByteString, Metainfo)> {
@@ -182,3 +785,23 @@ pub fn get_info_hash(value: &BencodeValue) -> Result<ByteString> {
         Err(anyhow!("value is not a dict"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn should_remove_only_empty_dirs() {
+        let dir = std::env::temp_dir().join(format!("biter-test-{}", hex(&thread_rng().gen::<[u8; 8]>())));
+        std::fs::create_dir_all(dir.join("a/b")).unwrap();
+        std::fs::create_dir_all(dir.join("c")).unwrap();
+        std::fs::write(dir.join("c/file"), b"data").unwrap();
+        remove_empty_dirs(&dir).await;
+        assert!(!dir.join("a").exists());
+        assert_eq!(std::fs::read(dir.join("c/file")).unwrap(), b"data");
+        assert!(same_path(&dir.join("c/.."), &dir));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,15 +1,22 @@
 use core::fmt;
-use std::{collections::BTreeSet, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Context, Error, Result};
-use reqwest::Client;
-use tokio::{spawn, sync::Mutex, time::sleep};
+use rand::{seq::SliceRandom, thread_rng};
+use reqwest::{Client, Url};
+use tokio::{net::lookup_host, spawn, sync::Mutex, time::sleep};
 use urlencoding::encode_binary;
 
 use crate::{
     bencode::{parse_bencoded, BencodeValue},
-    state::{Peer, PeerInfo, PeerStatus, State},
-    tracker_udp::tracker_request_udp,
+    state::{PeerInfo, PeerStatus, State},
+    stats::PeerSource,
+    tracker_udp::{tracker_request_udp, UdpService},
     types::ByteString,
 };
 
@@ -23,7 +30,10 @@ pub struct TrackerRequest {
     pub compact: u64,
     pub no_peer_id: u64,
     pub event: Option<TrackerEvent>,
-    pub ip: Option<ByteString>,
+    /// Our IPv4 address, for trackers contacted over IPv6
+    pub ip: Option<Ipv4Addr>,
+    /// Our IPv6 address, for trackers contacted over IPv4
+    pub ipv6: Option<Ipv6Addr>,
     pub numwant: Option<i64>,
     pub key: Option<ByteString>,
     pub tracker_id: Option<ByteString>,
@@ -52,8 +62,8 @@ impl TrackerRequest {
             // TODO: no_peer_id
             no_peer_id: 0,
             event,
-            // TODO
             ip: None,
+            ipv6: None,
             numwant: None,
             key: None,
             tracker_id,
@@ -75,6 +85,12 @@ impl TrackerRequest {
         if let Some(event) = &self.event {
             params.push(("event", event.to_string().into()));
         }
+        if let Some(ip) = &self.ip {
+            params.push(("ip", ip.to_string().into()));
+        }
+        if let Some(ipv6) = &self.ipv6 {
+            params.push(("ipv6", ipv6.to_string().into()));
+        }
 
         params
             .iter()
@@ -84,7 +100,7 @@ impl TrackerRequest {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, PartialEq, PartialOrd)]
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum TrackerEvent {
     Started,
     Stopped,
@@ -116,36 +132,56 @@ impl TryFrom<BencodeValue> for TrackerResponse {
             BencodeValue::Dict(d) => d,
             _ => return Err(anyhow!("response is not a dict")),
         };
-        let peers = match dict.get("peers") {
+        // failure response has no other keys
+        if let Some(reason) = dict.get("failure reason") {
+            return match reason {
+                BencodeValue::String(s) => Ok(TrackerResponse::Failure {
+                    failure_reason: String::from_utf8_lossy(s).into(),
+                }),
+                _ => Err(anyhow!("'failure reason' is not a string")),
+            };
+        }
+        let mut compact_peers = BTreeSet::new();
+        let addrs = match dict.get("peers") {
+            Some(BencodeValue::String(ps)) => {
+                compact_peers.extend(parse_compact_peers(ps, 6)?);
+                vec![]
+            }
             Some(BencodeValue::List(ps)) => ps
                 .iter()
                 .map(|p| match p {
-                    BencodeValue::Dict(p_dict) => Ok(PeerInfo {
-                        ip: match p_dict.get("ip") {
+                    BencodeValue::Dict(p_dict) => Ok((
+                        match p_dict.get("ip") {
                             Some(BencodeValue::String(i)) => String::from_utf8(i.clone())?,
                             _ => return Err(anyhow!("'ip' missing")),
                         },
-                        port: match p_dict.get("port") {
+                        match p_dict.get("port") {
                             Some(BencodeValue::Int(p)) => *p as u16,
                             _ => return Err(anyhow!("'port' missing")),
                         },
-                    }),
+                    )),
                     _ => Err(anyhow!("'peers' missing")),
                 })
-                .collect::<Result<_, _>>()?,
+                .collect::<Result<Vec<_>, _>>()?,
             _ => return Err(anyhow!("'peers' missing")),
         };
+        if let Some(BencodeValue::String(ps)) = dict.get("peers6") {
+            compact_peers.extend(parse_compact_peers(ps, 18)?);
+        }
+        let (mut peers, peer_hosts) = split_peer_hosts(addrs);
+        peers.extend(compact_peers);
         let resp = TrackerResponse::Success(TrackerResponseSuccess {
             peers,
+            peer_hosts,
             interval: match dict.get("interval") {
                 Some(BencodeValue::Int(p)) => *p,
                 _ => return Err(anyhow!("'interval' missing")),
             },
-            warning_message: dict.get("warning_message").and_then(|m| match m {
+            warning_message: dict.get("warning message").and_then(|m| match m {
                 BencodeValue::String(s) => Some(String::from_utf8_lossy(s).into()),
                 _ => None,
             }),
-            min_interval: dict.get("min_interval").and_then(|m| match m {
+            min_interval: dict.get("min interval").and_then(|m| match m {
                 BencodeValue::Int(i) => Some(*i),
                 _ => None,
             }),
@@ -169,6 +205,8 @@ impl TryFrom<BencodeValue> for TrackerResponse {
 #[derive(Clone, Debug, Default, PartialEq, PartialOrd, Hash)]
 pub struct TrackerResponseSuccess {
     pub peers: BTreeSet<PeerInfo>,
+    /// Peers specified by hostname instead of ip address, need to be resolved
+    pub peer_hosts: BTreeSet<(String, u16)>,
     pub interval: i64,
     pub warning_message: Option<String>,
     pub min_interval: Option<i64>,
@@ -177,29 +215,146 @@ pub struct TrackerResponseSuccess {
     pub incomplete: Option<i64>,
 }
 
-pub async fn tracker_request(announce: String, request: TrackerRequest) -> Result<TrackerResponse> {
+/// Floor of the announce interval, protecting trackers from too frequent announces
+const MIN_INTERVAL: i64 = 60;
+
+/// Wait before the next announce after failed one
+const RETRY_WAIT: Duration = Duration::from_secs(60);
+
+/// Announce status of the tracker
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrackerState {
+    pub last_announce: Option<Instant>,
+    /// Announce interval in seconds
+    pub interval: Option<i64>,
+    pub min_interval: Option<i64>,
+    /// Sent back to the tracker in the next announces
+    pub tracker_id: Option<ByteString>,
+    /// Number of seeders (`complete`) and leechers (`incomplete`) as reported by the tracker
+    pub seeders: Option<i64>,
+    pub leechers: Option<i64>,
+    /// Failure reason or request error of the last announce
+    pub last_error: Option<String>,
+}
+
+impl TrackerState {
+    /// Record announce result
+    pub fn update(&mut self, res: &Result<TrackerResponse>) {
+        self.last_announce = Some(Instant::now());
+        match res {
+            Ok(TrackerResponse::Success(resp)) => {
+                self.interval = Some(resp.interval);
+                self.min_interval = resp.min_interval;
+                // tracker id is kept until the tracker sends a new one
+                if resp.tracker_id.is_some() {
+                    self.tracker_id = resp.tracker_id.clone();
+                }
+                self.seeders = resp.complete;
+                self.leechers = resp.incomplete;
+                self.last_error = None;
+            }
+            Ok(TrackerResponse::Failure { failure_reason }) => self.last_error = Some(failure_reason.clone()),
+            Err(e) => self.last_error = Some(format!("{e:#}")),
+        }
+    }
+
+    /// Wait until the next announce: tracker interval after successful announce, [RETRY_WAIT] otherwise
+    pub fn announce_wait(&self) -> Duration {
+        match self.interval {
+            Some(interval) if self.last_error.is_none() => {
+                let interval = interval.max(self.min_interval.unwrap_or_default()).max(MIN_INTERVAL);
+                Duration::from_secs(interval as u64)
+            }
+            _ => RETRY_WAIT,
+        }
+    }
+}
+
+/// Parse compact peer list, where each peer takes `size` bytes: 6 for `peers`, 18 for `peers6`
+fn parse_compact_peers(bytes: &[u8], size: usize) -> Result<Vec<PeerInfo>> {
+    if !bytes.len().is_multiple_of(size) {
+        return Err(anyhow!("compact peers length is not a multiple of {}", size));
+    }
+    bytes.chunks(size).map(PeerInfo::try_from).collect()
+}
+
+/// Our global IPv6 address, if the host has one and it is allowed by the bind address.
+/// Determined by the source address the OS picks to reach a public IPv6 host, no packets are sent
+pub fn local_ipv6(bind_address: Option<IpAddr>) -> Option<Ipv6Addr> {
+    let bind_ip = match bind_address {
+        None => Ipv6Addr::UNSPECIFIED,
+        Some(IpAddr::V6(ip)) => ip,
+        Some(IpAddr::V4(_)) => return None,
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(bind_ip.into(), 0)).ok()?;
+    socket.connect("[2001:4860:4860::8888]:53").ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if is_global_ipv6(&ip) => Some(ip),
+        _ => None,
+    }
+}
+
+fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_unicast_link_local()
+        || ip.is_unique_local()
+        || ip.to_ipv4_mapped().is_some())
+}
+
+/// Split peer addresses into peers with ip address and peers with hostname
+fn split_peer_hosts(addrs: Vec<(String, u16)>) -> (BTreeSet<PeerInfo>, BTreeSet<(String, u16)>) {
+    let mut peers = BTreeSet::new();
+    let mut peer_hosts = BTreeSet::new();
+    for (host, port) in addrs {
+        match host.parse::<IpAddr>() {
+            Ok(ip) => {
+                peers.insert(PeerInfo::from(SocketAddr::new(ip, port)));
+            }
+            Err(_) => {
+                peer_hosts.insert((host, port));
+            }
+        }
+    }
+    (peers, peer_hosts)
+}
+
+/// Resolve peer hostnames, skipping ones that fail to resolve
+pub async fn resolve_peer_hosts(peer_hosts: &BTreeSet<(String, u16)>) -> BTreeSet<PeerInfo> {
+    let mut peers = BTreeSet::new();
+    for (host, port) in peer_hosts {
+        match lookup_host((host.as_str(), *port)).await {
+            Ok(addrs) => peers.extend(addrs.map(PeerInfo::from)),
+            Err(e) => debug!("unable to resolve peer {}: {}", host, e),
+        }
+    }
+    peers
+}
+
+pub async fn tracker_request(
+    announce: String,
+    request: TrackerRequest,
+    bind_address: Option<IpAddr>,
+    udp: &UdpService,
+) -> Result<TrackerResponse> {
     if announce.starts_with("http") {
-        tracker_request_http(announce, request).await
+        tracker_request_http(announce, request, bind_address).await
     } else if announce.starts_with("udp") {
-        tracker_request_udp(announce, request).await
+        tracker_request_udp(announce, request, bind_address, udp).await
     } else {
         Err(anyhow!("unsupported tracker url scheme: {}", announce))
     }
 }
 
-pub async fn tracker_request_http(announce: String, request: TrackerRequest) -> Result<TrackerResponse> {
-    let params = format!(
-        "?{}",
-        request
-            .to_params()
-            .into_iter()
-            .map(|(k, v)| format!("{k}={v}"))
-            .collect::<Vec<_>>()
-            .join("&")
-    );
-    let url = format!("{announce}{params}");
+pub async fn tracker_request_http(
+    announce: String,
+    request: TrackerRequest,
+    bind_address: Option<IpAddr>,
+) -> Result<TrackerResponse> {
+    let url = announce_url(&announce, &request)?;
     debug!("url: {url}");
-    let resp = spawn(Client::new().get(url).send())
+    let client = Client::builder().local_address(bind_address).build()?;
+    let resp = spawn(client.get(url).send())
         .await?
         .context("request error")?
         .bytes()
@@ -211,64 +366,303 @@ pub async fn tracker_request_http(announce: String, request: TrackerRequest) ->
     TrackerResponse::try_from(resp_dict)
 }
 
+/// Append request params to the announce url, keeping its existing query params (e.g. passkey).
+/// Params are percent-encoded manually, since info hash and peer id are not valid UTF-8
+fn announce_url(announce: &str, request: &TrackerRequest) -> Result<Url> {
+    let mut url = Url::parse(announce).context("invalid announce url")?;
+    let params = request
+        .to_params()
+        .into_iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join("&");
+    let query = match url.query() {
+        Some(q) if !q.is_empty() => format!("{q}&{params}"),
+        _ => params,
+    };
+    url.set_query(Some(&query));
+    Ok(url)
+}
+
+/// Announce to the tracker, recording the result in its tracker state
+async fn announce(state: &Arc<Mutex<State>>, announce: &str, event: Option<TrackerEvent>) -> Result<TrackerResponse> {
+    let (info_hash, peer_id, port, tracker_id, bind_address, udp, external_ip, left) = {
+        let state = state.lock().await;
+        (
+            state.info_hash.clone(),
+            state.peer_id.clone(),
+            state.port,
+            state.tracker_states.get(announce).and_then(|t| t.tracker_id.clone()),
+            state.config.bind_address,
+            state.udp.clone(),
+            state.external_ip,
+            state.left(),
+        )
+    };
+    let mut request = TrackerRequest::new(info_hash, peer_id, port, event, tracker_id);
+    // announce both address families, so that tracker can hand us out to IPv4 and IPv6 peers
+    request.ip = external_ip;
+    request.ipv6 = local_ipv6(bind_address);
+    request.left = left;
+    let res = tracker_request(announce.to_string(), request, bind_address, &udp)
+        .await
+        .context("request failed");
+    state
+        .lock()
+        .await
+        .tracker_states
+        .entry(announce.to_string())
+        .or_default()
+        .update(&res);
+    res
+}
+
+/// Announce to trackers tier by tier until one of them responds successfully, see
+/// [BEP-12](https://www.bittorrent.org/beps/bep_0012.html). Trackers of every tier are shuffled once metainfo is known,
+/// responding tracker is moved to the front of its tier, so that it is announced to first next time.
+/// Returns the responding tracker with its response
+pub async fn announce_tiers(
+    state: &Arc<Mutex<State>>,
+    event: Option<TrackerEvent>,
+) -> Option<(String, TrackerResponseSuccess)> {
+    let tiers = {
+        let mut state = state.lock().await;
+        if state.tracker_tiers.is_empty() {
+            let mut tiers = state.metainfo.as_ref().map(|m| m.tracker_tiers()).unwrap_or_default();
+            tiers.iter_mut().for_each(|tier| tier.shuffle(&mut thread_rng()));
+            state.tracker_tiers = tiers;
+        }
+        state.tracker_tiers.clone()
+    };
+    for (tier_idx, tier) in tiers.iter().enumerate() {
+        for (idx, url) in tier.iter().enumerate() {
+            debug!("announcing to {}", url);
+            match announce(state, url, event.clone()).await {
+                Ok(TrackerResponse::Success(resp)) => {
+                    if let Some(tier) = state.lock().await.tracker_tiers.get_mut(tier_idx) {
+                        tier[..=idx].rotate_right(1);
+                    }
+                    return Some((url.clone(), resp));
+                }
+                Ok(TrackerResponse::Failure { failure_reason }) => {
+                    debug!("tracker {} failure: {}", url, failure_reason)
+                }
+                Err(e) => debug!("tracker {}: {e:#}", url),
+            }
+        }
+    }
+    None
+}
+
+/// Announce event to the trackers outside of the regular announce interval
+pub async fn tracker_announce_event(state: Arc<Mutex<State>>, event: TrackerEvent) -> Result<()> {
+    if state.lock().await.metainfo.as_ref().map_or(true, |m| !m.has_trackers()) {
+        debug!("no tracker to announce event {} to", event);
+        return Ok(());
+    }
+    debug!("announcing event {}", event);
+    match announce_tiers(&state, Some(event.clone())).await {
+        Some((url, _)) => {
+            debug!("event {} is announced to {}", event, url);
+            Ok(())
+        }
+        None => Err(anyhow!("no tracker accepted event {}", event)),
+    }
+}
+
+/// Announce to the trackers every interval. Returns once metainfo turns out to have no trackers
 pub async fn tracker_loop(state: Arc<Mutex<State>>) {
     loop {
-        if let (Some(announce), info_hash, peer_id, port, Some(tracker_id), Some(tracker_timeout)) = {
+        let (has_trackers, resolve_peer_hosts_enabled) = {
             let state = state.lock().await;
             (
-                state.metainfo.clone().ok().and_then(|m| m.announce),
-                state.info_hash.clone(),
-                state.peer_id.clone(),
-                state.config.port,
-                state.tracker_response.as_ref().map(|r| r.tracker_id.clone()),
-                state.tracker_response.as_ref().map(|r| r.interval),
-            )
-        } {
-            let tracker_response = tracker_request(
-                announce,
-                TrackerRequest::new(info_hash, peer_id, port, None, tracker_id),
+                state.metainfo.as_ref().map(|m| m.has_trackers()).map_err(|_| ()),
+                state.config.resolve_peer_hosts,
             )
-            .await
-            .context("request failed");
-            info!("tracker response: {tracker_response:?}");
+        };
+        match has_trackers {
+            Ok(false) => {
+                info!("torrent has no trackers, discovering peers with DHT");
+                return;
+            }
+            Err(_) => {
+                let timeout = Duration::from_secs(10);
+                debug!("tracker not available, timeout is {:?}", timeout);
+                sleep(timeout).await;
+                continue;
+            }
+            Ok(true) => {}
+        }
 
-            // TODO: in case of error, try trackers from announce-list
-            match tracker_response {
-                Ok(TrackerResponse::Success(resp)) => {
-                    let mut state = state.lock().await;
-                    let new_peers: Vec<_> = resp
+        let wait = match announce_tiers(&state, None).await {
+            Some((url, resp)) => {
+                info!("tracker {} response: {:?}", url, resp);
+                let resolved = if resolve_peer_hosts_enabled && !resp.peer_hosts.is_empty() {
+                    resolve_peer_hosts(&resp.peer_hosts).await
+                } else {
+                    BTreeSet::new()
+                };
+                let mut state = state.lock().await;
+                let new_peers = state.add_peers(resp.peers.into_iter().chain(resolved), PeerSource::Tracker);
+                info!("received {} new peers", new_peers);
+                info!(
+                    "total {} peers, {} connected",
+                    state.peers.len(),
+                    state
                         .peers
-                        .into_iter()
-                        .filter(|p| !state.peers.contains_key(p))
-                        .map(Peer::new)
-                        .collect();
-                    info!("received {} new peers", new_peers.len());
-                    for p in new_peers {
-                        state.peers.insert(p.info.clone(), p);
-                    }
-                    info!(
-                        "total {} peers, {} connected",
-                        state.peers.len(),
-                        state
-                            .peers
-                            .values()
-                            .filter(|p| p.status == PeerStatus::Connected)
-                            .count()
-                    );
-                }
-                Ok(TrackerResponse::Failure { failure_reason }) => {
-                    debug!("tracker failure: {}", failure_reason);
-                }
-                Err(e) => {
-                    debug!("{e:#}");
-                }
-            };
-            debug!("tracker timeout is {:?}", tracker_timeout);
-            sleep(Duration::from_secs(tracker_timeout as u64)).await;
-        } else {
-            let timeout = Duration::from_secs(10);
-            debug!("tracker not available, timeout is {:?}", timeout);
-            sleep(timeout).await;
+                        .values()
+                        .filter(|p| p.status == PeerStatus::Connected)
+                        .count()
+                );
+                state.tracker_states.get(&url).map_or(RETRY_WAIT, |t| t.announce_wait())
+            }
+            None => {
+                info!("no tracker responded");
+                RETRY_WAIT
+            }
+        };
+        debug!("next announce in {:?}", wait);
+        sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_update_tracker_state() {
+        let mut tracker = TrackerState::default();
+        assert_eq!(tracker.announce_wait(), RETRY_WAIT);
+        tracker.update(&Ok(TrackerResponse::Success(TrackerResponseSuccess {
+            interval: 1800,
+            tracker_id: Some(b"id".to_vec()),
+            complete: Some(5),
+            incomplete: Some(2),
+            ..Default::default()
+        })));
+        assert_eq!(tracker.announce_wait(), Duration::from_secs(1800));
+        assert_eq!((tracker.seeders, tracker.leechers), (Some(5), Some(2)));
+
+        tracker.update(&Ok(TrackerResponse::Success(TrackerResponseSuccess {
+            interval: 1,
+            ..Default::default()
+        })));
+        assert_eq!(tracker.tracker_id, Some(b"id".to_vec()));
+        assert_eq!(tracker.announce_wait(), Duration::from_secs(MIN_INTERVAL as u64));
+
+        tracker.update(&Ok(TrackerResponse::Failure {
+            failure_reason: "unregistered torrent".into(),
+        }));
+        assert_eq!(tracker.last_error.as_deref(), Some("unregistered torrent"));
+        assert_eq!(tracker.announce_wait(), RETRY_WAIT);
+        assert!(tracker.last_announce.is_some());
+    }
+
+    #[test]
+    fn should_parse_http_response() {
+        let parse = |s: &str| TrackerResponse::try_from(parse_bencoded(s.as_bytes().to_vec()).0.unwrap());
+
+        match parse("d14:failure reason20:unregistered torrente").unwrap() {
+            TrackerResponse::Failure { failure_reason } => assert_eq!(failure_reason, "unregistered torrent"),
+            r => panic!("unexpected response {:?}", r),
+        }
+
+        let resp = "d8:intervali1800e12:min intervali60e15:warning message4:slow5:peers6:\x01\x02\x03\x04\x1a!e";
+        match parse(resp).unwrap() {
+            TrackerResponse::Success(resp) => {
+                assert_eq!(resp.interval, 1800);
+                assert_eq!(resp.min_interval, Some(60));
+                assert_eq!(resp.warning_message.as_deref(), Some("slow"));
+                assert_eq!(
+                    resp.peers,
+                    [PeerInfo::from(SocketAddr::from(([1, 2, 3, 4], 0x1a21)))].into()
+                );
+            }
+            r => panic!("unexpected response {:?}", r),
         }
+
+        let mut tracker = TrackerState::default();
+        tracker.update(&parse("d14:failure reason20:unregistered torrente"));
+        assert_eq!(tracker.last_error.as_deref(), Some("unregistered torrent"));
+    }
+
+    #[test]
+    fn should_keep_announce_query() {
+        let request = TrackerRequest::new(vec![0xff, b'a'], b"-ER0000-".to_vec(), 6881, None, None);
+        let url = announce_url("http://tracker.org/announce.php?passkey=x", &request).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "http://tracker.org/announce.php?passkey=x&info_hash=%FFa&peer_id=-ER0000-&port=6881&uploaded=0\
+            &downloaded=0&left=0&compact=0&no_peer_id=0"
+        );
+    }
+
+    #[test]
+    fn should_build_announce_query() {
+        let request = TrackerRequest::new(vec![1], vec![2], 6881, Some(TrackerEvent::Started), None);
+        let url = announce_url("http://tracker.org/announce", &request).unwrap();
+        assert_eq!(
+            url.query(),
+            Some("info_hash=%01&peer_id=%02&port=6881&uploaded=0&downloaded=0&left=0&compact=0&no_peer_id=0&event=started")
+        );
+    }
+
+    #[test]
+    fn should_add_announce_addresses() {
+        let mut request = TrackerRequest::new(vec![1], vec![2], 6881, None, None);
+        request.ip = Some([1, 2, 3, 4].into());
+        request.ipv6 = Some("2001:db8::1".parse().unwrap());
+        let url = announce_url("http://tracker.org/announce", &request).unwrap();
+        assert!(url.query().unwrap().ends_with("&ip=1.2.3.4&ipv6=2001%3Adb8%3A%3A1"));
+    }
+
+    #[test]
+    fn should_merge_compact_peers() {
+        let mut peers6 = vec![0x20, 0x01, 0x0d, 0xb8];
+        peers6.extend([0; 11]);
+        peers6.extend([1, 0x1a, 0xe1]);
+        let resp = BencodeValue::Dict(
+            [
+                ("interval".into(), BencodeValue::Int(60)),
+                ("peers".into(), BencodeValue::String(vec![1, 2, 3, 4, 0x1a, 0xe1])),
+                ("peers6".into(), BencodeValue::String(peers6)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let peers = match TrackerResponse::try_from(resp).unwrap() {
+            TrackerResponse::Success(r) => r.peers,
+            r => panic!("unexpected response {:?}", r),
+        };
+        assert_eq!(
+            peers.into_iter().map(|p| p.to_addr().to_string()).collect::<Vec<_>>(),
+            vec!["1.2.3.4:6881", "[2001:db8::1]:6881"]
+        );
+    }
+
+    #[test]
+    fn should_reject_truncated_compact_peers() {
+        assert!(parse_compact_peers(&[1, 2, 3, 4, 0x1a], 6).is_err());
+    }
+
+    #[test]
+    fn should_split_peer_hosts() {
+        let (peers, hosts) = split_peer_hosts(vec![
+            ("1.2.3.4".into(), 6881),
+            ("::ffff:1.2.3.4".into(), 6881),
+            ("peer.example.org".into(), 6882),
+        ]);
+        assert_eq!(
+            peers.into_iter().collect::<Vec<_>>(),
+            vec![PeerInfo {
+                ip: [1, 2, 3, 4].into(),
+                port: 6881
+            }]
+        );
+        assert_eq!(
+            hosts.into_iter().collect::<Vec<_>>(),
+            vec![("peer.example.org".into(), 6882)]
+        );
     }
 }
@@ -1,41 +1,256 @@
-use anyhow::{ensure, Result};
+use core::fmt;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, ensure, Context, Result};
 use rand::{thread_rng, Rng};
 use reqwest::Url;
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    spawn,
+    sync::{oneshot, Mutex, OnceCell},
+    time::{sleep, timeout},
+};
 
 use crate::{
     hex::hex,
     state::PeerInfo,
     tracker::{TrackerEvent, TrackerRequest, TrackerResponse, TrackerResponseSuccess},
-    udp::send_udp,
 };
 
-pub async fn tracker_request_udp(announce: String, request: TrackerRequest) -> Result<TrackerResponse> {
-    fn i32_from_slice(slice: &[u8]) -> Result<i32> {
-        Ok(i32::from_be_bytes(slice.try_into()?))
+/// How long connection id is valid after it is received, see [BEP-15](https://www.bittorrent.org/beps/bep_0015.html)
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+
+/// How long to wait for the tracker response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Wait after the first socket receive error, doubled with every consecutive error up to [MAX_RECV_ERROR_WAIT]
+const RECV_ERROR_WAIT: Duration = Duration::from_millis(100);
+const MAX_RECV_ERROR_WAIT: Duration = Duration::from_secs(10);
+
+const ACTION_CONNECT: i32 = 0;
+const ACTION_ANNOUNCE: i32 = 1;
+const ACTION_ERROR: i32 = 3;
+
+/// Connection id of the tracker <connection id, time it is received>, locked while connect handshake is in progress
+type ConnectionId = Arc<Mutex<Option<(i64, Instant)>>>;
+
+/// Requests waiting for response <transaction id> -> <tracker address, response sender>
+type Pending = Arc<Mutex<BTreeMap<i32, (SocketAddr, oneshot::Sender<Vec<u8>>)>>>;
+
+/// UDP tracker client shared by torrents of the session. Requests to every tracker are sent from one socket without
+/// waiting for each other, responses are matched to requests by transaction id and tracker address, so that packets
+/// from other sources are ignored. Connection id of every tracker is
+/// cached, so that concurrent announces to the same tracker share one connect handshake
+#[derive(Clone, Default)]
+pub struct UdpService {
+    socket: Arc<OnceCell<Arc<UdpSocket>>>,
+    pending: Pending,
+    conn_ids: Arc<Mutex<BTreeMap<String, ConnectionId>>>,
+}
+
+impl UdpService {
+    /// Socket bound on the first request, receiving responses in background
+    async fn socket(&self, bind_address: Option<IpAddr>) -> Result<Arc<UdpSocket>> {
+        self.socket
+            .get_or_try_init(|| async {
+                let local_addr = SocketAddr::new(bind_address.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), 0);
+                let socket = UdpSocket::bind(local_addr)
+                    .await
+                    .with_context(|| format!("unable to bind to {}", local_addr))?;
+                let socket = Arc::new(socket);
+                spawn(recv_loop(socket.clone(), self.pending.clone()));
+                Ok(socket)
+            })
+            .await
+            .cloned()
+    }
+
+    /// Send packet and wait for the response with the same transaction id. Transaction id, at the same offset in every
+    /// request packet, is filled in with one not used by other pending requests
+    async fn request(&self, tracker_addr: &str, bind_address: Option<IpAddr>, mut packet: Vec<u8>) -> Result<Vec<u8>> {
+        ensure!(packet.len() >= 16, "request packet too short");
+        let socket = self.socket(bind_address).await?;
+        let ipv4 = socket.local_addr()?.is_ipv4();
+        let addr = lookup_host(tracker_addr)
+            .await?
+            .find(|a| a.is_ipv4() == ipv4)
+            .context("tracker has no address of socket address family")?;
+        let (sender, receiver) = oneshot::channel();
+        let tx_id = {
+            let mut pending = self.pending.lock().await;
+            let mut tx_id: i32 = thread_rng().gen();
+            while pending.contains_key(&tx_id) {
+                tx_id = thread_rng().gen();
+            }
+            pending.insert(tx_id, (addr, sender));
+            tx_id
+        };
+        packet[12..16].copy_from_slice(&tx_id.to_be_bytes());
+        let res = async {
+            trace!("sending pkt to {}: {}", addr, hex(&packet));
+            socket.send_to(&packet, addr).await?;
+            timeout(REQUEST_TIMEOUT, receiver)
+                .await
+                .context("tracker response timeout")?
+                .context("udp service stopped")
+        }
+        .await;
+        self.pending.lock().await.remove(&tx_id);
+        res
+    }
+
+    /// Connection id of the tracker, cached one if it is still valid
+    async fn connection_id(&self, tracker_addr: &str, bind_address: Option<IpAddr>) -> Result<i64> {
+        let conn_id = self
+            .conn_ids
+            .lock()
+            .await
+            .entry(tracker_addr.to_string())
+            .or_default()
+            .clone();
+        let mut conn_id = conn_id.lock().await;
+        if let Some((id, received)) = *conn_id {
+            if received.elapsed() < CONNECTION_ID_TTL {
+                trace!("cached connection id: {}", hex(&id.to_be_bytes()));
+                return Ok(id);
+            }
+        }
+        let id = self.connect(tracker_addr, bind_address).await?;
+        *conn_id = Some((id, Instant::now()));
+        Ok(id)
+    }
+
+    async fn forget_connection_id(&self, tracker_addr: &str) {
+        self.conn_ids.lock().await.remove(tracker_addr);
+    }
+
+    async fn connect(&self, tracker_addr: &str, bind_address: Option<IpAddr>) -> Result<i64> {
+        let conn_id: i64 = 0x41727101980;
+        let connect_pkt = [
+            &conn_id.to_be_bytes()[..],
+            &ACTION_CONNECT.to_be_bytes(),
+            // transaction id, set by the request
+            &0_i32.to_be_bytes(),
+        ]
+        .concat();
+        let pkt = self.request(tracker_addr, bind_address, connect_pkt).await?;
+        if let Some(message) = parse_error(&pkt)? {
+            return Err(anyhow!("tracker error: {}", message));
+        }
+        ensure!(pkt.len() >= 16, "connect packet too short");
+        ensure!(i32_from_slice(&pkt[0..4])? == ACTION_CONNECT, "action is not connect");
+        let conn_id = i64::from_be_bytes(pkt[8..16].try_into()?);
+        trace!("connection id: {}", hex(&conn_id.to_be_bytes()));
+        Ok(conn_id)
+    }
+}
+
+impl fmt::Debug for UdpService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<udp service>")
+    }
+}
+
+impl PartialEq for UdpService {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.pending, &other.pending)
+    }
+}
+
+/// Dispatch received packets to requests waiting for them
+async fn recv_loop(socket: Arc<UdpSocket>, pending: Pending) {
+    let mut buf = [0u8; 1 << 16];
+    let mut error_wait = RECV_ERROR_WAIT;
+    loop {
+        let (n, addr) = match socket.recv_from(&mut buf).await {
+            Ok(r) => {
+                error_wait = RECV_ERROR_WAIT;
+                r
+            }
+            Err(e) => {
+                warn!("udp tracker socket error, retrying in {:?}: {e:#}", error_wait);
+                sleep(error_wait).await;
+                error_wait = (error_wait * 2).min(MAX_RECV_ERROR_WAIT);
+                continue;
+            }
+        };
+        let pkt = buf[0..n].to_vec();
+        trace!("read pkt from {}: {}", addr, hex(&pkt));
+        let tx_id = match pkt.get(4..8).map(i32_from_slice) {
+            Some(Ok(tx_id)) => tx_id,
+            _ => {
+                debug!("malformed tracker packet from {}", addr);
+                continue;
+            }
+        };
+        let mut pending = pending.lock().await;
+        match pending.get(&tx_id) {
+            Some((tracker_addr, _)) if *tracker_addr == addr => {
+                if let Some((_, sender)) = pending.remove(&tx_id) {
+                    let _ = sender.send(pkt);
+                }
+            }
+            Some((tracker_addr, _)) => {
+                debug!(
+                    "transaction {} response from {} instead of {}",
+                    tx_id, addr, tracker_addr
+                )
+            }
+            None => debug!("unexpected transaction id {} from {}", tx_id, addr),
+        }
     }
+}
 
+fn i32_from_slice(slice: &[u8]) -> Result<i32> {
+    Ok(i32::from_be_bytes(slice.try_into()?))
+}
+
+/// Message of the error response: <action = 3><transaction id><message>
+fn parse_error(pkt: &[u8]) -> Result<Option<String>> {
+    match pkt.get(0..4).map(i32_from_slice).transpose()? {
+        Some(ACTION_ERROR) => Ok(Some(String::from_utf8_lossy(pkt.get(8..).unwrap_or_default()).into())),
+        _ => Ok(None),
+    }
+}
+
+pub async fn tracker_request_udp(
+    announce: String,
+    request: TrackerRequest,
+    bind_address: Option<IpAddr>,
+    udp: &UdpService,
+) -> Result<TrackerResponse> {
     let url = Url::parse(&announce)?;
-    let tracker_addr = format!("{}:{}", url.host().expect("no host"), url.port().expect("no port"));
-
-    let conn_id: i64 = 0x41727101980;
-    let tx_id: i32 = thread_rng().gen();
-    let connect_pkt = [&conn_id.to_be_bytes()[..], &0_i32.to_be_bytes(), &tx_id.to_be_bytes()].concat();
-    trace!("sending connect pkt: {}", hex(&connect_pkt));
-    let pkt = send_udp(&tracker_addr, &connect_pkt).await?.0;
-    trace!("read connect pkt: {}", hex(&pkt));
-    ensure!(pkt.len() >= 16, "connect packet too short");
-    let conn_id = {
-        ensure!(i32_from_slice(&pkt[0..4])? == 0, "action is not connect");
-        ensure!(i32_from_slice(&pkt[4..8])? == tx_id, "transaction id doesn't match");
-        i64::from_be_bytes(pkt[8..16].try_into()?)
-    };
-    trace!("connection id: {}", hex(&conn_id.to_be_bytes()));
-
-    let tx_id: i32 = thread_rng().gen();
+    let tracker_addr = format!(
+        "{}:{}",
+        url.host().context("no tracker host")?,
+        url.port().context("no tracker port")?
+    );
+    let res = announce_udp(&tracker_addr, request, bind_address, udp).await;
+    if !matches!(res, Ok(TrackerResponse::Success(_))) {
+        // connection id might be expired by the tracker
+        udp.forget_connection_id(&tracker_addr).await;
+    }
+    res
+}
+
+async fn announce_udp(
+    tracker_addr: &str,
+    request: TrackerRequest,
+    bind_address: Option<IpAddr>,
+    udp: &UdpService,
+) -> Result<TrackerResponse> {
+    let conn_id = udp.connection_id(tracker_addr, bind_address).await?;
+
     let announce_pkt = [
         &conn_id.to_be_bytes()[..],
-        &1_i32.to_be_bytes(),
-        &tx_id.to_be_bytes(),
+        &ACTION_ANNOUNCE.to_be_bytes(),
+        // transaction id, set by the request
+        &0_i32.to_be_bytes(),
         &request.info_hash,
         &request.peer_id,
         &request.downloaded.to_be_bytes(),
@@ -54,38 +269,105 @@ pub async fn tracker_request_udp(announce: String, request: TrackerRequest) -> R
         &0_u32.to_be_bytes(),
         // TODO: numwant
         &(-1_i32).to_be_bytes(),
-        // TODO: port
-        &0_u16.to_be_bytes(),
+        &(request.port as u16).to_be_bytes(),
     ]
     .concat();
     ensure!(
         announce_pkt.len() == 98,
         format!("announce pkt is incorrect size: {}", announce_pkt.len())
     );
-    trace!("sending announce pkt: {}", hex(&connect_pkt));
-    let (pkt, addr) = send_udp(&tracker_addr, &announce_pkt).await?;
-    if addr.is_ipv6() {
-        todo!("ipv6 tracker response");
+    let pkt = udp.request(tracker_addr, bind_address, announce_pkt).await?;
+    let ipv6 = udp.socket(bind_address).await?.local_addr()?.is_ipv6();
+    let resp = parse_announce(&pkt, ipv6)?;
+    debug!("tracker response: {:?}", resp);
+    Ok(resp)
+}
+
+/// Parse announce response: <action = 1><transaction id><interval><leechers><seeders><peers>, or error response.
+/// Peers are compact IPv6 peers (18 bytes each) if announce is sent over IPv6, IPv4 peers (6 bytes each) otherwise
+fn parse_announce(pkt: &[u8], ipv6: bool) -> Result<TrackerResponse> {
+    if let Some(failure_reason) = parse_error(pkt)? {
+        return Ok(TrackerResponse::Failure { failure_reason });
     }
     ensure!(pkt.len() >= 20, "announce packet too short");
-    ensure!((pkt.len() - 20) % 6 == 0, "announce packet wierd size");
-    ensure!(i32::from_be_bytes(pkt[0..4].try_into()?) == 1, "action is not announce");
-    ensure!(i32_from_slice(&pkt[4..8])? == tx_id, "transaction id doesn't match");
-    let addr_count = (pkt.len() - 20) / 6;
-    let peers = (0..addr_count)
-        .map(|i| 20 + 6 * i)
-        .map(|i| PeerInfo::try_from(&pkt[i..i + 6]))
-        .collect::<Result<_, _>>()?;
-
-    let resp = TrackerResponse::Success(TrackerResponseSuccess {
-        peers,
-        interval: i32::from_be_bytes(pkt[8..12].try_into()?) as i64,
+    ensure!(i32_from_slice(&pkt[0..4])? == ACTION_ANNOUNCE, "action is not announce");
+    let peer_len = if ipv6 { 18 } else { 6 };
+    let peers = pkt[20..].chunks_exact(peer_len);
+    if !peers.remainder().is_empty() {
+        debug!("ignoring {} trailing bytes of announce packet", peers.remainder().len());
+    }
+    Ok(TrackerResponse::Success(TrackerResponseSuccess {
+        peers: peers.map(PeerInfo::try_from).collect::<Result<_, _>>()?,
+        peer_hosts: BTreeSet::new(),
+        interval: i32_from_slice(&pkt[8..12])? as i64,
         warning_message: None,
         min_interval: None,
         tracker_id: None,
-        complete: None,
-        incomplete: None,
-    });
-    debug!("tracker response: {:?}", resp);
-    Ok(resp)
+        complete: Some(i32_from_slice(&pkt[16..20])? as i64),
+        incomplete: Some(i32_from_slice(&pkt[12..16])? as i64),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_announce() {
+        let header = [
+            &1_i32.to_be_bytes()[..],
+            &[0; 4],
+            &1800_i32.to_be_bytes(),
+            &2_i32.to_be_bytes(),
+            &5_i32.to_be_bytes(),
+        ]
+        .concat();
+        let ipv6_peer = [[0x20, 0x01].as_slice(), &[0; 13], &[1], &6881_u16.to_be_bytes()].concat();
+        let resp = parse_announce(&[header.as_slice(), &ipv6_peer, &[0; 3]].concat(), true).unwrap();
+        assert!(matches!(resp, TrackerResponse::Success(r)
+            if r.peers == BTreeSet::from([PeerInfo { ip: "2001::1".parse().unwrap(), port: 6881 }])
+                && r.complete == Some(5)
+                && r.incomplete == Some(2)));
+
+        let ipv4_peer = [1, 2, 3, 4, 0x1a, 0xe1];
+        let resp = parse_announce(&[header.as_slice(), &ipv4_peer, &[0]].concat(), false).unwrap();
+        assert!(matches!(resp, TrackerResponse::Success(r) if r.peers.len() == 1));
+
+        let error = [&3_i32.to_be_bytes()[..], &[0; 4], b"Connection ID missmatch"].concat();
+        assert!(matches!(parse_announce(&error, false).unwrap(),
+            TrackerResponse::Failure { failure_reason } if failure_reason == "Connection ID missmatch"));
+        assert!(parse_announce(&header[..19], false).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_share_connection_id_between_announces() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker_addr = tracker.local_addr().unwrap().to_string();
+        let connects = spawn(async move {
+            let mut buf = [0u8; 1024];
+            let mut connects = 0;
+            for _ in 0..3 {
+                let (_, addr) = tracker.recv_from(&mut buf).await.unwrap();
+                let tx_id = &buf[12..16];
+                let resp = if i32_from_slice(&buf[8..12]).unwrap() == 0 {
+                    connects += 1;
+                    [&0_i32.to_be_bytes()[..], tx_id, &7_i64.to_be_bytes()].concat()
+                } else {
+                    assert_eq!(buf[0..8], 7_i64.to_be_bytes());
+                    [&1_i32.to_be_bytes()[..], tx_id, &1800_i32.to_be_bytes(), &[0; 8]].concat()
+                };
+                tracker.send_to(&resp, addr).await.unwrap();
+            }
+            connects
+        });
+        let udp = UdpService::default();
+        let request = |i: u8| TrackerRequest::new(vec![i; 20], vec![0; 20], 6881, None, None);
+        let (a, b) = tokio::join!(
+            announce_udp(&tracker_addr, request(1), None, &udp),
+            announce_udp(&tracker_addr, request(2), None, &udp)
+        );
+        assert!(matches!(a.unwrap(), TrackerResponse::Success(r) if r.interval == 1800));
+        assert!(b.is_ok());
+        assert_eq!(connects.await.unwrap(), 1);
+    }
 }
@@ -1,14 +1,16 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use tokio::net::UdpSocket;
 
 use crate::hex::hex;
 
-pub async fn send_udp(addr: &str, packet: &[u8]) -> Result<(Vec<u8>, SocketAddr)> {
-    let local_addr = "0.0.0.0:0";
+pub async fn send_udp(addr: &str, bind_address: Option<IpAddr>, packet: &[u8]) -> Result<(Vec<u8>, SocketAddr)> {
+    let local_addr = SocketAddr::new(bind_address.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), 0);
     trace!("creating socket at {}", local_addr);
-    let socket = UdpSocket::bind(local_addr).await?;
+    let socket = UdpSocket::bind(local_addr)
+        .await
+        .with_context(|| format!("unable to bind to {}", local_addr))?;
     trace!("connecting to {}", addr);
     socket.connect(addr).await?;
     trace!("connected");
@@ -0,0 +1,283 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use futures::future::join_all;
+use reqwest::{header::RANGE, Client, StatusCode, Url};
+use tokio::{spawn, sync::Mutex, time::sleep};
+use urlencoding::{encode, encode_binary};
+
+use crate::{
+    metainfo::{FileInfo, Info},
+    sha1,
+    state::{Block, Piece, State, TorrentStatus, BLOCK_SIZE},
+    torrent::write_piece_retry,
+};
+
+/// HTTP server hosting torrent data
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum WebSeed {
+    /// Server hosting torrent files, requested by byte ranges, see [BEP-19](https://www.bittorrent.org/beps/bep_0019.html)
+    Url(String),
+    /// Server responding with pieces by index, see [BEP-17](https://www.bittorrent.org/beps/bep_0017.html)
+    Http(String),
+}
+
+impl WebSeed {
+    fn url(&self) -> &str {
+        match self {
+            WebSeed::Url(u) | WebSeed::Http(u) => u,
+        }
+    }
+}
+
+/// Web seeds from magnet `ws` params
+pub fn magnet_web_seeds(magnet: &Url) -> BTreeSet<WebSeed> {
+    magnet
+        .query_pairs()
+        .filter(|(k, _)| k == "ws")
+        .map(|(_, v)| WebSeed::Url(v.to_string()))
+        .collect()
+}
+
+/// Download pieces from web seeds of the state and metainfo, once metainfo is known
+pub async fn webseed_loop(state: Arc<Mutex<State>>) -> Result<()> {
+    let seeds = loop {
+        {
+            let state = state.lock().await;
+            if let Ok(metainfo) = &state.metainfo {
+                break state
+                    .web_seeds
+                    .iter()
+                    .chain(metainfo.web_seeds.iter())
+                    .cloned()
+                    .collect::<BTreeSet<_>>();
+            }
+        }
+        let wait = state.lock().await.config.downloaded_check_wait;
+        sleep(wait).await;
+    };
+    if seeds.is_empty() {
+        return Ok(());
+    }
+    info!("downloading from {} web seeds", seeds.len());
+    join_all(seeds.into_iter().map(|seed| {
+        let state = state.clone();
+        spawn(async move {
+            if let Err(e) = webseed_worker(&seed, state).await {
+                debug!("web seed {} error: {e:#}", seed.url());
+            }
+        })
+    }))
+    .await;
+    Ok(())
+}
+
+async fn webseed_worker(seed: &WebSeed, state: Arc<Mutex<State>>) -> Result<()> {
+    let (client, max_fails, wait) = {
+        let state = state.lock().await;
+        (
+            Client::builder().local_address(state.config.bind_address).build()?,
+            state.config.max_hash_fails,
+            state.config.downloaded_check_wait,
+        )
+    };
+    let mut fails = 0;
+    while fails < max_fails {
+        let (piece, info, info_hash) = {
+            let mut state = state.lock().await;
+            match state.status {
+                TorrentStatus::Downloading => {}
+                TorrentStatus::Paused => {
+                    drop(state);
+                    sleep(wait).await;
+                    continue;
+                }
+                _ => return Ok(()),
+            }
+            let info = state
+                .metainfo
+                .as_ref()
+                .map_err(|_| anyhow!("no metainfo"))?
+                .info
+                .clone();
+            match state.next_webseed_piece() {
+                Some(piece) => (piece, info, state.info_hash.clone()),
+                None if state.is_downloaded() => {
+                    info!("torrent is downloaded");
+                    state.status = TorrentStatus::Downloaded;
+                    return Ok(());
+                }
+                None => {
+                    drop(state);
+                    sleep(wait).await;
+                    continue;
+                }
+            }
+        };
+        debug!("requesting piece {} from web seed {}", piece.index, seed.url());
+        let data = download_piece(&client, seed, &info, &info_hash, &piece).await;
+        let hash = match &data {
+            Ok(d) if d.len() == piece.length as usize => Some(sha1::encode_blocking(d.clone()).await?),
+            _ => None,
+        };
+        let verified = match &data {
+            Ok(_) if hash.as_ref() == Some(&piece.hash.0) => true,
+            Ok(_) => {
+                warn!("piece {} from web seed {} failed hash check", piece.index, seed.url());
+                false
+            }
+            Err(e) => {
+                debug!("piece {} from web seed {} error: {e:#}", piece.index, seed.url());
+                false
+            }
+        };
+        {
+            let mut state = state.lock().await;
+            let State { pieces, stats, .. } = &mut *state;
+            let pc = pieces
+                .as_mut()
+                .and_then(|ps| ps.get_mut(&piece.index))
+                .context("no piece")?;
+            pc.webseed = false;
+            match data {
+                Ok(data) if verified && pc.status == TorrentStatus::Downloading => {
+                    pc.blocks = data
+                        .chunks(BLOCK_SIZE as usize)
+                        .enumerate()
+                        .map(|(i, b)| (i as u32, Block(b.to_vec())))
+                        .collect();
+                    pc.status = TorrentStatus::Downloaded;
+                    stats.downloaded += pc.length as u64;
+                }
+                Ok(data) if !verified => {
+                    stats.hash_fails += 1;
+                    stats.wasted_hash_fail += data.len() as u64;
+                }
+                _ => {}
+            }
+            state.notify_peers();
+        }
+        if verified {
+            if let Err(e) = write_piece_retry(piece.index, state.clone()).await {
+                debug!("{e:#}");
+            }
+        } else {
+            fails += 1;
+        }
+    }
+    Err(anyhow!("giving up after {} failures", fails))
+}
+
+async fn download_piece(
+    client: &Client,
+    seed: &WebSeed,
+    info: &Info,
+    info_hash: &[u8],
+    piece: &Piece,
+) -> Result<Vec<u8>> {
+    match seed {
+        WebSeed::Url(url) => {
+            let files = info.file_info.files();
+            let mut data = Vec::with_capacity(piece.length as usize);
+            for f in &piece.file_locations {
+                let url = file_url(url, info, f.file_index);
+                let range = format!("bytes={}-{}", f.offset, f.offset + f.length - 1);
+                trace!(
+                    "requesting {} of {} ({})",
+                    range,
+                    url,
+                    files[f.file_index].path.display()
+                );
+                let resp = client.get(&url).header(RANGE, range).send().await?;
+                ensure!(
+                    resp.status() == StatusCode::PARTIAL_CONTENT,
+                    "unexpected response status: {}",
+                    resp.status()
+                );
+                data.extend(resp.bytes().await?);
+            }
+            Ok(data)
+        }
+        WebSeed::Http(url) => {
+            let url = format!("{}?info_hash={}&piece={}", url, encode_binary(info_hash), piece.index);
+            let resp = client.get(&url).send().await?;
+            ensure!(
+                resp.status().is_success(),
+                "unexpected response status: {}",
+                resp.status()
+            );
+            Ok(resp.bytes().await?.to_vec())
+        }
+    }
+}
+
+/// URL of the torrent file on the BEP-19 web seed. Single file torrent URL can point to the file itself
+fn file_url(base: &str, info: &Info, file_index: usize) -> String {
+    match &info.file_info {
+        FileInfo::Single(_) if !base.ends_with('/') => base.to_string(),
+        FileInfo::Single(_) => format!("{}{}", base, encode(&info.name)),
+        FileInfo::Multi(files) => {
+            let path = files[file_index]
+                .path
+                .iter()
+                .map(|c| encode(&c.to_string_lossy()).into_owned())
+                .collect::<Vec<_>>()
+                .join("/");
+            format!("{}/{}/{}", base.trim_end_matches('/'), encode(&info.name), path)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::metainfo::PathInfo;
+
+    fn info(file_info: FileInfo) -> Info {
+        Info {
+            piece_length: 1,
+            pieces: vec![],
+            name: "my torrent".into(),
+            file_info,
+            private: None,
+        }
+    }
+
+    fn path_info(path: &str) -> PathInfo {
+        PathInfo {
+            length: 1,
+            path: PathBuf::from(path),
+            md5_sum: None,
+        }
+    }
+
+    #[test]
+    fn should_build_file_url() {
+        let single = info(FileInfo::Single(path_info("my torrent")));
+        assert_eq!(
+            file_url("http://cdn.org/file.iso", &single, 0),
+            "http://cdn.org/file.iso"
+        );
+        assert_eq!(file_url("http://cdn.org/", &single, 0), "http://cdn.org/my%20torrent");
+        let multi = info(FileInfo::Multi(vec![path_info("a"), path_info("dir/b c")]));
+        assert_eq!(
+            file_url("http://cdn.org/", &multi, 1),
+            "http://cdn.org/my%20torrent/dir/b%20c"
+        );
+        assert_eq!(file_url("http://cdn.org", &multi, 0), "http://cdn.org/my%20torrent/a");
+    }
+
+    #[test]
+    fn should_parse_magnet_web_seeds() {
+        let magnet = Url::parse("magnet:?xt=urn:btih:00&ws=http%3A%2F%2Fcdn.org%2Fa&ws=http://cdn.org/b").unwrap();
+        assert_eq!(
+            magnet_web_seeds(&magnet).into_iter().collect::<Vec<_>>(),
+            vec![
+                WebSeed::Url("http://cdn.org/a".into()),
+                WebSeed::Url("http://cdn.org/b".into())
+            ]
+        );
+    }
+}
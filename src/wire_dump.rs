@@ -0,0 +1,79 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Context, Error, Result};
+use serde::Serialize;
+
+use crate::{hex::hex, message::Message};
+
+/// Max number of message bytes written to the dump
+const MAX_PAYLOAD: usize = 64;
+
+/// Dump file shared by connections of every torrent, set once by [init]
+static DUMP: OnceLock<Mutex<BufWriter<File>>> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// Dumped message, written as a JSON line
+#[derive(Debug, Serialize)]
+struct WireRecord {
+    /// Unix time in seconds
+    time: f64,
+    peer: SocketAddr,
+    direction: Direction,
+    message: &'static str,
+    /// Length of the encoded message, including length prefix
+    length: usize,
+    /// Hex of the encoded message, truncated to [MAX_PAYLOAD] bytes
+    payload: String,
+}
+
+/// Start dumping peer wire messages to the JSONL file at `path`
+pub fn init(path: &Path) -> Result<()> {
+    let file = File::create(path).context(format!("unable to create wire dump {}", path.display()))?;
+    DUMP.set(Mutex::new(BufWriter::new(file)))
+        .map_err(|_| anyhow!("wire dump is already initialized"))
+}
+
+pub fn is_enabled() -> bool {
+    DUMP.get().is_some()
+}
+
+/// Write the message exchanged with the peer to the dump, if it is enabled
+pub fn record(peer: SocketAddr, direction: Direction, message: &Message) {
+    let dump = match DUMP.get() {
+        Some(dump) => dump,
+        None => return,
+    };
+    let encoded: Vec<u8> = message.clone().into();
+    let record = WireRecord {
+        time: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64(),
+        peer,
+        direction,
+        message: message.name(),
+        length: encoded.len(),
+        payload: hex(&encoded[..encoded.len().min(MAX_PAYLOAD)]),
+    };
+    let mut dump = dump.lock().unwrap_or_else(|e| e.into_inner());
+    let res = serde_json::to_writer(&mut *dump, &record)
+        .map_err(Error::from)
+        .and_then(|_| Ok(dump.write_all(b"\n")?))
+        .and_then(|_| Ok(dump.flush()?));
+    if let Err(e) = res {
+        debug!("wire dump write error: {e:#}");
+    }
+}